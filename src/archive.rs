@@ -0,0 +1,245 @@
+use crate::scraper::Story;
+use crate::state::{Publication, State};
+use std::collections::BTreeSet;
+
+/// Separates the term from the story url in index keys.
+/// Never occurs in UTF-8, so a term can't be a prefix of another term's keys.
+const KEY_SEPARATOR: u8 = 0xff;
+
+/// Inverted index over the stories recorded in [`State`].
+///
+/// Every story is indexed by the words of its title and summaries, by the words
+/// of its sources' names (`source:<word>`) and by its sides (`side:<side>`).
+#[derive(Debug, Clone)]
+pub struct Archive {
+    state: State,
+    index: sled::Tree,
+    /// The terms every url is indexed by, so a republished story loses the terms
+    /// of its previous version
+    terms: sled::Tree,
+}
+
+impl Archive {
+    pub fn try_new(state: &State) -> anyhow::Result<Self> {
+        Ok(Archive {
+            state: state.clone(),
+            index: state.open_tree("archive_index")?,
+            terms: state.open_tree("archive_terms")?,
+        })
+    }
+
+    /// Indexes the story, replacing the version of it indexed before.
+    pub async fn insert(&self, url: &str, story: &Story) -> anyhow::Result<()> {
+        self.remove_terms(url, None)?;
+        let terms = story_terms(story);
+        for term in &terms {
+            self.index.insert(index_key(term, url), &[])?;
+        }
+        self.terms.insert(url, serde_json::to_vec(&terms)?)?;
+        self.index.flush_async().await?;
+        self.terms.flush_async().await?;
+        Ok(())
+    }
+
+    /// Removes the story from the index.
+    pub async fn remove(&self, url: &str, story: &Story) -> anyhow::Result<()> {
+        self.remove_terms(url, Some(story))?;
+        self.index.flush_async().await?;
+        self.terms.flush_async().await?;
+        Ok(())
+    }
+
+    /// Removes the terms the url is indexed by, or the terms of the story
+    /// if they weren't recorded.
+    fn remove_terms(&self, url: &str, story: Option<&Story>) -> anyhow::Result<()> {
+        let terms: BTreeSet<String> = match self.terms.remove(url)? {
+            Some(terms) => serde_json::from_slice(&terms)?,
+            None => story.map(story_terms).unwrap_or_default(),
+        };
+        for term in terms {
+            self.index.remove(index_key(&term, url))?;
+        }
+        Ok(())
+    }
+
+    /// Indexes every published story, leaving out the partial publications,
+    /// returns the number of indexed stories.
+    pub async fn reindex(&self) -> anyhow::Result<usize> {
+        self.index.clear()?;
+        self.terms.clear()?;
+        let mut count = 0;
+        for publication in self.state.publications() {
+            let (url, publication) = publication?;
+            if publication.partial {
+                continue;
+            }
+            self.insert(&url, &publication.story).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Finds the stories matching every term of the query, newest first.
+    ///
+    /// Plain terms match words anywhere in the story, `source:<word>` and
+    /// `side:<side>` only match the sources and sides of its articles.
+    pub fn search(&self, query: &str) -> anyhow::Result<Vec<(String, Publication)>> {
+        let terms = query_terms(query);
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut matches: Option<BTreeSet<String>> = None;
+        for term in terms {
+            let urls = self.lookup(&term)?;
+            matches = Some(match matches {
+                Some(matches) => matches.intersection(&urls).cloned().collect(),
+                None => urls,
+            });
+        }
+
+        let mut found = Vec::new();
+        for url in matches.unwrap_or_default() {
+            if let Some(publication) = self.state.publication(&url)? {
+                found.push((url, publication));
+            }
+        }
+        found.sort_by_key(|(_, publication)| std::cmp::Reverse(publication.published_at));
+        Ok(found)
+    }
+
+    fn lookup(&self, term: &str) -> anyhow::Result<BTreeSet<String>> {
+        let mut prefix = term.as_bytes().to_vec();
+        prefix.push(KEY_SEPARATOR);
+        self.index
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?[prefix.len()..].to_vec())?))
+            .collect()
+    }
+}
+
+fn index_key(term: &str, url: &str) -> Vec<u8> {
+    let mut key = term.as_bytes().to_vec();
+    key.push(KEY_SEPARATOR);
+    key.extend_from_slice(url.as_bytes());
+    key
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(str::to_lowercase)
+}
+
+fn story_terms(story: &Story) -> BTreeSet<String> {
+    let mut terms = BTreeSet::new();
+    let paragraphs = story
+        .summary
+        .iter()
+        .chain(story.articles.iter().flat_map(|article| &article.summary));
+    terms.extend(words(&story.title));
+    for paragraph in paragraphs {
+        terms.extend(words(&paragraph.text()));
+    }
+    for article in &story.articles {
+        terms.extend(words(&article.title));
+        terms.extend(words(&article.source));
+        terms.extend(words(&article.source).map(|word| format!("source:{}", word)));
+        terms.insert(format!("side:{}", article.side.name()));
+    }
+    terms
+}
+
+fn query_terms(query: &str) -> Vec<String> {
+    query
+        .split_whitespace()
+        .flat_map(|term| {
+            let (qualifier, term) = match term.find(':') {
+                Some(idx) => (Some(&term[..idx]), &term[idx + 1..]),
+                None => (None, term),
+            };
+            match qualifier.map(str::to_lowercase).as_deref() {
+                Some("side") => vec![format!("side:{}", term.to_lowercase())],
+                Some("source") => words(term).map(|w| format!("source:{}", w)).collect(),
+                _ => words(term).collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn query_qualifiers() {
+        assert_eq!(
+            query_terms("Cuomo source:Fox side:Center-Right"),
+            vec!["cuomo", "source:fox", "side:center-right"]
+        );
+    }
+
+    #[test]
+    fn story_is_indexed_by_sources_and_sides() -> anyhow::Result<()> {
+        let terms = story_terms(&fixtures::story());
+        assert!(terms.contains("cuomo"));
+        assert!(terms.contains("source:vox"));
+        assert!(terms.contains("side:left"));
+        assert!(terms.contains("side:center-right"));
+        assert!(!terms.contains("side:center"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removed_story_is_not_found() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let archive = Archive::try_new(&State::try_new_temporary()?)?;
+        archive.insert(fixtures::STORY_URL, &story).await?;
+        assert_eq!(archive.lookup("cuomo")?.len(), 1);
+
+        archive.remove(fixtures::STORY_URL, &story).await?;
+        assert!(archive.lookup("cuomo")?.is_empty());
+        assert!(archive.index.is_empty());
+        assert!(archive.terms.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn republished_story_loses_previous_terms() -> anyhow::Result<()> {
+        let mut story = fixtures::story();
+        let archive = Archive::try_new(&State::try_new_temporary()?)?;
+        archive.insert(fixtures::STORY_URL, &story).await?;
+
+        story.title = "Governor accused".to_owned();
+        archive.insert(fixtures::STORY_URL, &story).await?;
+        assert_eq!(archive.lookup("governor")?.len(), 1);
+        assert!(archive.lookup("harrassment")?.is_empty());
+        // Still in the summary
+        assert_eq!(archive.lookup("cuomo")?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_publications_are_not_reindexed() -> anyhow::Result<()> {
+        let mut state = State::try_new_temporary()?;
+        let story = fixtures::story();
+        state.set_published(fixtures::STORY_URL, &story).await?;
+        state
+            .add_to_digest("daily", "https://www.allsides.com/story/partial", &story)
+            .await?;
+        state
+            .set_skipped("https://www.allsides.com/story/skipped")
+            .await?;
+
+        let archive = Archive::try_new(&state)?;
+        assert_eq!(archive.reindex().await?, 1);
+        assert_eq!(
+            archive.lookup("cuomo")?,
+            std::iter::once(fixtures::STORY_URL.to_owned()).collect()
+        );
+        Ok(())
+    }
+}
@@ -1,23 +1,642 @@
-use serde::Deserialize;
+use crate::schedule::Hours;
+use crate::scraper::{Side, Story};
+use anyhow::{anyhow, bail};
+use chrono::{NaiveTime, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub update_interval: u64,
+    /// Minutes before a repeated error is reported to the admin again
+    #[serde(default = "default_error_backoff")]
+    pub error_backoff: u32,
+    /// What the backoff of a repeated error is multiplied by after every report
+    #[serde(default = "default_error_backoff_factor")]
+    pub error_backoff_factor: u32,
+    /// Minutes of the longest backoff of a repeated error
+    #[serde(default = "default_error_backoff_max")]
+    pub error_backoff_max: u32,
     pub story_db: PathBuf,
+    /// Where to keep the raw HTML of loaded pages, pages aren't kept if unset
+    pub page_archive: Option<PathBuf>,
+    /// JSON file listing the [`Destination`]s, the Telegram channel options are used if unset
+    pub destinations: Option<PathBuf>,
+    /// Where `astg site build` writes the website
+    pub site_dir: Option<PathBuf>,
     // envy bugs out on trying to parse u16 inside a flattened structure
     pub webdriver_host: String,
     pub webdriver_port: u16,
     #[serde(flatten)]
     pub telegram: TelegramOptions,
+    #[serde(flatten)]
+    pub telegraph: TelegraphOptions,
+    #[serde(flatten)]
+    pub matrix: MatrixOptions,
+    #[serde(flatten)]
+    pub mastodon: MastodonOptions,
+    #[serde(flatten)]
+    pub feed: FeedOptions,
+    #[serde(flatten)]
+    pub smtp: SmtpOptions,
+    #[serde(flatten)]
+    pub email: EmailOptions,
+    #[serde(flatten)]
+    pub webhooks: WebhookOptions,
+    #[serde(flatten)]
+    pub schedule: ScheduleOptions,
+    #[serde(flatten)]
+    pub stats: StatsOptions,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TelegramOptions {
     #[serde(rename = "telegram_secret")]
     pub secret: String,
+    /// The only destination, unless the destinations file is configured
     #[serde(rename = "telegram_channel")]
-    pub channel: String,
+    pub channel: Option<String>,
     #[serde(rename = "telegram_admin")]
     pub admin: String,
+    #[serde(rename = "telegram_layout", default)]
+    pub layout: Layout,
+    /// Attach a button linking to every article to the published posts
+    #[serde(rename = "telegram_buttons", default, deserialize_with = "from_str")]
+    pub buttons: bool,
+    /// Order of the article replies in the thread layout, e.g. `right,center,left`
+    #[serde(rename = "telegram_side_order", default, deserialize_with = "from_str")]
+    pub side_order: SideOrder,
+    /// Send new stories to the admin for approval before publishing them
+    #[serde(rename = "telegram_approval", default, deserialize_with = "from_str")]
+    pub approval: bool,
+    /// Minutes after which a story waiting for approval is published anyway
+    #[serde(
+        rename = "telegram_approval_timeout",
+        default,
+        deserialize_with = "option_from_str"
+    )]
+    pub approval_timeout: Option<u64>,
+}
+
+impl Config {
+    /// Reads the destinations file, or makes the only destination of the Telegram channel options.
+    pub fn destinations(&self) -> anyhow::Result<Vec<Destination>> {
+        let path = match &self.destinations {
+            Some(path) => path,
+            None => {
+                let telegram = &self.telegram;
+                let chat = match &telegram.channel {
+                    Some(channel) => channel.clone(),
+                    None => {
+                        bail!("neither ASTG_TELEGRAM_CHANNEL nor ASTG_DESTINATIONS is configured")
+                    }
+                };
+                return Ok(vec![Destination {
+                    name: "channel".to_owned(),
+                    platform: Platform::Telegram,
+                    chat,
+                    layout: telegram.layout,
+                    template: None,
+                    buttons: telegram.buttons,
+                    side_order: telegram.side_order.clone(),
+                    filter: Filter::default(),
+                    digest_at: None,
+                }]);
+            }
+        };
+        let file = std::fs::File::open(path)
+            .map_err(|e| anyhow!("cannot open {}: {}", path.display(), e))?;
+        let destinations: Vec<Destination> = serde_json::from_reader(file)
+            .map_err(|e| anyhow!("cannot parse {}: {}", path.display(), e))?;
+        if destinations.is_empty() {
+            bail!("{} lists no destinations", path.display());
+        }
+        for (idx, destination) in destinations.iter().enumerate() {
+            if destinations[..idx]
+                .iter()
+                .any(|other| other.name == destination.name)
+            {
+                bail!("duplicate destination name: {}", destination.name);
+            }
+            // There's a single Mastodon account, see MastodonOptions
+            if destination.platform == Platform::Mastodon
+                && destinations[..idx]
+                    .iter()
+                    .any(|other| other.platform == Platform::Mastodon)
+            {
+                bail!(
+                    "{}: only one mastodon destination is supported, they'd all post to the same account",
+                    destination.name
+                );
+            }
+            // Discord and Mastodon posts are built from a single story
+            if destination.digest_at.is_some() && destination.platform.builds_posts() {
+                bail!(
+                    "{}: {:?} destinations don't support the digest mode",
+                    destination.name,
+                    destination.platform
+                );
+            }
+        }
+        Ok(destinations)
+    }
+}
+
+/// A chat the stories are published to, each with its own layout and filter.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Destination {
+    /// Identifies the destination in the publication records, mustn't change once used
+    pub name: String,
+    #[serde(default)]
+    pub platform: Platform,
+    /// Username (e.g. `@channel`) or numeric id of a Telegram channel, group or user,
+    /// id of a Matrix room (e.g. `!abc:matrix.org`), or url of a Discord webhook
+    #[serde(default)]
+    pub chat: String,
+    #[serde(default)]
+    pub layout: Layout,
+    /// Handlebars template used instead of the default one of the layout,
+    /// or of the digest in the digest mode
+    pub template: Option<PathBuf>,
+    /// Attach a button linking to every article to the published posts
+    #[serde(default)]
+    pub buttons: bool,
+    #[serde(default, deserialize_with = "from_str")]
+    pub side_order: SideOrder,
+    #[serde(default)]
+    pub filter: Filter,
+    /// Time of the day (in the schedule timezone) to post the digest of the stories at,
+    /// e.g. `20:00:00`; the stories are collected instead of being posted one by one if set
+    #[serde(default)]
+    pub digest_at: Option<NaiveTime>,
+}
+
+/// Selects the stories published to a destination.
+///
+/// A story matches if it matches each non-empty criterion: any of the topics,
+/// an article from any of the sides, and any of the keywords in its title or summary.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Filter {
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default, deserialize_with = "side_names")]
+    pub sides: Vec<Side>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, story: &Story) -> bool {
+        let topics = self.topics.is_empty()
+            || story.topics.iter().any(|topic| {
+                self.topics
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(topic))
+            });
+        let sides = self.sides.is_empty()
+            || story
+                .articles
+                .iter()
+                .any(|article| self.sides.contains(&article.side));
+        let keywords = self.keywords.is_empty() || {
+            let text = std::iter::once(story.title.clone())
+                .chain(story.summary.iter().map(|paragraph| paragraph.text()))
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase();
+            self.keywords
+                .iter()
+                .any(|keyword| text.contains(&keyword.to_lowercase()))
+        };
+        topics && sides && keywords
+    }
+}
+
+/// Messaging platform of a destination.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    #[default]
+    Telegram,
+    Matrix,
+    /// Posts embeds built from the story, ignoring the layout and the template
+    Discord,
+    /// Posts statuses built from the story to the account of the Mastodon options,
+    /// ignoring the chat, the layout and the template
+    Mastodon,
+}
+
+impl Platform {
+    /// Whether the publisher builds the posts from the story, ignoring the rendered post.
+    pub fn builds_posts(self) -> bool {
+        matches!(self, Platform::Discord | Platform::Mastodon)
+    }
+}
+
+/// How a story is laid out in the channel.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// The whole story, split into a chain of replies if it's too long
+    #[default]
+    Full,
+    /// A short teaser linking to the full story published on Telegraph
+    Telegraph,
+    /// Headlines only, with buttons revealing the articles of each side
+    Compact,
+    /// The story summary, with every article posted as a reply to it
+    Thread,
+}
+
+/// Comma-separated list of [`Side::name`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SideOrder(pub Vec<Side>);
+
+impl Default for SideOrder {
+    fn default() -> Self {
+        SideOrder(Side::ALL.to_vec())
+    }
+}
+
+impl FromStr for SideOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| Side::from_name(name).ok_or_else(|| anyhow!("unknown side: {}", name)))
+            .collect::<Result<_, _>>()
+            .map(SideOrder)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TelegraphOptions {
+    #[serde(rename = "telegraph_url", default = "default_telegraph_url")]
+    pub url: String,
+    #[serde(rename = "telegraph_author")]
+    pub author: Option<String>,
+}
+
+/// The Matrix account the matrix destinations are published from.
+#[derive(Deserialize, Debug, Default)]
+pub struct MatrixOptions {
+    /// Base url of the homeserver, e.g. `https://matrix.org`
+    #[serde(rename = "matrix_homeserver")]
+    pub homeserver: Option<String>,
+    /// Access token of the account
+    #[serde(rename = "matrix_token")]
+    pub token: Option<String>,
+}
+
+/// The Mastodon account the mastodon destination is published to, so there can be only one.
+#[derive(Deserialize, Debug, Default)]
+pub struct MastodonOptions {
+    /// Base url of the instance, e.g. `https://mastodon.social`
+    #[serde(rename = "mastodon_instance")]
+    pub instance: Option<String>,
+    /// Access token of the account, with the `write:statuses` scope
+    #[serde(rename = "mastodon_token")]
+    pub token: Option<String>,
+    #[serde(rename = "mastodon_visibility", default)]
+    pub visibility: Visibility,
+    /// Content warning the statuses are hidden behind, if set
+    #[serde(rename = "mastodon_content_warning")]
+    pub content_warning: Option<String>,
+}
+
+/// Who can see the statuses, see https://docs.joinmastodon.org/entities/Status/#visibility
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+    Direct,
+}
+
+/// Feeds of the latest published stories, rewritten after each publication.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FeedOptions {
+    /// Where to write the Atom feed, it isn't written if unset
+    #[serde(rename = "feed_atom")]
+    pub atom: Option<PathBuf>,
+    /// Where to write the RSS 2.0 feed, it isn't written if unset
+    #[serde(rename = "feed_rss")]
+    pub rss: Option<PathBuf>,
+    /// Number of stories in the feeds
+    #[serde(
+        rename = "feed_entries",
+        default = "default_feed_entries",
+        deserialize_with = "from_str"
+    )]
+    pub entries: usize,
+    #[serde(rename = "feed_title", default = "default_feed_title")]
+    pub title: String,
+    /// The website of the feed, also used as its id
+    #[serde(rename = "feed_link", default = "default_feed_link")]
+    pub link: String,
+}
+
+/// The server the emails are sent through.
+#[derive(Deserialize, Debug)]
+pub struct SmtpOptions {
+    #[serde(rename = "smtp_host")]
+    pub host: Option<String>,
+    #[serde(
+        rename = "smtp_port",
+        default = "default_smtp_port",
+        deserialize_with = "from_str"
+    )]
+    pub port: u16,
+    /// Upgrade the connection to TLS before logging in
+    #[serde(rename = "smtp_starttls", default, deserialize_with = "from_str")]
+    pub starttls: bool,
+    #[serde(rename = "smtp_username")]
+    pub username: Option<String>,
+    #[serde(rename = "smtp_password")]
+    pub password: Option<String>,
+}
+
+/// The daily email digest, sent if there are recipients.
+#[derive(Deserialize, Debug)]
+pub struct EmailOptions {
+    #[serde(rename = "email_from")]
+    pub from: Option<String>,
+    /// Comma-separated addresses of the recipients
+    #[serde(rename = "email_to", default, deserialize_with = "comma_separated")]
+    pub to: Vec<String>,
+    /// Time of the day, in the timezone of the schedule, the digest of the stories
+    /// published since the previous one is sent at
+    #[serde(
+        rename = "email_digest_at",
+        default = "default_email_digest_at",
+        deserialize_with = "from_str"
+    )]
+    pub digest_at: NaiveTime,
+}
+
+/// Endpoints notified of the published stories with a signed JSON payload.
+#[derive(Deserialize, Debug)]
+pub struct WebhookOptions {
+    /// Comma-separated urls of the endpoints
+    #[serde(rename = "webhooks", default, deserialize_with = "comma_separated")]
+    pub endpoints: Vec<String>,
+    /// Key of the HMAC-SHA256 signature of the payloads, required with the endpoints
+    #[serde(rename = "webhook_secret")]
+    pub secret: Option<String>,
+    /// Attempts to deliver a payload before recording it as a dead letter
+    #[serde(
+        rename = "webhook_attempts",
+        default = "default_webhook_attempts",
+        deserialize_with = "from_str"
+    )]
+    pub attempts: u32,
+    /// Seconds before the first retry, doubled with each retry
+    #[serde(
+        rename = "webhook_retry_delay",
+        default = "default_webhook_retry_delay",
+        deserialize_with = "from_str"
+    )]
+    pub retry_delay: u64,
+}
+
+/// When the posts are sent, see [`Schedule`](crate::schedule::Schedule).
+#[derive(Deserialize, Debug)]
+pub struct ScheduleOptions {
+    /// Timezone of the hours below, e.g. `Europe/Berlin`
+    #[serde(
+        rename = "schedule_timezone",
+        default = "default_schedule_timezone",
+        deserialize_with = "from_str"
+    )]
+    pub timezone: Tz,
+    /// Hours nothing is posted in, e.g. `23:00-07:00`, the posts wait in the outbox until they end
+    #[serde(
+        rename = "schedule_quiet_hours",
+        default,
+        deserialize_with = "option_from_str"
+    )]
+    pub quiet_hours: Option<Hours>,
+    /// Hours the posts are sent without a notification in
+    #[serde(
+        rename = "schedule_silent_hours",
+        default,
+        deserialize_with = "option_from_str"
+    )]
+    pub silent_hours: Option<Hours>,
+    /// Minutes between two posts to the same destination
+    #[serde(rename = "schedule_spacing", default, deserialize_with = "from_str")]
+    pub spacing: u32,
+}
+
+/// The weekly post of the coverage statistics, sent if there are destinations.
+#[derive(Deserialize, Debug)]
+pub struct StatsOptions {
+    /// Comma-separated names of the destinations the statistics are posted to
+    #[serde(
+        rename = "stats_destinations",
+        default,
+        deserialize_with = "comma_separated"
+    )]
+    pub destinations: Vec<String>,
+    /// Day of the week the statistics of the last seven days are posted on, e.g. `sun`
+    #[serde(rename = "stats_weekday", default = "default_stats_weekday")]
+    pub weekday: Weekday,
+    /// Time of the day, in the timezone of the schedule, the statistics are posted at
+    #[serde(
+        rename = "stats_at",
+        default = "default_stats_at",
+        deserialize_with = "from_str"
+    )]
+    pub at: NaiveTime,
+    /// Handlebars template replacing the default one
+    #[serde(rename = "stats_template")]
+    pub template: Option<PathBuf>,
+}
+
+fn default_error_backoff() -> u32 {
+    60
+}
+
+fn default_error_backoff_factor() -> u32 {
+    2
+}
+
+fn default_error_backoff_max() -> u32 {
+    24 * 60
+}
+
+fn default_feed_entries() -> usize {
+    50
+}
+
+fn default_feed_title() -> String {
+    "AllSides".to_owned()
+}
+
+fn default_feed_link() -> String {
+    crate::ALL_SIDES_MAINPAGE.to_owned()
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn default_schedule_timezone() -> Tz {
+    Tz::UTC
+}
+
+fn default_email_digest_at() -> NaiveTime {
+    NaiveTime::from_hms(8, 0, 0)
+}
+
+fn default_stats_weekday() -> Weekday {
+    Weekday::Sun
+}
+
+fn default_stats_at() -> NaiveTime {
+    NaiveTime::from_hms(18, 0, 0)
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_retry_delay() -> u64 {
+    10
+}
+
+fn default_telegraph_url() -> String {
+    "https://api.telegra.ph".to_owned()
+}
+
+/// Parses flattened values, which envy passes as strings regardless of the field type.
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+fn comma_separated<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(String::deserialize(deserializer)?
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Parses a list of [`Side::name`]s.
+fn side_names<'de, D>(deserializer: D) -> Result<Vec<Side>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| {
+            Side::from_name(name)
+                .ok_or_else(|| serde::de::Error::custom(format!("unknown side: {}", name)))
+        })
+        .collect()
+}
+
+fn option_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    from_str(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::story;
+
+    fn filter(json: &str) -> Filter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn filter_requires_every_criterion() {
+        let story = story();
+        assert!(filter("{}").matches(&story));
+        assert!(filter(r#"{"topics": ["sexual misconduct"]}"#).matches(&story));
+        assert!(!filter(r#"{"topics": ["Economy"]}"#).matches(&story));
+        assert!(filter(r#"{"sides": ["center", "right"]}"#).matches(&story));
+        assert!(!filter(r#"{"sides": ["center"]}"#).matches(&story));
+        assert!(filter(r#"{"keywords": ["CUOMO"], "sides": ["left"]}"#).matches(&story));
+        assert!(!filter(r#"{"keywords": ["cuomo"], "topics": ["Economy"]}"#).matches(&story));
+    }
+
+    /// Reads the config from the required variables and the given ones.
+    fn config(vars: &[(&str, &str)]) -> Result<Config, envy::Error> {
+        let required = [
+            ("UPDATE_INTERVAL", "10"),
+            ("STORY_DB", "db"),
+            ("WEBDRIVER_HOST", "localhost"),
+            ("WEBDRIVER_PORT", "4444"),
+            ("TELEGRAM_SECRET", "secret"),
+            ("TELEGRAM_ADMIN", "admin"),
+        ];
+        envy::from_iter::<_, Config>(
+            required
+                .iter()
+                .chain(vars)
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+    }
+
+    #[test]
+    fn negative_minutes_are_rejected() {
+        type Minutes = fn(&Config) -> u64;
+        let minutes: [(&str, Minutes); 3] = [
+            ("ERROR_BACKOFF", |config| config.error_backoff.into()),
+            ("SCHEDULE_SPACING", |config| config.schedule.spacing.into()),
+            ("TELEGRAM_APPROVAL_TIMEOUT", |config| {
+                config.telegram.approval_timeout.unwrap()
+            }),
+        ];
+        for (name, value) in &minutes {
+            assert_eq!(value(&config(&[(name, "30")]).unwrap()), 30, "{}", name);
+            assert!(config(&[(name, "-30")]).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn second_mastodon_destination_is_rejected() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("astg-destinations-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[
+                {"name": "one", "platform": "mastodon"},
+                {"name": "two", "platform": "mastodon"}
+            ]"#,
+        )?;
+        let destinations = config(&[("DESTINATIONS", path.to_str().unwrap())])?.destinations();
+        std::fs::remove_file(&path)?;
+        assert!(destinations
+            .unwrap_err()
+            .to_string()
+            .contains("only one mastodon destination"));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_side_is_rejected() {
+        assert!(serde_json::from_str::<Filter>(r#"{"sides": ["far-left"]}"#).is_err());
+    }
 }
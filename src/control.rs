@@ -0,0 +1,61 @@
+use tokio::sync::{mpsc, oneshot};
+
+/// Commands the admin sends to the running importer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    Status,
+    Pause,
+    Resume,
+    /// Run a tick right away
+    Tick,
+    /// Mark the story as published without posting it
+    Skip(String),
+    /// Load the story again and post it, replacing the previous post
+    Republish(String),
+    /// Forget the story was published, so it's posted again if it's still on the main page
+    Forget(String),
+    /// Publish the story waiting for approval
+    Approve(u64),
+    /// Drop the story waiting for approval, it won't be queued again
+    Reject(u64),
+    /// Record the message asking the admin for the edited post of the story waiting for approval
+    EditPrompt(u64, i32),
+    /// Replace the post of the story waiting for approval with the given HTML
+    Edit(u64, String),
+    /// List the posts waiting to be sent and the ones which failed
+    Outbox,
+    /// Send the post in the outbox again, starting its attempts over
+    Retry(u64),
+    /// Remove the post from the outbox without sending it
+    Drop(u64),
+    /// Deliver the webhook deliveries which kept failing again
+    Redeliver,
+    /// Load the story and publish it, even if it was published before if `force` is set
+    Publish {
+        url: String,
+        force: bool,
+    },
+}
+
+/// A control command with the channel to send the reply to the admin over.
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: Control,
+    pub reply: oneshot::Sender<String>,
+}
+
+pub type ControlSender = mpsc::UnboundedSender<ControlRequest>;
+pub type ControlReceiver = mpsc::UnboundedReceiver<ControlRequest>;
+
+pub fn channel() -> (ControlSender, ControlReceiver) {
+    mpsc::unbounded_channel()
+}
+
+/// Sends the command to the importer and waits for the reply.
+pub async fn request(sender: &ControlSender, command: Control) -> anyhow::Result<String> {
+    let (reply, response) = oneshot::channel();
+    sender
+        .send(ControlRequest { command, reply })
+        .map_err(|_| anyhow::anyhow!("the importer is not running"))?;
+    Ok(response.await?)
+}
@@ -0,0 +1,397 @@
+use crate::publisher::{ApiError, Progress, Publisher, Receipt};
+use crate::scraper::{escape_html, Side, Story};
+use crate::tg_bot::Post;
+use anyhow::bail;
+use async_trait::async_trait;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Limits of an embed, see https://discord.com/developers/docs/resources/channel#embed-limits
+const TITLE_LIMIT: usize = 256;
+const DESCRIPTION_LIMIT: usize = 4096;
+const FIELD_NAME_LIMIT: usize = 256;
+const FIELD_VALUE_LIMIT: usize = 1024;
+const FIELDS_PER_EMBED: usize = 25;
+/// Limits of a message: the embeds and the text of all its embeds together
+const EMBEDS_PER_MESSAGE: usize = 10;
+const TOTAL_TEXT_LIMIT: usize = 6000;
+/// Length of the description kept when the articles don't fit into a message otherwise
+const DESCRIPTION_RESERVE: usize = 1024;
+
+/// Message flag suppressing the push and desktop notifications.
+const SUPPRESS_NOTIFICATIONS: u32 = 1 << 12;
+
+/// Embed colours of the stories covered only by the left and only by the right,
+/// the colours of the other stories are in between.
+const LEFT_COLOR: (u8, u8, u8) = (0x1f, 0x5f, 0xbf);
+const RIGHT_COLOR: (u8, u8, u8) = (0xd2, 0x2f, 0x2f);
+
+#[derive(Deserialize)]
+struct WebhookMessage {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+    /// Seconds to wait before the next request, set on rate-limited requests
+    retry_after: Option<f64>,
+}
+
+/// Publishes stories as embeds through a Discord incoming webhook.
+///
+/// The embeds are built from the story itself, so the layout and the templates
+/// of the destination don't apply.
+pub struct DiscordWebhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl DiscordWebhook {
+    pub fn new(url: &str) -> Self {
+        DiscordWebhook {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    async fn check(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.bytes().await?;
+        Err(match serde_json::from_slice::<ErrorResponse>(&body) {
+            Ok(error) => ApiError {
+                message: format!("discord: {}: {}", status, error.message),
+                status,
+                retry_after: error.retry_after.map(Duration::from_secs_f64),
+            },
+            Err(_) => ApiError {
+                message: format!("discord: {}", status),
+                status,
+                retry_after: None,
+            },
+        }
+        .into())
+    }
+}
+
+#[async_trait]
+impl Publisher for DiscordWebhook {
+    async fn publish(
+        &self,
+        story: Option<(&str, &Story)>,
+        post: &Post,
+        _key: &str,
+        _progress: &mut dyn Progress,
+    ) -> anyhow::Result<Receipt> {
+        let (url, story) = match story {
+            Some(story) => story,
+            None => bail!("discord posts are built from a single story"),
+        };
+        let mut message = json!({ "embeds": story_embeds(story, url) });
+        if post.silent {
+            message["flags"] = json!(SUPPRESS_NOTIFICATIONS);
+        }
+        let response = self
+            .client
+            .post(&format!("{}?wait=true", self.url))
+            .json(&message)
+            .send()
+            .await?;
+        let message: WebhookMessage = Self::check(response).await?.json().await?;
+        Ok(Receipt::Discord {
+            webhook_message_ids: vec![message.id],
+        })
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> anyhow::Result<()> {
+        let ids = match receipt {
+            Receipt::Discord {
+                webhook_message_ids,
+            } => webhook_message_ids,
+            _ => bail!("not a discord post: {:?}", receipt),
+        };
+        for id in ids {
+            let response = self
+                .client
+                .delete(&format!("{}/messages/{}", self.url, id))
+                .send()
+                .await?;
+            Self::check(response).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds the embed of the story with a field per article, continued in more embeds
+/// if the story has too many articles for one.
+///
+/// The articles which don't fit into the limits of a message are left out,
+/// and the description is shortened to make room for the first articles if needed.
+fn story_embeds(story: &Story, url: &str) -> Vec<Value> {
+    let color = balance_color(story);
+    let title = truncate(&story.title, TITLE_LIMIT);
+    let description = story
+        .summary
+        .iter()
+        .map(|paragraph| paragraph.text().trim().to_owned())
+        .join("\n\n");
+    let mut fields = story
+        .articles
+        .iter()
+        .map(|article| {
+            let name = format!("{} {}", article.side.emoji(), article.source);
+            (
+                truncate(&name, FIELD_NAME_LIMIT),
+                article_link(&article.title, &article.url),
+            )
+        })
+        .take(FIELDS_PER_EMBED * EMBEDS_PER_MESSAGE)
+        .collect::<Vec<_>>();
+
+    // Leave out the last articles until the title, the articles and the start
+    // of the description fit, then fill the rest of the text limit with the description
+    let text_len = |fields: &[(String, String)]| {
+        fields
+            .iter()
+            .map(|(name, value)| name.chars().count() + value.chars().count())
+            .sum::<usize>()
+    };
+    let title_len = title.chars().count();
+    let description_reserve = description.chars().count().min(DESCRIPTION_RESERVE);
+    while title_len + text_len(&fields) + description_reserve > TOTAL_TEXT_LIMIT {
+        fields.pop();
+    }
+    let description_limit =
+        (TOTAL_TEXT_LIMIT - title_len - text_len(&fields)).min(DESCRIPTION_LIMIT);
+    let description = truncate(&description, description_limit);
+
+    let fields = fields
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value, "inline": false }))
+        .collect::<Vec<_>>();
+    let mut embeds = vec![json!({
+        "title": title,
+        "url": url,
+        "description": description,
+        "color": color,
+        "timestamp": story.datetime.to_rfc3339(),
+        "fields": fields.iter().take(FIELDS_PER_EMBED).collect::<Vec<_>>(),
+    })];
+    for fields in fields[fields.len().min(FIELDS_PER_EMBED)..].chunks(FIELDS_PER_EMBED) {
+        embeds.push(json!({ "color": color, "fields": fields }));
+    }
+    embeds
+}
+
+/// Renders the embeds of the story as HTML, to show the admin what is posted.
+pub fn preview(story: &Story, url: &str) -> String {
+    let mut html = String::new();
+    for embed in story_embeds(story, url) {
+        if let Some(title) = embed["title"].as_str() {
+            html.push_str(&format!(
+                "<b><a href=\"{}\">{}</a></b>\n\n",
+                escape_html(url),
+                escape_html(title)
+            ));
+        }
+        if let Some(description) = embed["description"].as_str() {
+            html.push_str(&format!("{}\n\n", escape_html(description)));
+        }
+        for field in embed["fields"].as_array().into_iter().flatten() {
+            html.push_str(&format!(
+                "<b>{}</b>\n{}\n",
+                escape_html(field["name"].as_str().unwrap_or_default()),
+                escape_html(field["value"].as_str().unwrap_or_default())
+            ));
+        }
+    }
+    html.trim_end().to_owned()
+}
+
+/// Interpolates the embed colour between [`LEFT_COLOR`] and [`RIGHT_COLOR`]
+/// by the mean side of the articles of the story.
+fn balance_color(story: &Story) -> u32 {
+    let positions = story
+        .articles
+        .iter()
+        .filter_map(|article| Side::ALL.iter().position(|&side| side == article.side))
+        .collect::<Vec<_>>();
+    let balance = if positions.is_empty() {
+        0.5
+    } else {
+        let mean = positions.iter().sum::<usize>() as f64 / positions.len() as f64;
+        mean / (Side::ALL.len() - 1) as f64
+    };
+    let channel = |left: u8, right: u8| {
+        (f64::from(left) + (f64::from(right) - f64::from(left)) * balance).round() as u32
+    };
+    channel(LEFT_COLOR.0, RIGHT_COLOR.0) << 16
+        | channel(LEFT_COLOR.1, RIGHT_COLOR.1) << 8
+        | channel(LEFT_COLOR.2, RIGHT_COLOR.2)
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '[' | ']' | '*' | '_' | '~' | '`' | '|') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Links the title to the url in markdown, truncating the title so the link fits
+/// into a field. The url is never cut, a url too long for a field leaves the title unlinked.
+fn article_link(title: &str, url: &str) -> String {
+    // The brackets and the parentheses around the title and the url
+    let budget = match FIELD_VALUE_LIMIT.checked_sub(url.chars().count() + 4) {
+        Some(budget) if budget > 0 => budget,
+        _ => return truncate(&escape_markdown(title), FIELD_VALUE_LIMIT),
+    };
+    let mut limit = budget;
+    loop {
+        // Escaping lengthens the title, so it's truncated before it's escaped
+        let escaped = escape_markdown(&truncate(title, limit));
+        let len = escaped.chars().count();
+        if len <= budget || limit == 1 {
+            return format!("[{}]({})", escaped, url);
+        }
+        limit = limit.saturating_sub(len - budget).max(1);
+    }
+}
+
+fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_owned();
+    }
+    let mut truncated: String = text.chars().take(limit - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{story, STORY_URL};
+    use crate::mock_server::MockServer;
+    use crate::tg_bot::ReplyTo;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn publish_embed() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"id":"123","channel_id":"456"}"#)]);
+        let webhook = DiscordWebhook::new(&format!("{}/api/webhooks/1/token", server.url));
+        let post = Post {
+            messages: vec!["ignored".to_owned()],
+            keyboard: None,
+            reply_to: ReplyTo::Previous,
+            silent: false,
+        };
+        let receipt = webhook
+            .publish(Some((STORY_URL, &story())), &post, "1", &mut None)
+            .await?;
+        assert_eq!(
+            receipt,
+            Receipt::Discord {
+                webhook_message_ids: vec!["123".to_owned()]
+            }
+        );
+
+        let request = &server.requests()[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/webhooks/1/token?wait=true");
+        let body: Value = serde_json::from_str(&request.body)?;
+        let embed = &body["embeds"][0];
+        assert_eq!(
+            embed["title"],
+            "NY Gov. Cuomo Accused of Sexual Harrassment; Less Coverage from Left-Rated Outlets"
+        );
+        assert_eq!(embed["url"], "https://www.allsides.com/story/cuomo");
+        assert!(embed["description"]
+            .as_str()
+            .unwrap()
+            .starts_with("A former aide accused"));
+        assert_eq!(embed["fields"][0]["name"], "🟦 Vox");
+        assert_eq!(
+            embed["fields"][0]["value"],
+            "[The sexual harassment allegation against Gov. Andrew Cuomo, explained]\
+             (https://www.vox.com/22174452/andrew-cuomo-lindsey-boylan-sexual-harassment)"
+        );
+        assert_eq!(embed["fields"].as_array().unwrap().len(), 3);
+        assert_eq!(body.get("flags"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn color_follows_balance() {
+        let mut story = story();
+        let only = |story: &mut Story, side: Side| {
+            for article in &mut story.articles {
+                article.side = side;
+            }
+        };
+        only(&mut story, Side::Left);
+        assert_eq!(balance_color(&story), 0x1f5fbf);
+        only(&mut story, Side::Right);
+        assert_eq!(balance_color(&story), 0xd22f2f);
+        only(&mut story, Side::Center);
+        assert_eq!(balance_color(&story), 0x794777);
+    }
+
+    #[test]
+    fn embeds_fit_into_message() {
+        let mut story = story();
+        let article = story.articles[0].clone();
+        story.articles = std::iter::repeat_n(article, 300).collect();
+        story.summary = std::iter::repeat_n(story.summary[0].clone(), 20).collect();
+
+        let embeds = story_embeds(&story, STORY_URL);
+        assert!(embeds.len() <= EMBEDS_PER_MESSAGE);
+        let text_len = |value: &Value| value.as_str().map_or(0, |text| text.chars().count());
+        let total = embeds
+            .iter()
+            .map(|embed| {
+                let fields = embed["fields"].as_array().unwrap();
+                text_len(&embed["title"])
+                    + text_len(&embed["description"])
+                    + fields
+                        .iter()
+                        .map(|field| text_len(&field["name"]) + text_len(&field["value"]))
+                        .sum::<usize>()
+            })
+            .sum::<usize>();
+        assert!(total <= TOTAL_TEXT_LIMIT);
+        assert!(text_len(&embeds[0]["description"]) > 0);
+    }
+
+    #[test]
+    fn preview_shows_embed() {
+        let preview = preview(&story(), STORY_URL);
+        assert!(preview.starts_with(
+            "<b><a href=\"https://www.allsides.com/story/cuomo\">NY Gov. Cuomo Accused"
+        ));
+        assert!(preview.contains("<b>🟦 Vox</b>\n[The sexual harassment allegation"));
+    }
+
+    #[test]
+    fn long_text_is_truncated() {
+        assert_eq!(truncate("short", 5), "short");
+        assert_eq!(truncate("longer", 5), "long…");
+        assert_eq!(escape_markdown("a [b]_c"), r"a \[b\]\_c");
+    }
+
+    #[test]
+    fn long_title_keeps_link() {
+        let url = "https://www.vox.com/22174452/andrew-cuomo";
+        let link = article_link(&"[long] ".repeat(300), url);
+        assert!(link.chars().count() <= FIELD_VALUE_LIMIT);
+        assert!(link.starts_with(r"[\[long\] "));
+        assert!(link.ends_with(&format!("…]({})", url)));
+    }
+}
@@ -0,0 +1,325 @@
+use crate::config::{EmailOptions, SmtpOptions};
+use crate::smtp::Smtp;
+use crate::state::{Publication, State};
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use handlebars::Handlebars;
+use itertools::Itertools;
+use serde_json::json;
+
+/// Length of the lines of base64-encoded message parts.
+const BASE64_LINE: usize = 76;
+
+/// Emails the stories published during the last day.
+pub struct EmailDigest {
+    smtp: Smtp,
+    /// Mailbox of the From header, possibly with a display name
+    from: String,
+    /// Bare address of the sender, for the envelope
+    sender: String,
+    to: Vec<String>,
+    html: Handlebars<'static>,
+    text: Handlebars<'static>,
+}
+
+impl EmailDigest {
+    pub fn try_new(smtp: &SmtpOptions, email: &EmailOptions) -> anyhow::Result<Self> {
+        let from = match &email.from {
+            Some(from) => from.clone(),
+            None => bail!("the sender of the digest is not configured (ASTG_EMAIL_FROM)"),
+        };
+        let sender = match bare_address(&from) {
+            Some(sender) => sender.to_owned(),
+            None => bail!("invalid sender of the digest (ASTG_EMAIL_FROM): {}", from),
+        };
+        if email.to.is_empty() {
+            bail!("the recipients of the digest are not configured (ASTG_EMAIL_TO)");
+        }
+        let mut html = Handlebars::new();
+        html.register_template_string(
+            "digest",
+            include_str!("../data/email-digest-template.handlebars"),
+        )?;
+        let mut text = Handlebars::new();
+        text.register_escape_fn(handlebars::no_escape);
+        text.register_template_string(
+            "digest",
+            include_str!("../data/email-digest-text.handlebars"),
+        )?;
+        Ok(EmailDigest {
+            smtp: Smtp::try_new(smtp)?,
+            from,
+            sender,
+            to: email.to.clone(),
+            html,
+            text,
+        })
+    }
+
+    /// Sends the stories the publications of which were completed after `since` up to `until`,
+    /// returns their number. Nothing is sent if there are no such stories.
+    ///
+    /// A story still partial at `until` is sent with the digest of the window it's completed in.
+    pub async fn send(
+        &self,
+        state: &State,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> anyhow::Result<usize> {
+        let mut publications = state.publications().collect::<anyhow::Result<Vec<_>>>()?;
+        publications.retain(|(_, publication)| {
+            publication
+                .completed()
+                .is_some_and(|completed| since < completed && completed <= until)
+        });
+        publications.sort_by_key(|(_, publication)| publication.completed());
+        if publications.is_empty() {
+            return Ok(0);
+        }
+        let message = self.compose(&publications, until)?;
+        self.smtp.send(&self.sender, &self.to, &message).await?;
+        Ok(publications.len())
+    }
+
+    /// Composes a message with the digest, due at `due`, as both HTML and plain text.
+    fn compose(
+        &self,
+        publications: &[(String, Publication)],
+        due: DateTime<Utc>,
+    ) -> anyhow::Result<String> {
+        let data = digest_data(publications, due);
+        let html = self.html.render("digest", &data)?;
+        let text = self.text.render("digest", &data)?;
+
+        let now = Utc::now();
+        let boundary = format!("astg-{}", now.timestamp_nanos());
+        let domain = self.sender.rsplit('@').next().unwrap_or("localhost");
+        let subject = format!(
+            "AllSides, {}: {} {}",
+            due.format("%Y-%m-%d"),
+            publications.len(),
+            if publications.len() == 1 {
+                "story"
+            } else {
+                "stories"
+            }
+        );
+        let headers = [
+            format!("From: {}", self.from),
+            format!("To: {}", self.to.join(", ")),
+            format!("Subject: =?UTF-8?B?{}?=", base64::encode(subject)),
+            format!("Date: {}", now.to_rfc2822()),
+            format!("Message-ID: <{}@{}>", boundary, domain),
+            "MIME-Version: 1.0".to_owned(),
+            format!(
+                "Content-Type: multipart/alternative; boundary=\"{}\"",
+                boundary
+            ),
+        ];
+        let part = |content_type: &str, body: &str| {
+            format!(
+                "--{}\r\nContent-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+                boundary,
+                content_type,
+                encode_body(body)
+            )
+        };
+        Ok(format!(
+            "{}\r\n\r\n{}{}--{}--\r\n",
+            headers.join("\r\n"),
+            part("text/plain", &text),
+            part("text/html", &html),
+            boundary
+        ))
+    }
+}
+
+fn digest_data(publications: &[(String, Publication)], now: DateTime<Utc>) -> serde_json::Value {
+    let stories = publications
+        .iter()
+        .map(|(url, publication)| {
+            let story = &publication.story;
+            let side_stories = story
+                .articles
+                .iter()
+                .sorted_by_key(|article| article.side)
+                .map(|article| {
+                    json!({
+                        "side_story_emoji": article.side.emoji(),
+                        "side_story_label": article.side.label(),
+                        "side_story_title": article.title.trim(),
+                        "side_story_url": article.url,
+                        "side_story_source": article.source.trim(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            let summary = story
+                .summary
+                .iter()
+                .map(|p| p.text().trim().to_owned())
+                .join("\n\n");
+            let content = story
+                .summary
+                .iter()
+                .map(|p| format!("<p>{}</p>", p.telegram_html().trim()))
+                .join("\n");
+            json!({
+                "story_title": story.title,
+                "story_url": url,
+                "story_summary": summary,
+                "story_content": content,
+                "side_stories": side_stories,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "date": now.format("%Y-%m-%d").to_string(),
+        "stories": stories,
+    })
+}
+
+/// Returns the address of a mailbox, either bare or as `Name <address>`.
+fn bare_address(mailbox: &str) -> Option<&str> {
+    let mailbox = mailbox.trim();
+    let address = match mailbox.rfind('<') {
+        Some(start) => mailbox[start + 1..].strip_suffix('>')?,
+        None => mailbox,
+    };
+    let invalid = |c: char| c.is_whitespace() || c == '<' || c == '>';
+    if address.contains('@') && !address.contains(invalid) {
+        Some(address)
+    } else {
+        None
+    }
+}
+
+/// Encodes the body as base64, with the CRLF line endings the message requires.
+fn encode_body(body: &str) -> String {
+    let body = body.replace("\r\n", "\n").replace('\n', "\r\n");
+    base64::encode(body)
+        .as_bytes()
+        .chunks(BASE64_LINE)
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .join("\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, STORY_URL};
+    use crate::mock_server::MockSmtpServer;
+    use chrono::Duration;
+
+    fn options(server: &MockSmtpServer) -> (SmtpOptions, EmailOptions) {
+        let smtp = SmtpOptions {
+            host: Some("127.0.0.1".to_owned()),
+            port: server.port,
+            starttls: false,
+            username: None,
+            password: None,
+        };
+        let email = EmailOptions {
+            from: Some("astg@example.com".to_owned()),
+            to: vec!["reader@example.com".to_owned()],
+            digest_at: "08:00:00".parse().unwrap(),
+        };
+        (smtp, email)
+    }
+
+    fn digest(server: &MockSmtpServer) -> EmailDigest {
+        let (smtp, email) = options(server);
+        EmailDigest::try_new(&smtp, &email).unwrap()
+    }
+
+    #[test]
+    fn recipients_are_required() {
+        let server = MockSmtpServer::start();
+        let (smtp, mut email) = options(&server);
+        email.to.clear();
+        assert!(EmailDigest::try_new(&smtp, &email).is_err());
+    }
+
+    #[tokio::test]
+    async fn digest_of_last_day() -> anyhow::Result<()> {
+        let server = MockSmtpServer::start();
+        let mut state = State::try_new_temporary()?;
+        state.set_published(STORY_URL, &fixtures::story()).await?;
+
+        let now = Utc::now();
+        let sent = digest(&server)
+            .send(&state, now - Duration::hours(24), now)
+            .await?;
+        assert_eq!(sent, 1);
+        let session = server.session();
+        assert!(session.contains(&"RCPT TO:<reader@example.com>".to_owned()));
+        assert!(session.contains(&"Content-Type: text/html; charset=utf-8".to_owned()));
+
+        // The story was published in the window of the previous digest
+        let sent = digest(&server)
+            .send(&state, now, now + Duration::hours(24))
+            .await?;
+        assert_eq!(sent, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn story_completed_after_its_window() -> anyhow::Result<()> {
+        let server = MockSmtpServer::start();
+        let mut state = State::try_new_temporary()?;
+        let story = fixtures::story();
+        // The story collected for a digest is partial until the digest is sent
+        state.add_to_digest("daily", STORY_URL, &story).await?;
+
+        let first = Utc::now();
+        let sent = digest(&server)
+            .send(&state, first - Duration::hours(24), first)
+            .await?;
+        assert_eq!(sent, 0);
+
+        state.set_published(STORY_URL, &story).await?;
+        let second = Utc::now() + Duration::seconds(1);
+        let sent = digest(&server).send(&state, first, second).await?;
+        assert_eq!(sent, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sender_with_display_name() -> anyhow::Result<()> {
+        let server = MockSmtpServer::start();
+        let (smtp, mut email) = options(&server);
+        email.from = Some("AllSides <astg@example.com>".to_owned());
+        let digest = EmailDigest::try_new(&smtp, &email)?;
+        let mut state = State::try_new_temporary()?;
+        state.set_published(STORY_URL, &fixtures::story()).await?;
+
+        let now = Utc::now();
+        digest.send(&state, now - Duration::hours(24), now).await?;
+        let session = server.session();
+        assert!(session.contains(&"MAIL FROM:<astg@example.com>".to_owned()));
+        assert!(session.contains(&"From: AllSides <astg@example.com>".to_owned()));
+
+        email.from = Some("AllSides".to_owned());
+        assert!(EmailDigest::try_new(&smtp, &email).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn text_isnt_escaped() -> anyhow::Result<()> {
+        let server = MockSmtpServer::start();
+        let publication = fixtures::publication(Utc::now());
+        let data = digest_data(
+            &[(
+                "https://www.allsides.com/story/a?b&c".to_owned(),
+                publication,
+            )],
+            Utc::now(),
+        );
+        let digest = digest(&server);
+        let text = digest.text.render("digest", &data)?;
+        assert!(text.contains("https://www.allsides.com/story/a?b&c"));
+        assert!(text.contains("The governor's office"));
+        let html = digest.html.render("digest", &data)?;
+        assert!(html.contains(r#"<a href="https://www.allsides.com/story/a?b&amp;c">"#));
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use crate::scraper::escape_html;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+/// Groups the errors so the admin isn't sent the same error on every tick.
+///
+/// An error is reported when it first occurs, then again only after the backoff,
+/// which is multiplied by the factor with every report up to the longest backoff,
+/// with the number of occurrences since the first one.
+/// Once a tick succeeds, the admin is notified of the recovery.
+pub struct ErrorReporter {
+    /// What fails, in the plural, e.g. "ticks"
+    subject: &'static str,
+    backoff: Duration,
+    factor: u32,
+    max_backoff: Duration,
+    active: HashMap<(&'static str, String), ActiveError>,
+}
+
+struct ActiveError {
+    occurrences: u64,
+    first_seen: DateTime<Utc>,
+    last_reported: DateTime<Utc>,
+    reports: u32,
+}
+
+impl ErrorReporter {
+    pub fn new(
+        subject: &'static str,
+        backoff: Duration,
+        factor: u32,
+        max_backoff: Duration,
+    ) -> Self {
+        ErrorReporter {
+            subject,
+            backoff,
+            factor,
+            max_backoff,
+            active: HashMap::new(),
+        }
+    }
+
+    /// Records the error, returns the report to send to the admin, if it's due.
+    pub fn record(&mut self, err: &anyhow::Error, now: DateTime<Utc>) -> Option<String> {
+        let kind = error_kind(err);
+        let message = format!("{:#}", err);
+        let key = (kind, fingerprint(&message));
+
+        let (backoff, factor, max_backoff) = (self.backoff, self.factor, self.max_backoff);
+        let error = self.active.entry(key).or_insert(ActiveError {
+            occurrences: 0,
+            first_seen: now,
+            last_reported: now,
+            reports: 0,
+        });
+        error.occurrences += 1;
+
+        if error.reports > 0 {
+            let factor = factor.saturating_pow(error.reports - 1);
+            let interval = backoff
+                .num_milliseconds()
+                .saturating_mul(factor.into())
+                .min(max_backoff.num_milliseconds());
+            let interval = Duration::milliseconds(interval);
+            if now - error.last_reported < interval {
+                return None;
+            }
+        }
+        error.reports += 1;
+        error.last_reported = now;
+
+        let mut report = format!("⚠️ [{}] {}", kind, escape_html(&message));
+        if error.occurrences > 1 {
+            report.push_str(&format!(
+                "\n\noccurred {} times since {}",
+                error.occurrences,
+                error.first_seen.format("%Y-%m-%d %H:%M UTC")
+            ));
+        }
+        Some(report)
+    }
+
+    /// Forgets the active errors, returns the recovery notice if there were any.
+    pub fn recovered(&mut self) -> Option<String> {
+        if self.active.is_empty() {
+            return None;
+        }
+        let occurrences: u64 = self.active.values().map(|error| error.occurrences).sum();
+        self.active.clear();
+        Some(format!(
+            "✅ recovered after {} failed {}",
+            occurrences, self.subject
+        ))
+    }
+}
+
+/// Broad category of the error, by the type of its root cause.
+fn error_kind(err: &anyhow::Error) -> &'static str {
+    let cause = err.root_cause();
+    if cause.is::<teloxide::RequestError>() {
+        "telegram"
+    } else if cause.is::<fantoccini::error::CmdError>()
+        || cause.is::<fantoccini::error::NewSessionError>()
+    {
+        "webdriver"
+    } else if cause.is::<crate::publisher::ApiError>() {
+        "publisher"
+    } else if cause.is::<reqwest::Error>() {
+        "network"
+    } else if cause.is::<sled::Error>() || cause.is::<std::io::Error>() {
+        "storage"
+    } else {
+        "scraper"
+    }
+}
+
+/// Error message with the parts varying between occurrences of the same error
+/// (numbers, urls) erased.
+fn fingerprint(message: &str) -> String {
+    message
+        .split_whitespace()
+        .filter(|word| !word.contains("://"))
+        .map(|word| {
+            word.chars()
+                .map(|c| if c.is_ascii_digit() { '#' } else { c })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn repeated_errors_are_throttled() {
+        let mut reporter =
+            ErrorReporter::new("ticks", Duration::minutes(60), 2, Duration::hours(24));
+        let start = Utc.ymd(2020, 12, 15).and_hms(0, 0, 0);
+        let err = || anyhow::anyhow!("cannot query story body (id=content)");
+
+        assert!(reporter.record(&err(), start).is_some());
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(10))
+            .is_none());
+        let report = reporter.record(&err(), start + Duration::minutes(60));
+        assert!(report.unwrap().contains("occurred 3 times"));
+        // The backoff doubles after every report
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(150))
+            .is_none());
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(180))
+            .is_some());
+
+        assert_eq!(
+            reporter.recovered().as_deref(),
+            Some("✅ recovered after 5 failed ticks")
+        );
+        assert_eq!(reporter.recovered(), None);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut reporter =
+            ErrorReporter::new("ticks", Duration::minutes(10), 3, Duration::minutes(60));
+        let start = Utc.ymd(2020, 12, 15).and_hms(0, 0, 0);
+        let err = || anyhow::anyhow!("cannot query story body (id=content)");
+
+        assert!(reporter.record(&err(), start).is_some());
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(10))
+            .is_some());
+        // Tripled to 30 minutes
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(30))
+            .is_none());
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(40))
+            .is_some());
+        // 90 minutes capped to an hour
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(99))
+            .is_none());
+        assert!(reporter
+            .record(&err(), start + Duration::minutes(100))
+            .is_some());
+    }
+
+    #[test]
+    fn errors_differing_in_numbers_are_grouped() {
+        let mut reporter =
+            ErrorReporter::new("ticks", Duration::minutes(60), 2, Duration::hours(24));
+        let now = Utc.ymd(2020, 12, 15).and_hms(0, 0, 0);
+        assert!(reporter
+            .record(&anyhow::anyhow!("timeout after 10s at https://a"), now)
+            .is_some());
+        assert!(reporter
+            .record(&anyhow::anyhow!("timeout after 12s at https://b"), now)
+            .is_none());
+        assert!(reporter
+            .record(&anyhow::anyhow!("summary contains no paragraphs"), now)
+            .is_some());
+    }
+}
@@ -0,0 +1,346 @@
+use crate::config::FeedOptions;
+use crate::scraper::Story;
+use crate::state::{Publication, State};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use std::cmp::Reverse;
+use std::fmt::Write;
+use std::path::Path;
+
+/// The configured feeds of the latest published stories.
+///
+/// The latest stories are kept, so the feeds are rewritten after each publication
+/// without scanning all the published stories again.
+pub struct Feeds {
+    opts: FeedOptions,
+    entries: Vec<(String, Publication)>,
+}
+
+impl Feeds {
+    /// Loads the latest published stories, if any feed is configured.
+    pub fn try_new(opts: &FeedOptions, state: &State) -> anyhow::Result<Self> {
+        let mut feeds = Feeds {
+            opts: opts.clone(),
+            entries: Vec::new(),
+        };
+        if feeds.enabled() {
+            feeds.entries = latest_publications(state, opts.entries)?;
+        }
+        Ok(feeds)
+    }
+
+    fn enabled(&self) -> bool {
+        self.opts.atom.is_some() || self.opts.rss.is_some()
+    }
+
+    /// Adds the published story to the feeds, replacing its previous entry, and writes them.
+    pub fn publish(&mut self, url: &str, publication: &Publication) -> anyhow::Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        self.entries.retain(|(other, _)| other != url);
+        self.entries.push((url.to_owned(), publication.clone()));
+        self.entries
+            .sort_by_key(|(_, publication)| Reverse(publication.completed()));
+        self.entries.truncate(self.opts.entries);
+        self.write()
+    }
+
+    /// Removes the story forgotten from the state from the feeds and writes them, if it was
+    /// in the feeds, refilling them with the latest stories left.
+    pub fn remove(&mut self, url: &str, state: &State) -> anyhow::Result<()> {
+        let count = self.entries.len();
+        self.entries.retain(|(other, _)| other != url);
+        if self.entries.len() == count {
+            return Ok(());
+        }
+        self.entries = latest_publications(state, self.opts.entries)?;
+        self.write()
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.opts.atom {
+            write_atomically(path, &atom(&self.opts, &self.entries))?;
+        }
+        if let Some(path) = &self.opts.rss {
+            write_atomically(path, &rss(&self.opts, &self.entries))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the `count` stories the publications of which were completed last, newest first.
+fn latest_publications(state: &State, count: usize) -> anyhow::Result<Vec<(String, Publication)>> {
+    let mut publications = state.publications().collect::<anyhow::Result<Vec<_>>>()?;
+    publications.retain(|(_, publication)| !publication.partial);
+    publications.sort_by_key(|(_, publication)| Reverse(publication.completed()));
+    publications.truncate(count);
+    Ok(publications)
+}
+
+/// Writes the file next to the destination first, so readers never see it half-written.
+fn write_atomically(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn atom(opts: &FeedOptions, entries: &[(String, Publication)]) -> String {
+    let mut feed = String::new();
+    let updated = last_updated(entries);
+    writeln!(feed, r#"<?xml version="1.0" encoding="utf-8"?>"#).unwrap();
+    writeln!(feed, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#).unwrap();
+    writeln!(feed, "  <title>{}</title>", escape_xml(&opts.title)).unwrap();
+    writeln!(feed, r#"  <link href="{}"/>"#, escape_xml(&opts.link)).unwrap();
+    writeln!(feed, "  <id>{}</id>", escape_xml(&opts.link)).unwrap();
+    writeln!(feed, "  <updated>{}</updated>", updated.to_rfc3339()).unwrap();
+    writeln!(
+        feed,
+        "  <author><name>{}</name></author>",
+        escape_xml(&opts.title)
+    )
+    .unwrap();
+    for (url, publication) in entries {
+        let story = &publication.story;
+        writeln!(feed, "  <entry>").unwrap();
+        writeln!(feed, "    <title>{}</title>", escape_xml(&story.title)).unwrap();
+        writeln!(feed, r#"    <link href="{}"/>"#, escape_xml(url)).unwrap();
+        writeln!(feed, "    <id>{}</id>", escape_xml(url)).unwrap();
+        writeln!(
+            feed,
+            "    <published>{}</published>",
+            story.datetime.to_rfc3339()
+        )
+        .unwrap();
+        writeln!(
+            feed,
+            "    <updated>{}</updated>",
+            publication.published_at.to_rfc3339()
+        )
+        .unwrap();
+        for topic in &story.topics {
+            writeln!(feed, r#"    <category term="{}"/>"#, escape_xml(topic)).unwrap();
+        }
+        writeln!(
+            feed,
+            r#"    <content type="html">{}</content>"#,
+            escape_xml(&story_html(story))
+        )
+        .unwrap();
+        writeln!(feed, "  </entry>").unwrap();
+    }
+    writeln!(feed, "</feed>").unwrap();
+    feed
+}
+
+fn rss(opts: &FeedOptions, entries: &[(String, Publication)]) -> String {
+    let mut feed = String::new();
+    writeln!(feed, r#"<?xml version="1.0" encoding="utf-8"?>"#).unwrap();
+    writeln!(feed, r#"<rss version="2.0">"#).unwrap();
+    writeln!(feed, "  <channel>").unwrap();
+    writeln!(feed, "    <title>{}</title>", escape_xml(&opts.title)).unwrap();
+    writeln!(feed, "    <link>{}</link>", escape_xml(&opts.link)).unwrap();
+    writeln!(
+        feed,
+        "    <description>{}</description>",
+        escape_xml(&opts.title)
+    )
+    .unwrap();
+    writeln!(
+        feed,
+        "    <lastBuildDate>{}</lastBuildDate>",
+        last_updated(entries).to_rfc2822()
+    )
+    .unwrap();
+    for (url, publication) in entries {
+        let story = &publication.story;
+        writeln!(feed, "    <item>").unwrap();
+        writeln!(feed, "      <title>{}</title>", escape_xml(&story.title)).unwrap();
+        writeln!(feed, "      <link>{}</link>", escape_xml(url)).unwrap();
+        writeln!(
+            feed,
+            r#"      <guid isPermaLink="true">{}</guid>"#,
+            escape_xml(url)
+        )
+        .unwrap();
+        writeln!(
+            feed,
+            "      <pubDate>{}</pubDate>",
+            story.datetime.to_rfc2822()
+        )
+        .unwrap();
+        for topic in &story.topics {
+            writeln!(feed, "      <category>{}</category>", escape_xml(topic)).unwrap();
+        }
+        writeln!(
+            feed,
+            "      <description>{}</description>",
+            escape_xml(&story_html(story))
+        )
+        .unwrap();
+        writeln!(feed, "    </item>").unwrap();
+    }
+    writeln!(feed, "  </channel>").unwrap();
+    writeln!(feed, "</rss>").unwrap();
+    feed
+}
+
+fn last_updated(entries: &[(String, Publication)]) -> DateTime<Utc> {
+    entries
+        .iter()
+        .map(|(_, publication)| publication.published_at)
+        .max()
+        .unwrap_or_else(Utc::now)
+}
+
+/// Renders the story summary followed by its articles, grouped by side from left to right.
+fn story_html(story: &Story) -> String {
+    let mut html = String::new();
+    for paragraph in &story.summary {
+        write!(html, "<p>{}</p>", paragraph.telegram_html().trim()).unwrap();
+    }
+    let sides = story
+        .articles
+        .iter()
+        .sorted_by_key(|article| article.side)
+        .group_by(|article| article.side);
+    for (side, articles) in &sides {
+        write!(
+            html,
+            "<h3>{} From the {}</h3><ul>",
+            side.emoji(),
+            side.label()
+        )
+        .unwrap();
+        for article in articles {
+            write!(
+                html,
+                r#"<li><a href="{url}">{title}</a> ({source})</li>"#,
+                url = escape_xml(&article.url),
+                title = escape_xml(article.title.trim()),
+                source = escape_xml(article.source.trim()),
+            )
+            .unwrap();
+        }
+        html.push_str("</ul>");
+    }
+    html
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn entries() -> Vec<(String, Publication)> {
+        let publication = fixtures::publication("2020-12-16T01:00:00Z".parse().unwrap());
+        vec![(
+            "https://www.allsides.com/story/cuomo?a=1&b=2".to_owned(),
+            publication,
+        )]
+    }
+
+    fn opts() -> FeedOptions {
+        FeedOptions {
+            atom: None,
+            rss: None,
+            entries: 10,
+            title: "AllSides".to_owned(),
+            link: "https://www.allsides.com/unbiased-balanced-news".to_owned(),
+        }
+    }
+
+    #[test]
+    fn atom_entries() {
+        let feed = atom(&opts(), &entries());
+        assert!(feed.contains("<updated>2020-12-16T01:00:00+00:00</updated>"));
+        assert!(feed.contains(r#"<link href="https://www.allsides.com/story/cuomo?a=1&amp;b=2"/>"#));
+        assert!(feed.contains(r#"<category term="Sexual Misconduct"/>"#));
+        // the content is HTML escaped once more to be embedded in XML
+        assert!(feed.contains("&lt;h3&gt;🟦 From the Left&lt;/h3&gt;&lt;ul&gt;&lt;li&gt;&lt;a href=&quot;https://www.vox.com/"));
+        assert!(feed.contains("(Fox News (Online News))"));
+    }
+
+    #[test]
+    fn rss_items() {
+        let feed = rss(&opts(), &entries());
+        assert!(feed.contains(r#"<rss version="2.0">"#));
+        assert!(feed.contains("<lastBuildDate>Wed, 16 Dec 2020 01:00:00 +0000</lastBuildDate>"));
+        assert!(feed.contains(
+            r#"<guid isPermaLink="true">https://www.allsides.com/story/cuomo?a=1&amp;b=2</guid>"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn latest_stories_are_kept() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("astg-atom-{}.xml", std::process::id()));
+        let opts = FeedOptions {
+            atom: Some(path.clone()),
+            entries: 1,
+            ..opts()
+        };
+        let mut state = State::try_new_temporary()?;
+        let mut feeds = Feeds::try_new(&opts, &state)?;
+        let older = fixtures::publication("2020-12-16T01:00:00Z".parse().unwrap());
+        let newer = fixtures::publication("2020-12-17T01:00:00Z".parse().unwrap());
+        feeds.publish("https://www.allsides.com/story/newer", &newer)?;
+        feeds.publish("https://www.allsides.com/story/older", &older)?;
+        let feed = std::fs::read_to_string(&path)?;
+        assert!(feed.contains("https://www.allsides.com/story/newer"));
+        assert!(!feed.contains("https://www.allsides.com/story/older"));
+
+        // The stories left in the state take the place of the forgotten one
+        state
+            .set_published("https://www.allsides.com/story/left", &fixtures::story())
+            .await?;
+        feeds.remove("https://www.allsides.com/story/newer", &state)?;
+        let feed = std::fs::read_to_string(&path)?;
+        assert!(!feed.contains("https://www.allsides.com/story/newer"));
+        assert!(feed.contains("https://www.allsides.com/story/left"));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn stories_are_ordered_by_completion() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("astg-rss-{}.xml", std::process::id()));
+        let opts = FeedOptions {
+            rss: Some(path.clone()),
+            entries: 1,
+            ..opts()
+        };
+        let mut feeds = Feeds::try_new(&opts, &State::try_new_temporary()?)?;
+        let newer = fixtures::publication("2020-12-17T01:00:00Z".parse().unwrap());
+        let completed_later = Publication {
+            completed_at: Some("2020-12-18T01:00:00Z".parse().unwrap()),
+            ..fixtures::publication("2020-12-16T01:00:00Z".parse().unwrap())
+        };
+        feeds.publish("https://www.allsides.com/story/newer", &newer)?;
+        feeds.publish("https://www.allsides.com/story/later", &completed_later)?;
+        let feed = std::fs::read_to_string(&path)?;
+        assert!(feed.contains("https://www.allsides.com/story/later"));
+        assert!(!feed.contains("https://www.allsides.com/story/newer"));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn feed_is_replaced() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("astg-feed-{}.xml", std::process::id()));
+        write_atomically(&path, "old")?;
+        write_atomically(&path, "new")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "new");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+//! Stories shared by the tests of the modules.
+
+use crate::scraper::{FromHTML, Story};
+use crate::state::Publication;
+use chrono::{DateTime, Utc};
+use select::document::Document;
+
+/// Url the fixture story is published under.
+pub const STORY_URL: &str = "https://www.allsides.com/story/cuomo";
+
+/// The story of `data/allsides-story.html`, on sexual misconduct, with articles
+/// from Vox (left), Fox News (lean right) and the New York Post (right).
+pub fn story() -> Story {
+    let html = Document::from(include_str!("../data/allsides-story.html"));
+    Story::from_html(&html).expect("the fixture story doesn't parse")
+}
+
+/// The fixture story, recorded as published and completed at the time without any posts.
+pub fn publication(published_at: DateTime<Utc>) -> Publication {
+    Publication {
+        story: story(),
+        published_at,
+        posts: Vec::new(),
+        partial: false,
+        completed_at: Some(published_at),
+    }
+}
@@ -0,0 +1,418 @@
+use crate::archive::Archive;
+use crate::control::{self, Control, ControlSender};
+use crate::publisher::Receipt;
+use crate::render::{message_len, MESSAGE_LIMIT};
+use crate::routing::Route;
+use crate::scraper::{escape_html, parse_story_url, Side};
+use crate::state::{Publication, State};
+use crate::tg_bot::{
+    self, APPROVE_CALLBACK_PREFIX, COLLAPSE_CALLBACK, EDIT_CALLBACK_PREFIX, EXPAND_CALLBACK_PREFIX,
+    REJECT_CALLBACK_PREFIX,
+};
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{CallbackQuery, ChatId, ChatOrInlineMessage, ForceReply, User};
+use teloxide::utils::command::BotCommand;
+
+/// Maximum number of search results sent in a single reply.
+const SEARCH_RESULTS_LIMIT: usize = 10;
+
+#[derive(BotCommand)]
+#[command(rename = "lowercase", description = "These commands are supported:")]
+enum Command {
+    #[command(description = "display this text.")]
+    Help,
+    #[command(description = "search published stories, e.g. /search cuomo source:fox side:right")]
+    Search(String),
+    #[command(description = "(admin) show the importer status.")]
+    Status,
+    #[command(description = "(admin) stop publishing new stories.")]
+    Pause,
+    #[command(description = "(admin) resume publishing new stories.")]
+    Resume,
+    #[command(description = "(admin) check for new stories right away.")]
+    Tick,
+    #[command(description = "(admin) mark a story as published without posting it: /skip <url>")]
+    Skip(String),
+    #[command(description = "(admin) post a story again, replacing its post: /republish <url>")]
+    Republish(String),
+    #[command(description = "(admin) forget a story was published: /forget <url>")]
+    Forget(String),
+    #[command(
+        description = "(admin) publish a story without approval, add 'force' to publish it again: /publish <url> [force]"
+    )]
+    Publish(String),
+    #[command(description = "(admin) list the posts waiting to be sent and the failed ones.")]
+    Outbox,
+    #[command(
+        description = "(admin) send a post of the outbox again: /retry <id>",
+        parse_with = "split"
+    )]
+    Retry(u64),
+    #[command(
+        description = "(admin) drop a post of the outbox without sending it: /drop <id>",
+        parse_with = "split"
+    )]
+    Drop(u64),
+    #[command(description = "(admin) deliver the failed webhook deliveries again.")]
+    Redeliver,
+}
+
+impl Command {
+    /// Returns the importer control command, if this is one.
+    fn control(self) -> Option<Control> {
+        let control = match self {
+            Command::Help | Command::Search(_) => return None,
+            Command::Status => Control::Status,
+            Command::Pause => Control::Pause,
+            Command::Resume => Control::Resume,
+            Command::Tick => Control::Tick,
+            Command::Skip(url) => Control::Skip(url),
+            Command::Republish(url) => Control::Republish(url),
+            Command::Forget(url) => Control::Forget(url),
+            Command::Publish(args) => {
+                let mut args = args.split_whitespace();
+                Control::Publish {
+                    url: args.next().unwrap_or_default().to_owned(),
+                    force: args.next() == Some("force"),
+                }
+            }
+            Command::Outbox => Control::Outbox,
+            Command::Retry(id) => Control::Retry(id),
+            Command::Drop(id) => Control::Drop(id),
+            Command::Redeliver => Control::Redeliver,
+        };
+        Some(control)
+    }
+}
+
+/// Handles the updates sent to the bot: commands and presses of the post buttons.
+pub struct Listener {
+    pub archive: Archive,
+    pub state: State,
+    /// The destinations, to re-render their compact posts
+    pub routes: Arc<Vec<Route>>,
+    /// Username or id of the only user allowed to control the importer
+    pub admin: String,
+    pub control: ControlSender,
+}
+
+impl Listener {
+    /// Handles the updates until the update stream ends.
+    pub async fn listen(self, bot: teloxide::Bot) -> anyhow::Result<()> {
+        let me = bot.get_me().send().await?;
+        let bot_name = me.user.username.unwrap_or_default();
+        let listener = Arc::new(self);
+        let callback_listener = listener.clone();
+
+        Dispatcher::new(bot)
+            .messages_handler(move |rx: DispatcherHandlerRx<Message>| {
+                rx.text_messages()
+                    .for_each_concurrent(None, move |(cx, text)| {
+                        let listener = listener.clone();
+                        let bot_name = bot_name.clone();
+                        async move {
+                            let result = match Command::parse(&text, bot_name) {
+                                Ok(command) => listener.answer(&cx, command).await,
+                                Err(_) if cx.update.reply_to_message().is_some() => {
+                                    listener.edit_pending(&cx, text).await
+                                }
+                                Err(_) => listener.publish_url(&cx, &text).await,
+                            };
+                            if let Err(e) = result {
+                                log::error!("failed to answer a message: {}", e);
+                            }
+                        }
+                    })
+            })
+            .callback_queries_handler(move |rx: DispatcherHandlerRx<CallbackQuery>| {
+                rx.for_each_concurrent(None, move |cx| {
+                    let listener = callback_listener.clone();
+                    async move {
+                        if let Err(e) = listener.button_pressed(&cx).await {
+                            log::error!("failed to handle a button press: {}", e);
+                        }
+                    }
+                })
+            })
+            .dispatch()
+            .await;
+        Ok(())
+    }
+
+    async fn answer(&self, cx: &UpdateWithCx<Message>, command: Command) -> anyhow::Result<()> {
+        let reply = match command {
+            Command::Help => escape_html(&Command::descriptions()),
+            Command::Search(query) => self.search(&query)?,
+            command => {
+                let is_admin = cx.update.from().is_some_and(|user| self.is_admin(user));
+                match command.control() {
+                    Some(_) if !is_admin => {
+                        "This command is available to the admin only".to_owned()
+                    }
+                    Some(control) => escape_html(&control::request(&self.control, control).await?),
+                    None => return Ok(()),
+                }
+            }
+        };
+        cx.answer(reply).send().await?;
+        Ok(())
+    }
+
+    /// Replaces the post of a pending story, if the message is the admin's reply
+    /// to the edit prompt of the story.
+    async fn edit_pending(&self, cx: &UpdateWithCx<Message>, text: String) -> anyhow::Result<()> {
+        if !cx.update.from().is_some_and(|user| self.is_admin(user)) {
+            return Ok(());
+        }
+        let prompt = match cx.update.reply_to_message() {
+            Some(prompt) => prompt.id,
+            None => return Ok(()),
+        };
+        let pending = self.state.pending_stories()?;
+        let id = match pending.iter().find(|(_, p)| p.edit_prompt == Some(prompt)) {
+            Some((id, _)) => *id,
+            None => return Ok(()),
+        };
+
+        // The prompt shows the post HTML as code, so the reply text is the edited HTML
+        let reply = control::request(&self.control, Control::Edit(id, text)).await?;
+        cx.answer(escape_html(&reply)).send().await?;
+        Ok(())
+    }
+
+    /// Publishes the story, if the message is a story url sent by the admin.
+    async fn publish_url(&self, cx: &UpdateWithCx<Message>, text: &str) -> anyhow::Result<()> {
+        if !cx.update.from().is_some_and(|user| self.is_admin(user)) {
+            return Ok(());
+        }
+        let url = match parse_story_url(text) {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+        let command = Control::Publish { url, force: false };
+        let reply = control::request(&self.control, command).await?;
+        cx.answer(escape_html(&reply)).send().await?;
+        Ok(())
+    }
+
+    async fn button_pressed(&self, cx: &UpdateWithCx<CallbackQuery>) -> anyhow::Result<()> {
+        let data = cx.update.data.as_deref().unwrap_or_default();
+        let approval = [
+            APPROVE_CALLBACK_PREFIX,
+            REJECT_CALLBACK_PREFIX,
+            EDIT_CALLBACK_PREFIX,
+        ];
+        if approval.iter().any(|prefix| data.starts_with(prefix)) {
+            self.review(cx, data).await
+        } else {
+            self.toggle_side(cx).await
+        }
+    }
+
+    /// Handles the admin's decision on a story waiting for approval.
+    async fn review(&self, cx: &UpdateWithCx<CallbackQuery>, data: &str) -> anyhow::Result<()> {
+        let query = &cx.update;
+        if !self.is_admin(&query.from) {
+            return Ok(());
+        }
+
+        let parse_id = |prefix: &str| data.strip_prefix(prefix).and_then(|id| id.parse().ok());
+        let command = if let Some(id) = parse_id(APPROVE_CALLBACK_PREFIX) {
+            Control::Approve(id)
+        } else if let Some(id) = parse_id(REJECT_CALLBACK_PREFIX) {
+            Control::Reject(id)
+        } else if let Some(id) = parse_id(EDIT_CALLBACK_PREFIX) {
+            return self.prompt_edit(cx, id).await;
+        } else {
+            return Ok(());
+        };
+
+        let reply = control::request(&self.control, command).await?;
+        if let Some(message) = &query.message {
+            cx.bot
+                .edit_message_reply_markup(ChatOrInlineMessage::Chat {
+                    chat_id: ChatId::Id(message.chat.id),
+                    message_id: message.id,
+                })
+                .send()
+                .await?;
+        }
+        cx.bot
+            .answer_callback_query(query.id.clone())
+            .text(reply)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Asks the admin to reply with the edited post.
+    async fn prompt_edit(&self, cx: &UpdateWithCx<CallbackQuery>, id: u64) -> anyhow::Result<()> {
+        let query = &cx.update;
+        let (pending, message) = match (self.state.pending(id)?, &query.message) {
+            (Some(pending), Some(message)) => (pending, message),
+            _ => {
+                cx.bot
+                    .answer_callback_query(query.id.clone())
+                    .text("The story is no longer pending")
+                    .send()
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let builds_posts = self.routes.iter().any(|route| {
+            route.destination.name == pending.destination
+                && route.destination.platform.builds_posts()
+        });
+        if builds_posts {
+            cx.bot
+                .answer_callback_query(query.id.clone())
+                .text("The posts to this destination are built from the story and can't be edited")
+                .send()
+                .await?;
+            return Ok(());
+        }
+
+        let prompt = format!(
+            "Reply to this message with the edited post:\n\n<code>{}</code>",
+            escape_html(&pending.post.messages.join("\n\n"))
+        );
+        if message_len(&prompt) > MESSAGE_LIMIT {
+            cx.bot
+                .answer_callback_query(query.id.clone())
+                .text("The post is too long to be edited in a message")
+                .send()
+                .await?;
+            return Ok(());
+        }
+        let prompt = cx
+            .bot
+            .send_message(ChatId::Id(message.chat.id), prompt)
+            .reply_markup(ForceReply::new())
+            .send()
+            .await?;
+
+        let reply = control::request(&self.control, Control::EditPrompt(id, prompt.id)).await?;
+        cx.bot
+            .answer_callback_query(query.id.clone())
+            .text(reply)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    fn is_admin(&self, user: &User) -> bool {
+        let admin = self.admin.trim_start_matches('@');
+        user.username.as_deref() == Some(admin) || user.id.to_string() == admin
+    }
+
+    fn search(&self, query: &str) -> anyhow::Result<String> {
+        let found = self.archive.search(query)?;
+        let reply = if found.is_empty() {
+            "Nothing found".to_owned()
+        } else {
+            found
+                .iter()
+                .take(SEARCH_RESULTS_LIMIT)
+                .map(|(url, publication)| format_search_result(url, publication))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        Ok(reply)
+    }
+
+    /// Reveals or hides the articles of a side in a compact post.
+    ///
+    /// The callback is answered in any case, with the reason if the post isn't updated,
+    /// so the button doesn't keep spinning.
+    async fn toggle_side(&self, cx: &UpdateWithCx<CallbackQuery>) -> anyhow::Result<()> {
+        let result = self.try_toggle_side(cx).await;
+        let notice = match &result {
+            Ok(notice) => *notice,
+            Err(_) => Some("The post couldn't be updated"),
+        };
+        let mut answer = cx.bot.answer_callback_query(cx.update.id.clone());
+        if let Some(notice) = notice {
+            answer = answer.text(notice);
+        }
+        answer.send().await?;
+        result.map(|_| ())
+    }
+
+    /// Updates the compact post the button belongs to, returning the notice for the user
+    /// if it isn't updated.
+    async fn try_toggle_side(
+        &self,
+        cx: &UpdateWithCx<CallbackQuery>,
+    ) -> anyhow::Result<Option<&'static str>> {
+        let query = &cx.update;
+        let (data, message) = match (&query.data, &query.message) {
+            (Some(data), Some(message)) => (data, message),
+            _ => return Ok(Some("The post is no longer available")),
+        };
+
+        let expanded = match data.strip_prefix(EXPAND_CALLBACK_PREFIX) {
+            Some(name) => match Side::from_name(name) {
+                Some(side) => Some(side),
+                None => return Ok(Some("Unknown side")),
+            },
+            None if data == COLLAPSE_CALLBACK => None,
+            None => return Ok(Some("Unknown button")),
+        };
+
+        let chat_id = message.chat.id;
+        let found = self
+            .state
+            .publication_by_message(chat_id, message.id)?
+            .and_then(|(url, publication)| {
+                let post = publication.posts.iter().find(|post| match post.receipt {
+                    Receipt::Telegram { chat_id: id, .. } => id == chat_id,
+                    _ => false,
+                })?;
+                let route = self
+                    .routes
+                    .iter()
+                    .find(|route| route.destination.name == post.destination)?;
+                Some((url, publication, route))
+            });
+        let (url, publication, route) = match found {
+            Some(found) => found,
+            None => return Ok(Some("The story is no longer available")),
+        };
+
+        let text = match route
+            .renderer
+            .format_compact(&publication.story, &url, expanded)
+        {
+            Ok(text) => text,
+            Err(error) => {
+                log::warn!("can't expand {}: {:#}", url, error);
+                return Ok(Some("The side is too long to show here, see Read More"));
+            }
+        };
+        let keyboard =
+            tg_bot::compact_keyboard(&publication.story, expanded, route.destination.buttons);
+        cx.bot
+            .edit_message_text(
+                ChatOrInlineMessage::Chat {
+                    chat_id: ChatId::Id(chat_id),
+                    message_id: message.id,
+                },
+                text,
+            )
+            .reply_markup(keyboard)
+            .send()
+            .await?;
+        Ok(None)
+    }
+}
+
+fn format_search_result(url: &str, publication: &Publication) -> String {
+    format!(
+        r#"{date} <a href="{url}">{title}</a>"#,
+        date = publication.published_at.format("%Y-%m-%d"),
+        url = escape_html(url),
+        title = escape_html(&publication.story.title),
+    )
+}
@@ -1,3 +1,4 @@
+use crate::page_archive::PageArchive;
 use fantoccini::Client;
 use select::document::Document;
 use serde_json::json;
@@ -5,10 +6,15 @@ use webdriver::capabilities::Capabilities;
 
 pub struct HtmlLoader {
     client: Client,
+    archive: Option<PageArchive>,
 }
 
 impl HtmlLoader {
-    pub async fn try_new(wd_host: &str, wd_port: u16) -> Result<Self, anyhow::Error> {
+    pub async fn try_new(
+        wd_host: &str,
+        wd_port: u16,
+        archive: Option<PageArchive>,
+    ) -> Result<Self, anyhow::Error> {
         let mut caps = Capabilities::new();
         caps.insert(
             "moz:firefoxOptions".into(),
@@ -18,12 +24,18 @@ impl HtmlLoader {
         );
         let url = format!("http://{}:{}", wd_host, wd_port);
         let client = Client::with_capabilities(&url, caps).await?;
-        Ok(Self { client })
+        Ok(Self { client, archive })
     }
 
     pub async fn open(&mut self, url: &str) -> Result<Document, anyhow::Error> {
         self.client.goto(url).await?;
         let source = self.client.source().await?;
+        if let Some(archive) = &self.archive {
+            // The archive is only kept for re-parsing, it doesn't hold back the loading
+            if let Err(e) = archive.insert(url, chrono::Utc::now(), &source).await {
+                log::error!("cannot archive {}: {:#}", url, e);
+            }
+        }
         Ok(Document::from(source.as_str()))
     }
 }
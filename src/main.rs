@@ -1,128 +1,1104 @@
+mod archive;
 mod config;
+mod control;
+mod discord;
+mod email_digest;
+mod error_report;
+mod feed;
+#[cfg(test)]
+mod fixtures;
+mod listener;
 mod loader;
+mod mastodon;
+mod matrix;
+#[cfg(test)]
+mod mock_server;
+mod outbox;
+mod page_archive;
+mod publisher;
+mod render;
+mod reparse;
+mod routing;
+mod schedule;
 mod scraper;
+mod site;
+mod smtp;
 mod state;
+mod stats;
+mod telegraph;
 mod tg_bot;
+mod webhooks;
 
-use config::Config;
+use archive::Archive;
+use config::{Config, Layout, Platform};
+use control::{Control, ControlReceiver};
+use email_digest::EmailDigest;
+use error_report::ErrorReporter;
+use feed::Feeds;
+use listener::Listener;
 use loader::HtmlLoader;
-use scraper::{FromHTML, MainPage, Story};
-use state::State;
-use tg_bot::Bot;
+use outbox::{Outbox, Outcome};
+use page_archive::PageArchive;
+use render::{split_html, MESSAGE_LIMIT};
+use routing::Route;
+use schedule::Schedule;
+use scraper::{escape_html, parse_story_url, FromHTML, MainPage, Story};
+use state::{OutboxItem, PendingStory, State, Subject};
+use stats::{StatsPost, WeeklyStats};
+use telegraph::Telegraph;
+use tg_bot::{Bot, Post, ReplyTo};
+use webhooks::Webhooks;
 
-use handlebars::Handlebars;
-use itertools::Itertools;
-use serde_json::json;
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
 use std::time::Duration;
 
-const ALL_SIDES_MAINPAGE: &str = "https://www.allsides.com/unbiased-balanced-news";
+pub const ALL_SIDES_MAINPAGE: &str = "https://www.allsides.com/unbiased-balanced-news";
+
+/// Longest sleep of the outbox worker, so posts queued by the listener aren't delayed for long.
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+const USAGE: &str = "usage:
+    astg                            run the importer
+    astg archive search <terms>     search published stories (source:<name>, side:<side>)
+    astg archive reindex            rebuild the archive search index
+    astg reparse                    re-parse the archived pages with the current scraper
+    astg site build                 render the archive into a static website (ASTG_SITE_DIR)
+    astg email digest               email the stories of the last 24 hours right away
+    astg publish <url> [--force]    publish a story, skipping the approval; again with --force
+
+the commands open the databases of the bot, stop the running bot before using them;
+while it runs, /search and /publish do the same over Telegram";
 
 struct AllSidesTgImporter {
     cfg: Config,
     loader: HtmlLoader,
     bot: Bot,
     state: State,
-    template: Handlebars<'static>,
+    archive: Archive,
+    routes: Arc<Vec<Route>>,
+    telegraph: Telegraph,
+    email_digest: Option<EmailDigest>,
+    stats: Option<StatsPost>,
+    feeds: Feeds,
+    webhooks: Webhooks,
+    schedule: Schedule,
+    outbox: Outbox,
+    last_tick: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    errors: ErrorReporter,
+    /// The errors of the email digest, reported apart as it's sent even while paused
+    email_errors: ErrorReporter,
+    /// The errors of the outbox between the ticks and of the recovery on startup
+    outbox_errors: ErrorReporter,
 }
 
 impl AllSidesTgImporter {
     pub async fn try_new(cfg: Config) -> anyhow::Result<AllSidesTgImporter> {
-        let loader = HtmlLoader::try_new(&cfg.webdriver_host, cfg.webdriver_port).await?;
+        let page_archive = cfg
+            .page_archive
+            .as_deref()
+            .map(PageArchive::try_new)
+            .transpose()?;
+        let loader =
+            HtmlLoader::try_new(&cfg.webdriver_host, cfg.webdriver_port, page_archive).await?;
         let bot = Bot::try_new(&cfg.telegram)?;
         let state = State::try_new(&cfg.story_db)?;
-        let mut template = Handlebars::new();
-        template
-            .register_template_string("main", include_str!("../data/post-template.handlebars"))?;
+        let archive = Archive::try_new(&state)?;
+        let routes = Arc::new(Route::all(&cfg, &bot)?);
+        let telegraph = Telegraph::new(&cfg.telegraph);
+        let feeds = Feeds::try_new(&cfg.feed, &state)?;
+        let webhooks = Webhooks::try_new(&cfg.webhooks, &state)?;
+        let schedule = Schedule::new(&cfg.schedule);
+        let outbox = Outbox::new(&state, &routes, &schedule);
+        let email_digest = if cfg.email.to.is_empty() {
+            None
+        } else {
+            Some(EmailDigest::try_new(&cfg.smtp, &cfg.email)?)
+        };
+        let stats = if cfg.stats.destinations.is_empty() {
+            None
+        } else {
+            for name in &cfg.stats.destinations {
+                let route = match routes.iter().find(|route| &route.destination.name == name) {
+                    Some(route) => route,
+                    None => bail!("ASTG_STATS_DESTINATIONS: unknown destination {}", name),
+                };
+                // Discord and Mastodon posts are built from a single story
+                let platform = route.destination.platform;
+                if matches!(platform, Platform::Discord | Platform::Mastodon) {
+                    bail!(
+                        "ASTG_STATS_DESTINATIONS: {:?} destinations don't support the statistics",
+                        platform
+                    );
+                }
+            }
+            Some(StatsPost::try_new(&cfg.stats)?)
+        };
+        if cfg.error_backoff_factor == 0 {
+            bail!("ASTG_ERROR_BACKOFF_FACTOR must be at least 1");
+        }
+        let reporter = |subject| {
+            ErrorReporter::new(
+                subject,
+                chrono::Duration::minutes(cfg.error_backoff.into()),
+                cfg.error_backoff_factor,
+                chrono::Duration::minutes(cfg.error_backoff_max.into()),
+            )
+        };
+        let errors = reporter("ticks");
+        let email_errors = reporter("email digests");
+        let outbox_errors = reporter("outbox flushes");
         Ok(AllSidesTgImporter {
             cfg,
             loader,
             bot,
             state,
-            template,
+            archive,
+            routes,
+            telegraph,
+            email_digest,
+            stats,
+            feeds,
+            webhooks,
+            schedule,
+            outbox,
+            last_tick: None,
+            last_error: None,
+            errors,
+            email_errors,
+            outbox_errors,
         })
     }
 
-    pub async fn run(mut self) -> anyhow::Result<()> {
+    pub async fn run(self) -> anyhow::Result<()> {
+        let (control, control_rx) = control::channel();
+        let listener = Listener {
+            archive: self.archive.clone(),
+            state: self.state.clone(),
+            routes: self.routes.clone(),
+            admin: self.cfg.telegram.admin.clone(),
+            control,
+        };
+        let listener = listener.listen(self.bot.client());
+        tokio::try_join!(self.run_importer(control_rx), listener)?;
+        Ok(())
+    }
+
+    async fn run_importer(mut self, mut control: ControlReceiver) -> anyhow::Result<()> {
         let delay = Duration::from_secs(self.cfg.update_interval * 60);
+        let migrated = self.migrate_legacy_messages().await;
+        self.report_outbox_result(migrated).await;
+        let recovered = self.recover().await;
+        self.report_outbox_result(recovered).await;
         loop {
-            if let Err(e) = self.tick().await {
-                log::error!("{}", e);
-                self.bot
-                    .log_error(e)
-                    .await
-                    .map_err(|e| log::error!("failed to post log message to telegram: {}", e))
-                    .ok();
+            if !self.state.is_paused()? {
+                self.tick_and_report().await;
+            }
+            self.send_email_digest_and_report().await;
+
+            let mut next_tick = tokio::time::delay_for(delay);
+            loop {
+                let next_attempt = self.next_outbox_attempt()?;
+                tokio::select! {
+                    _ = &mut next_tick => break,
+                    _ = tokio::time::delay_for(next_attempt) => {
+                        let flushed = self.flush_outbox().await;
+                        self.report_outbox_result(flushed).await;
+                    }
+                    Some(request) = control.recv() => {
+                        let reply = self
+                            .control(request.command)
+                            .await
+                            .unwrap_or_else(|e| format!("failed: {}", e));
+                        request.reply.send(reply).ok();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records the messages of the stories published to the Telegram channel
+    /// before the destinations were introduced as their posts to the destination
+    /// of the channel, so their buttons and deletion keep working.
+    async fn migrate_legacy_messages(&mut self) -> anyhow::Result<()> {
+        if !self.state.has_legacy_messages()? {
+            return Ok(());
+        }
+        let channel = match &self.cfg.telegram.channel {
+            Some(channel) => channel,
+            None => bail!("ASTG_TELEGRAM_CHANNEL is needed to migrate the published messages"),
+        };
+        let route = self.routes.iter().find(|route| {
+            route.destination.platform == Platform::Telegram && &route.destination.chat == channel
+        });
+        let destination = match route {
+            Some(route) => route.destination.name.clone(),
+            None => bail!(
+                "no destination posts to {}, the published messages can't be migrated",
+                channel
+            ),
+        };
+        let chat_id = self.bot.chat_id_of(channel).await?;
+        let count = self.state.migrate_messages(&destination, chat_id).await?;
+        log::info!(
+            "migrated the messages of {} stories to {}",
+            count,
+            destination
+        );
+        Ok(())
+    }
+
+    async fn tick_and_report(&mut self) {
+        self.last_tick = Some(Utc::now());
+        match self.tick().await {
+            Ok(()) => {
+                self.last_error = None;
+                if let Some(report) = self.errors.recovered() {
+                    self.send_to_admin(report).await;
+                }
             }
-            tokio::time::delay_for(delay).await;
+            Err(e) => self.report_error(e).await,
         }
     }
 
+    async fn report_error(&mut self, err: anyhow::Error) {
+        log::error!("{:#}", err);
+        self.last_error = Some(err.to_string());
+        if let Some(report) = self.errors.record(&err, Utc::now()) {
+            self.send_to_admin(report).await;
+        }
+    }
+
+    /// Reports the errors of the outbox and of the recovery apart from the ticks.
+    async fn report_outbox_result(&mut self, result: anyhow::Result<()>) {
+        match result {
+            Ok(()) => {
+                if let Some(report) = self.outbox_errors.recovered() {
+                    self.send_to_admin(report).await;
+                }
+            }
+            Err(e) => {
+                log::error!("{:#}", e);
+                if let Some(report) = self.outbox_errors.record(&e, Utc::now()) {
+                    self.send_to_admin(report).await;
+                }
+            }
+        }
+    }
+
+    async fn send_to_admin(&self, message: String) {
+        self.bot
+            .log_error(message)
+            .await
+            .map_err(|e| log::error!("failed to post log message to telegram: {}", e))
+            .ok();
+    }
+
+    /// Executes the admin command, returns the reply.
+    async fn control(&mut self, command: Control) -> anyhow::Result<String> {
+        let reply = match command {
+            Control::Status => {
+                let paused = if self.state.is_paused()? {
+                    "paused"
+                } else {
+                    "running"
+                };
+                let last_tick = self
+                    .last_tick
+                    .map_or_else(|| "never".to_owned(), |at| at.to_rfc3339());
+                let outbox = self.state.outbox()?;
+                format!(
+                    "{}\nlast tick: {}\nlast error: {}\nstories published: {}\nstories pending approval: {}\nposts in the outbox: {} ({} failed)\nfailed webhook deliveries: {}",
+                    paused,
+                    last_tick,
+                    self.last_error.as_deref().unwrap_or("none"),
+                    self.state.published_count()?,
+                    self.state.pending_stories()?.len(),
+                    outbox.len(),
+                    outbox.iter().filter(|(_, item)| item.failed).count(),
+                    self.state.dead_letters()?.len(),
+                )
+            }
+            Control::Pause => {
+                self.state.set_paused(true).await?;
+                "paused".to_owned()
+            }
+            Control::Resume => {
+                self.state.set_paused(false).await?;
+                "resumed".to_owned()
+            }
+            Control::Tick => {
+                self.tick_and_report().await;
+                match &self.last_error {
+                    Some(e) => format!("tick failed: {}", e),
+                    None => "tick finished".to_owned(),
+                }
+            }
+            Control::Skip(url) => {
+                let url = story_url(&url)?;
+                if let Some(reply) = self.waiting_reply(&url)? {
+                    return Ok(reply);
+                }
+                self.state.set_skipped(&url).await?;
+                format!("skipped {}", url)
+            }
+            Control::Republish(url) => {
+                let url = story_url(&url)?;
+                if let Some(reply) = self.waiting_reply(&url)? {
+                    return Ok(reply);
+                }
+                if let Some(publication) = self.state.publication(&url)? {
+                    for post in &publication.posts {
+                        let route = self
+                            .routes
+                            .iter()
+                            .find(|route| route.destination.name == post.destination);
+                        let route = match route {
+                            Some(route) => route,
+                            None => continue,
+                        };
+                        // Telegram doesn't allow deleting old messages, post the story anyway
+                        if let Err(e) = route.publisher.delete(&post.receipt).await {
+                            log::warn!(
+                                "failed to delete the previous post of {} to {}: {}",
+                                url,
+                                post.destination,
+                                e
+                            );
+                        }
+                    }
+                }
+                let story = self.loader.open(&url).await?;
+                let story = Story::from_html(&story)?;
+                self.publish_story(&story, &url, true).await?;
+                format!("republished {}", url)
+            }
+            Control::Forget(url) => {
+                let url = story_url(&url)?;
+                if let Some(publication) = self.state.publication(&url)? {
+                    self.archive.remove(&url, &publication.story).await?;
+                }
+                let forgotten = self.state.forget(&url).await?;
+                self.feeds.remove(&url, &self.state)?;
+                if forgotten {
+                    format!("forgot {}", url)
+                } else {
+                    format!("{} was not published", url)
+                }
+            }
+            Control::Publish { url, force } => self.publish_url(&url, force).await?,
+            Control::Approve(id) => match self.state.pending(id)? {
+                Some(pending) => self.publish_pending(id, &pending).await?,
+                None => "the story is no longer pending".to_owned(),
+            },
+            Control::Reject(id) => match self.state.remove_pending(id).await? {
+                Some(pending) => {
+                    self.finish_review(&pending).await?;
+                    format!("rejected {} for {}", pending.url, pending.destination)
+                }
+                None => "the story is no longer pending".to_owned(),
+            },
+            Control::EditPrompt(id, prompt) => match self.state.pending(id)? {
+                Some(mut pending) => {
+                    pending.edit_prompt = Some(prompt);
+                    self.state.update_pending(id, &pending).await?;
+                    "reply with the edited post".to_owned()
+                }
+                None => "the story is no longer pending".to_owned(),
+            },
+            Control::Edit(id, html) => match self.state.pending(id)? {
+                Some(mut pending) => {
+                    pending.post.messages = split_html(&html, MESSAGE_LIMIT);
+                    pending.post.reply_to = ReplyTo::Previous;
+                    pending.edit_prompt = None;
+                    pending.edited = true;
+                    // Sending the preview first validates the edited HTML
+                    self.bot.send_preview(&pending.post, id, true).await?;
+                    self.state.update_pending(id, &pending).await?;
+                    "the post is updated".to_owned()
+                }
+                None => "the story is no longer pending".to_owned(),
+            },
+            Control::Outbox => {
+                let outbox = self.state.outbox()?;
+                if outbox.is_empty() {
+                    "the outbox is empty".to_owned()
+                } else {
+                    outbox
+                        .iter()
+                        .map(|(id, item)| format!("#{} {}", id, outbox_item_status(item)))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Control::Retry(id) => match self.outbox.retry(id).await? {
+                Some(item) => {
+                    self.flush_outbox().await?;
+                    self.outbox_reply(id, &item.subject.to_string(), &item.destination)?
+                }
+                None => format!("#{} is not in the outbox", id),
+            },
+            Control::Drop(id) => match self.state.remove_from_outbox(id).await? {
+                Some(item) => {
+                    self.finish_stories_if_sent(&item.subject).await?;
+                    format!("dropped {} for {}", item.subject, item.destination)
+                }
+                None => format!("#{} is not in the outbox", id),
+            },
+            Control::Redeliver => match self.webhooks.redeliver().await? {
+                0 => "no failed webhook deliveries".to_owned(),
+                count => format!("delivering {} webhook deliveries again", count),
+            },
+        };
+        Ok(reply)
+    }
+
     async fn tick(&mut self) -> anyhow::Result<()> {
+        self.publish_expired_pending().await?;
+
         let main_page = self.loader.open(ALL_SIDES_MAINPAGE).await?;
         let main_page = MainPage::from_html(&main_page)?;
         for teaser in main_page.teasers {
-            if self.state.is_published(&teaser.url)? {
+            if self.state.is_published(&teaser.url)?
+                || self.state.is_pending(&teaser.url)?
+                || self.state.is_queued(&teaser.url)?
+                || self.state.is_collected(&teaser.url)?
+            {
                 continue;
             }
 
             let story = self.loader.open(&teaser.url).await?;
             let story = Story::from_html(&story)?;
 
-            self.publish_story(&story, &teaser.url).await?;
+            if self.cfg.telegram.approval {
+                self.request_approval(&story, &teaser.url).await?;
+            } else {
+                self.publish_story(&story, &teaser.url, false).await?;
+            }
+        }
+
+        self.send_digests_if_due().await?;
+        self.send_stats_if_due().await
+    }
+
+    /// Queues the digests which are due and sends them.
+    async fn send_digests_if_due(&mut self) -> anyhow::Result<()> {
+        if self.outbox.queue_digests(Utc::now()).await? > 0 {
+            self.flush_outbox().await?;
+        }
+        Ok(())
+    }
+
+    /// Queues the statistics of the last seven days once a week, at the configured time,
+    /// and sends them.
+    async fn send_stats_if_due(&mut self) -> anyhow::Result<()> {
+        let stats_post = match &self.stats {
+            Some(stats_post) => stats_post,
+            None => return Ok(()),
+        };
+        let now = Utc::now();
+        let opts = &self.cfg.stats;
+        let due = self
+            .schedule
+            .last_weekly_occurrence(opts.weekday, opts.at, now);
+        if self
+            .state
+            .stats_sent_at()?
+            .is_some_and(|sent_at| sent_at >= due)
+        {
+            return Ok(());
+        }
+
+        let publications = self
+            .state
+            .publications()
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let stats = WeeklyStats::of(&publications, due);
+        let mut posts = Vec::new();
+        if stats.stories > 0 {
+            let post = Post {
+                messages: stats_post.format(&stats, &self.schedule)?,
+                keyboard: None,
+                reply_to: ReplyTo::Previous,
+                // Decided by the schedule as the post is sent
+                silent: false,
+            };
+            for route in self.routes.iter() {
+                if opts.destinations.contains(&route.destination.name) {
+                    let destination = &route.destination.name;
+                    posts.push(OutboxItem::new(Subject::Stats, destination, post.clone()));
+                }
+            }
+        }
+        // The week is recorded as sent as its posts are queued, so they're queued only once
+        self.state.stats_sent(&posts, now).await?;
+        if !posts.is_empty() {
+            log::info!("queued the statistics of {} stories", stats.stories);
+            self.flush_outbox().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the email digest if it's due, reporting its errors apart from the ticks.
+    async fn send_email_digest_and_report(&mut self) {
+        match self.send_email_digest_if_due().await {
+            Ok(()) => {
+                if let Some(report) = self.email_errors.recovered() {
+                    self.send_to_admin(report).await;
+                }
+            }
+            Err(e) => {
+                let e = e.context("cannot email the digest");
+                log::error!("{:#}", e);
+                if let Some(report) = self.email_errors.record(&e, Utc::now()) {
+                    self.send_to_admin(report).await;
+                }
+            }
+        }
+    }
+
+    /// Sends the email digest once a day, at the configured time.
+    ///
+    /// The digest has the stories published since the previous one was due,
+    /// or during the day before the first one.
+    async fn send_email_digest_if_due(&mut self) -> anyhow::Result<()> {
+        let digest = match &self.email_digest {
+            Some(digest) => digest,
+            None => return Ok(()),
+        };
+        let due = self
+            .schedule
+            .last_occurrence(self.cfg.email.digest_at, Utc::now());
+        let since = match self.state.email_digest_sent_at()? {
+            Some(sent_at) if sent_at >= due => return Ok(()),
+            Some(sent_at) => sent_at,
+            None => due - chrono::Duration::hours(24),
+        };
+        let sent = digest.send(&self.state, since, due).await?;
+        log::info!("emailed the digest of {} stories", sent);
+        self.state.set_email_digest_sent_at(due).await
+    }
+
+    /// Loads and publishes the story, returns the reply for the admin.
+    ///
+    /// The admin asked for the story, so it's posted without waiting for approval
+    /// even in the approval mode. A story already waiting for approval or in the outbox
+    /// isn't published again, even if `force` is set.
+    async fn publish_url(&mut self, url: &str, force: bool) -> anyhow::Result<String> {
+        let url = story_url(url)?;
+        if let Some(reply) = self.waiting_reply(&url)? {
+            return Ok(reply);
+        }
+        if !force && self.state.is_published(&url)? {
+            return Ok(format!("{} is already published", url));
+        }
+
+        let story = self.loader.open(&url).await?;
+        let story = Story::from_html(&story)?;
+        self.publish_story(&story, &url, force).await?;
+        if self.state.is_queued(&url)? {
+            Ok(format!("queued {}, see /outbox", url))
+        } else if self.state.is_collected(&url)? {
+            Ok(format!("collected {} for a digest", url))
+        } else if self.state.publication(&url)?.is_none() {
+            Ok(format!("skipped {}, no destination wants it", url))
+        } else {
+            Ok(format!("published {}", url))
+        }
+    }
+
+    /// Returns the reply for the admin if the story is waiting for approval, in the outbox
+    /// or collected for a digest, and can't be published or skipped before it leaves them.
+    fn waiting_reply(&self, url: &str) -> anyhow::Result<Option<String>> {
+        if self.state.is_pending(url)? {
+            Ok(Some(format!("{} is waiting for approval", url)))
+        } else if self.state.is_queued(url)? {
+            Ok(Some(format!("{} is already queued, see /outbox", url)))
+        } else if self.state.is_collected(url)? {
+            Ok(Some(format!("{} is already collected for a digest", url)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Queues the story to every destination the filter of which it matches,
+    /// skipping the destinations it is already published to unless `force` is set,
+    /// and sends the posts right away.
+    ///
+    /// A story no destination wants is recorded as skipped, so it isn't loaded again
+    /// but doesn't show up among the published ones.
+    async fn publish_story(&mut self, story: &Story, url: &str, force: bool) -> anyhow::Result<()> {
+        let publication = self.state.publication(url)?;
+        let routes = self.routes.clone();
+        let mut queued = false;
+        let mut collected = false;
+        for route in routes.iter() {
+            let destination = &route.destination;
+            let published = publication
+                .as_ref()
+                .is_some_and(|publication| publication.post(&destination.name).is_some());
+            if !destination.filter.matches(story) || (published && !force) {
+                continue;
+            }
+            if destination.digest_at.is_some() {
+                self.state
+                    .add_to_digest(&destination.name, url, story)
+                    .await?;
+                collected = true;
+                continue;
+            }
+            let post = self.render_post(route, story, url).await?;
+            self.outbox
+                .queue(story, url, &destination.name, post)
+                .await?;
+            queued = true;
+        }
+        if queued {
+            // The publication is finished once its last post is sent
+            self.flush_outbox().await
+        } else if collected {
+            // The publication is finished once the digest with the story is sent
+            Ok(())
+        } else if publication.is_some() {
+            self.finish_publication(url, story).await
+        } else {
+            self.state.set_skipped(url).await
+        }
+    }
+
+    /// Sends the posts of the outbox which are due, finishing the publications
+    /// the last post of which is sent, and tells the admin about the posts kept as failed.
+    async fn flush_outbox(&mut self) -> anyhow::Result<()> {
+        while let Some(attempt) = self.outbox.send_next().await? {
+            let (id, item) = (attempt.id, attempt.item);
+            match attempt.outcome {
+                Outcome::Sent => self.finish_stories_if_sent(&item.subject).await?,
+                Outcome::Retrying => {}
+                Outcome::Failed(error) => {
+                    self.send_to_admin(format!(
+                        "⚠️ failed to publish {} to {}: {}\n\n/retry {} or /drop {}",
+                        item.subject,
+                        item.destination,
+                        escape_html(&error),
+                        id,
+                        id
+                    ))
+                    .await
+                }
+                Outcome::Interrupted(_) => {
+                    self.send_to_admin(format!(
+                        "⚠️ publishing {} to {} was interrupted, check whether it was published\n\n\
+                         /retry {} to publish it or /drop {} if it was",
+                        item.subject, item.destination, id, id
+                    ))
+                    .await
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Settles what a crash interrupted: the posts which were being sent,
+    /// the stories all the posts of which were sent but which weren't recorded as published,
+    /// and the webhook deliveries.
+    async fn recover(&mut self) -> anyhow::Result<()> {
+        let deliveries = self.webhooks.resume()?;
+        if deliveries > 0 {
+            log::warn!("resuming {} webhook deliveries", deliveries);
+        }
+
+        let interrupted = self
+            .state
+            .outbox()?
+            .iter()
+            .filter(|(_, item)| item.sending_since.is_some() && !item.failed)
+            .count();
+        if interrupted > 0 {
+            log::warn!("recovering {} interrupted posts", interrupted);
+        }
+        self.flush_outbox().await?;
+
+        let unfinished = self
+            .state
+            .publications()
+            .filter(|entry| entry.as_ref().map_or(true, |(_, p)| p.partial))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for (url, publication) in unfinished {
+            self.finish_if_sent(&url, &publication.story).await?;
         }
         Ok(())
     }
 
-    async fn publish_story(&mut self, story: &Story<'_>, url: &str) -> anyhow::Result<()> {
-        let formatted = self.format_story(story, url)?;
-        self.bot.publish_message(formatted).await?;
-        self.state.set_published(url).await
+    /// Returns how long the outbox worker sleeps before sending the next post.
+    fn next_outbox_attempt(&self) -> anyhow::Result<Duration> {
+        let delay = match self.outbox.next_attempt()? {
+            Some(at) => (at - Utc::now()).to_std().unwrap_or_default(),
+            None => OUTBOX_POLL_INTERVAL,
+        };
+        Ok(delay.min(OUTBOX_POLL_INTERVAL))
+    }
+
+    /// Describes what became of the post in the outbox, for the admin.
+    fn outbox_reply(&self, id: u64, url: &str, destination: &str) -> anyhow::Result<String> {
+        let reply = match self.state.outbox_item(id)? {
+            None => format!("published {} to {}", url, destination),
+            Some(item) => format!("#{} {}", id, outbox_item_status(&item)),
+        };
+        Ok(reply)
     }
 
-    fn format_story(&self, story: &Story, url: &str) -> anyhow::Result<String> {
-        let story_content = story.summary.iter().map(|p| p.telegram_html()).join("\n\n");
+    /// Finishes the publication of the story once no post of it is left to review or send,
+    /// and it isn't collected for a digest. A failed post keeps the publication partial
+    /// until it's sent or dropped.
+    async fn finish_if_sent(&mut self, url: &str, story: &Story) -> anyhow::Result<()> {
+        if self.state.is_published(url)?
+            || self.state.is_pending(url)?
+            || self.state.is_queued(url)?
+            || self.state.is_collected(url)?
+        {
+            return Ok(());
+        }
+        self.finish_publication(url, story).await
+    }
 
-        let side_stories = story
-            .articles
+    /// Finishes the publications of the stories the post is of, once no post of them
+    /// is left to review or send.
+    async fn finish_stories_if_sent(&mut self, subject: &Subject) -> anyhow::Result<()> {
+        match subject {
+            Subject::Story { url, story } => self.finish_if_sent(url, story).await,
+            Subject::Digest { urls } => {
+                for url in urls {
+                    // Stories forgotten since they were collected stay forgotten
+                    if let Some(publication) = self.state.publication(url)? {
+                        self.finish_if_sent(url, &publication.story).await?;
+                    }
+                }
+                Ok(())
+            }
+            Subject::Stats => Ok(()),
+        }
+    }
+
+    /// Records the story as published to all its destinations, adds it to the archive,
+    /// notifies the webhooks and adds it to the feeds.
+    async fn finish_publication(&mut self, url: &str, story: &Story) -> anyhow::Result<()> {
+        self.state.set_published(url, story).await?;
+        self.archive.insert(url, story).await?;
+        if let Some(publication) = self.state.publication(url)? {
+            self.webhooks.story_published(url, &publication).await?;
+            // The story is published already, the feeds are written again with the next one
+            if let Err(e) = self.feeds.publish(url, &publication) {
+                log::error!("failed to write the feeds: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the rendered story to the admin for each destination the filter of which
+    /// it matches, and holds it until it's approved.
+    async fn request_approval(&mut self, story: &Story, url: &str) -> anyhow::Result<()> {
+        let routes = self.routes.clone();
+        let mut requested = false;
+        for route in routes.iter() {
+            if !route.destination.filter.matches(story) {
+                continue;
+            }
+            let pending = PendingStory {
+                url: url.to_owned(),
+                story: story.clone(),
+                destination: route.destination.name.clone(),
+                post: self.render_preview(route, story, url)?,
+                queued_at: Utc::now(),
+                edit_prompt: None,
+                edited: false,
+            };
+            let id = self.state.add_pending(&pending).await?;
+            let editable = !route.destination.platform.builds_posts();
+            self.bot.send_preview(&pending.post, id, editable).await?;
+            requested = true;
+        }
+        if !requested {
+            // No destination wants the story, record it so it isn't loaded again
+            self.state.set_skipped(url).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues the approved post and sends it, or collects the story for the digest
+    /// of a destination in the digest mode. The pending story is removed once the post
+    /// is queued, so it's never lost in between. Returns the reply for the admin.
+    ///
+    /// The post is rendered again, as the preview doesn't create the Telegraph page,
+    /// unless the admin edited it.
+    async fn publish_pending(&mut self, id: u64, pending: &PendingStory) -> anyhow::Result<String> {
+        let routes = self.routes.clone();
+        let route = routes
             .iter()
-            .map(|article| {
-                let article_content = article
-                    .summary
-                    .iter()
-                    .map(|p| p.telegram_html())
-                    .take_while(|para| !para.ends_with("..."))
-                    .join("\n\n");
-
-                json!({
-                    "side_story_emoji": article.side.emoji(),
-                    "side_story_title": article.title,
-                    "side_story_url": article.url,
-                    "side_story_source": article.source,
-                    "side_story_content": article_content,
-                })
-            })
-            .collect::<Vec<_>>();
-
-        let side_stories = serde_json::Value::Array(side_stories);
-
-        let data = json!({
-            "story_title": story.title,
-            "story_content": story_content,
-            "story_url": url,
-            "story_date": format!("{}", story.datetime.date().format("%Y-%m-%d")),
-            "side_stories": side_stories,
-        });
+            .find(|route| route.destination.name == pending.destination);
+        if route.is_some_and(|route| route.destination.digest_at.is_some()) {
+            self.state
+                .add_to_digest(&pending.destination, &pending.url, &pending.story)
+                .await?;
+            self.state.remove_pending(id).await?;
+            self.finish_review(pending).await?;
+            return Ok(format!(
+                "added {} to the digest of {}",
+                pending.url, pending.destination
+            ));
+        }
+
+        let post = match route {
+            Some(route) if !pending.edited => {
+                self.render_post(route, &pending.story, &pending.url)
+                    .await?
+            }
+            _ => pending.post.clone(),
+        };
+        let outbox_id = self
+            .outbox
+            .queue(&pending.story, &pending.url, &pending.destination, post)
+            .await?;
+        self.state.remove_pending(id).await?;
+        self.flush_outbox().await?;
+        self.outbox_reply(outbox_id, &pending.url, &pending.destination)
+    }
+
+    /// Records the story once the posts for all its destinations are reviewed:
+    /// as published if any of them was approved, as skipped otherwise.
+    async fn finish_review(&mut self, pending: &PendingStory) -> anyhow::Result<()> {
+        if self.state.is_pending(&pending.url)?
+            || self.state.is_queued(&pending.url)?
+            || self.state.is_collected(&pending.url)?
+        {
+            return Ok(());
+        }
+        if self.state.publication(&pending.url)?.is_some() {
+            self.finish_if_sent(&pending.url, &pending.story).await
+        } else {
+            self.state.set_skipped(&pending.url).await
+        }
+    }
+
+    /// Publishes the stories which have been waiting for approval longer than the timeout.
+    async fn publish_expired_pending(&mut self) -> anyhow::Result<()> {
+        let timeout = match self.cfg.telegram.approval_timeout {
+            Some(timeout) => {
+                chrono::Duration::from_std(Duration::from_secs(timeout.saturating_mul(60)))
+                    .map_err(|_| anyhow::anyhow!("ASTG_TELEGRAM_APPROVAL_TIMEOUT is too long"))?
+            }
+            None => return Ok(()),
+        };
+        for (id, pending) in self.state.pending_stories()? {
+            if pending.queued_at + timeout <= Utc::now() {
+                self.publish_pending(id, &pending).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the post of the story for the destination, creating its Telegraph page
+    /// in the Telegraph layout.
+    async fn render_post(
+        &mut self,
+        route: &Route,
+        story: &Story,
+        url: &str,
+    ) -> anyhow::Result<Post> {
+        let page_url = match route.destination.layout {
+            Layout::Telegraph => {
+                let token = self.telegraph_token().await?;
+                self.telegraph.create_page(&token, story, url).await?
+            }
+            _ => url.to_owned(),
+        };
+        format_post(route, story, url, &page_url)
+    }
 
-        Ok(self.template.render("main", &data)?)
+    /// Renders what the post of the story for the destination will look like,
+    /// without creating anything: the teaser of the Telegraph layout links to the story
+    /// until its page is created on approval, and the destinations building their own
+    /// posts show them instead of the rendered post.
+    fn render_preview(&self, route: &Route, story: &Story, url: &str) -> anyhow::Result<Post> {
+        let messages = match route.destination.platform {
+            Platform::Discord => split_html(&discord::preview(story, url), MESSAGE_LIMIT),
+            Platform::Mastodon => mastodon::preview(story, url),
+            Platform::Telegram | Platform::Matrix => return format_post(route, story, url, url),
+        };
+        Ok(Post {
+            messages,
+            keyboard: None,
+            reply_to: ReplyTo::Previous,
+            silent: false,
+        })
+    }
+
+    async fn telegraph_token(&mut self) -> anyhow::Result<String> {
+        if let Some(token) = self.state.telegraph_token()? {
+            return Ok(token);
+        }
+        let token = self.telegraph.create_account("AllSides").await?;
+        self.state.set_telegraph_token(&token).await?;
+        Ok(token)
     }
 }
 
+/// Renders the post of the story for the destination, with the teaser
+/// of the Telegraph layout linking to the page url.
+fn format_post(route: &Route, story: &Story, url: &str, page_url: &str) -> anyhow::Result<Post> {
+    let (destination, renderer) = (&route.destination, &route.renderer);
+    let buttons = destination.buttons;
+    let articles_keyboard = || buttons.then(|| tg_bot::articles_keyboard(story));
+    let (messages, keyboard, reply_to) = match destination.layout {
+        Layout::Full => (
+            renderer.format_story(story, url)?,
+            articles_keyboard(),
+            ReplyTo::Previous,
+        ),
+        Layout::Telegraph => (
+            vec![renderer.format_teaser(story, page_url)?],
+            articles_keyboard(),
+            ReplyTo::Previous,
+        ),
+        Layout::Compact => (
+            vec![renderer.format_compact(story, url, None)?],
+            Some(tg_bot::compact_keyboard(story, None, buttons)),
+            ReplyTo::Previous,
+        ),
+        Layout::Thread => (
+            renderer.format_thread(story, url, &destination.side_order.0)?,
+            articles_keyboard(),
+            ReplyTo::First,
+        ),
+    };
+    Ok(Post {
+        messages,
+        keyboard,
+        reply_to,
+        // Decided by the schedule as the post is sent
+        silent: false,
+    })
+}
+
+/// Normalizes the url of a story given by the admin.
+fn story_url(url: &str) -> anyhow::Result<String> {
+    match parse_story_url(url) {
+        Some(url) => Ok(url),
+        None => bail!("{} is not an AllSides story url", url.trim()),
+    }
+}
+
+/// Describes the post in the outbox: where it goes and how its attempts went.
+fn outbox_item_status(item: &OutboxItem) -> String {
+    let status = if item.failed {
+        format!("failed after {} attempts", item.attempts)
+    } else if item.attempts > 0 || item.last_error.is_some() {
+        format!("retrying at {}", item.next_attempt_at.to_rfc3339())
+    } else {
+        "queued".to_owned()
+    };
+    let mut line = format!("{} to {}: {}", item.subject, item.destination, status);
+    if let Some(error) = &item.last_error {
+        line.push_str(&format!(" ({})", error));
+    }
+    line
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     env_logger::init();
     let config = envy::prefixed("ASTG_").from_env::<Config>()?;
-    let bot = AllSidesTgImporter::try_new(config).await?;
-    bot.run().await?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => {
+            let bot = AllSidesTgImporter::try_new(config).await?;
+            bot.run().await?;
+        }
+        ["archive", "search", terms @ ..] => {
+            let archive = Archive::try_new(&State::try_new(&config.story_db)?)?;
+            for (url, publication) in archive.search(&terms.join(" "))? {
+                println!(
+                    "{}  {}\n            {}",
+                    publication.published_at.format("%Y-%m-%d"),
+                    publication.story.title,
+                    url
+                );
+            }
+        }
+        ["archive", "reindex"] => {
+            let archive = Archive::try_new(&State::try_new(&config.story_db)?)?;
+            let count = archive.reindex().await?;
+            println!("indexed {} stories", count);
+        }
+        ["publish", url, flags @ ..] => {
+            let force = match flags {
+                [] => false,
+                ["--force"] => true,
+                _ => bail!(USAGE),
+            };
+            let mut importer = AllSidesTgImporter::try_new(config).await?;
+            println!("{}", importer.publish_url(url, force).await?);
+            // The deliveries run in the background, and would be dropped on exit
+            importer.webhooks.wait().await;
+        }
+        ["reparse"] => {
+            let path = match &config.page_archive {
+                Some(path) => path,
+                None => bail!("page archive is not configured (ASTG_PAGE_ARCHIVE)"),
+            };
+            let report = reparse::reparse(
+                &PageArchive::try_new(path)?,
+                &State::try_new(&config.story_db)?,
+            )?;
+            for page in &report.failed {
+                println!(
+                    "FAILED {} ({}): {}",
+                    page.url,
+                    page.fetched_at,
+                    page.details.join("; ")
+                );
+            }
+            for page in &report.changed {
+                println!("CHANGED {} ({})", page.url, page.fetched_at);
+                for difference in &page.details {
+                    println!("    {}", difference);
+                }
+            }
+            println!(
+                "parsed: {}, failed: {}, changed: {}",
+                report.parsed,
+                report.failed.len(),
+                report.changed.len()
+            );
+        }
+        ["email", "digest"] => {
+            let digest = EmailDigest::try_new(&config.smtp, &config.email)?;
+            let now = Utc::now();
+            let state = State::try_new(&config.story_db)?;
+            let sent = digest
+                .send(&state, now - chrono::Duration::hours(24), now)
+                .await?;
+            println!("emailed {} stories", sent);
+        }
+        ["site", "build"] => {
+            let dir = match &config.site_dir {
+                Some(dir) => dir,
+                None => bail!("site directory is not configured (ASTG_SITE_DIR)"),
+            };
+            let publications = State::try_new(&config.story_db)?
+                .publications()
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let report = site::Site::try_new()?.build(&publications, dir)?;
+            println!(
+                "stories: {}, topics: {}, sources: {}",
+                report.stories, report.topics, report.sources
+            );
+        }
+        _ => bail!(USAGE),
+    }
     Ok(())
 }
@@ -0,0 +1,411 @@
+use crate::config::{MastodonOptions, Visibility};
+use crate::publisher::{ApiError, Progress, Publisher, Receipt};
+use crate::scraper::{escape_html, Story};
+use crate::tg_bot::Post;
+use anyhow::bail;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Maximum length of a status on most instances.
+pub const STATUS_LIMIT: usize = 500;
+
+#[derive(Serialize)]
+struct NewStatus<'a> {
+    status: &'a str,
+    visibility: Visibility,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spoiler_text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_reply_to_id: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Posts stories as statuses of a Mastodon account, threading replies
+/// when a story doesn't fit into a single status.
+pub struct Mastodon {
+    client: reqwest::Client,
+    instance: String,
+    token: String,
+    visibility: Visibility,
+    content_warning: Option<String>,
+}
+
+impl Mastodon {
+    pub fn try_new(opts: &MastodonOptions) -> anyhow::Result<Self> {
+        let (instance, token) = match (&opts.instance, &opts.token) {
+            (Some(instance), Some(token)) => (instance, token),
+            _ => bail!(
+                "mastodon destinations require ASTG_MASTODON_INSTANCE and ASTG_MASTODON_TOKEN"
+            ),
+        };
+        Ok(Mastodon {
+            client: reqwest::Client::new(),
+            instance: instance.trim_end_matches('/').to_owned(),
+            token: token.clone(),
+            visibility: opts.visibility,
+            content_warning: opts.content_warning.clone(),
+        })
+    }
+
+    async fn check(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let retry_after = match status {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => retry_after(response.headers(), Utc::now()),
+            _ => None,
+        };
+        let body = response.bytes().await?;
+        let message = match serde_json::from_slice::<ErrorResponse>(&body) {
+            Ok(error) => format!("mastodon: {}: {}", status, error.error),
+            Err(_) => format!("mastodon: {}", status),
+        };
+        Err(ApiError {
+            message,
+            status,
+            retry_after,
+        }
+        .into())
+    }
+}
+
+/// Time to wait before the next request when rate limited, from `Retry-After`
+/// or else from the `X-RateLimit-Reset` time Mastodon sends with every response.
+fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(secs) = header(RETRY_AFTER.as_str()).and_then(|secs| secs.parse().ok()) {
+        return Some(Duration::from_secs(secs));
+    }
+    let reset = DateTime::parse_from_rfc3339(header("x-ratelimit-reset")?).ok()?;
+    (reset.with_timezone(&Utc) - now).to_std().ok()
+}
+
+#[async_trait]
+impl Publisher for Mastodon {
+    async fn publish(
+        &self,
+        story: Option<(&str, &Story)>,
+        _post: &Post,
+        key: &str,
+        progress: &mut dyn Progress,
+    ) -> anyhow::Result<Receipt> {
+        let (url, story) = match story {
+            Some(story) => story,
+            None => bail!("mastodon statuses are built from a single story"),
+        };
+        let mut status_ids = match progress.published() {
+            Some(Receipt::Mastodon { status_ids }) => status_ids.clone(),
+            Some(receipt) => bail!("not a mastodon post: {:?}", receipt),
+            None => Vec::new(),
+        };
+        let resumed = status_ids.len();
+        for (idx, text) in format_statuses(story, url)
+            .into_iter()
+            .enumerate()
+            .skip(resumed)
+        {
+            let status = NewStatus {
+                status: &text,
+                visibility: self.visibility,
+                spoiler_text: self.content_warning.as_deref(),
+                in_reply_to_id: status_ids.last().map(String::as_str),
+            };
+            let response = self
+                .client
+                .post(&format!("{}/api/v1/statuses", self.instance))
+                .bearer_auth(&self.token)
+                // Mastodon returns the status posted before for a key repeated within about
+                // an hour; only a best effort against duplicates, the recovery of interrupted
+                // posts relies on the recorded statuses instead
+                .header("Idempotency-Key", format!("astg-{}-{}", key, idx))
+                .json(&status)
+                .send()
+                .await?;
+            let status: Status = Self::check(response).await?.json().await?;
+            status_ids.push(status.id);
+            let receipt = Receipt::Mastodon {
+                status_ids: status_ids.clone(),
+            };
+            progress.record(&receipt).await?;
+        }
+        Ok(Receipt::Mastodon { status_ids })
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> anyhow::Result<()> {
+        let ids = match receipt {
+            Receipt::Mastodon { status_ids } => status_ids,
+            _ => bail!("not a mastodon post: {:?}", receipt),
+        };
+        for id in ids {
+            let response = self
+                .client
+                .delete(&format!("{}/api/v1/statuses/{}", self.instance, id))
+                .bearer_auth(&self.token)
+                .send()
+                .await?;
+            Self::check(response).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the story as a thread of statuses, each fitting into [`STATUS_LIMIT`]:
+/// the title and the link, a line per article headline, and the topic hashtags.
+fn format_statuses(story: &Story, url: &str) -> Vec<String> {
+    // The title is cut to leave the url whole, with the line breaks after both
+    let title_limit = STATUS_LIMIT.saturating_sub(len(url) + 2).max(1);
+    let title = truncate(story.title.trim(), title_limit);
+    let mut blocks = vec![format!("{}\n{}\n", title, url)];
+    blocks.extend(story.articles.iter().map(|article| {
+        format!(
+            "{} {}: {}",
+            article.side.emoji(),
+            article.source.trim(),
+            article.title.trim()
+        )
+    }));
+    let hashtags = story
+        .topics
+        .iter()
+        .map(|topic| hashtag(topic))
+        .filter(|hashtag| hashtag.len() > 1)
+        .collect::<Vec<_>>();
+    if !hashtags.is_empty() {
+        blocks.push(format!("\n{}", hashtags.join(" ")));
+    }
+
+    let mut statuses: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for block in blocks {
+        let block = truncate(&block, STATUS_LIMIT);
+        if !current.is_empty() && len(&current) + 1 + len(&block) > STATUS_LIMIT {
+            statuses.push(current.trim_end().to_owned());
+            current.clear();
+        }
+        if current.is_empty() {
+            current.push_str(block.trim_start_matches('\n'));
+        } else {
+            current.push('\n');
+            current.push_str(&block);
+        }
+    }
+    if !current.is_empty() {
+        statuses.push(current.trim_end().to_owned());
+    }
+    statuses
+}
+
+/// Returns the statuses of the story as HTML, to show the admin what is posted.
+pub fn preview(story: &Story, url: &str) -> Vec<String> {
+    format_statuses(story, url)
+        .iter()
+        .map(|status| escape_html(status))
+        .collect()
+}
+
+/// Makes a hashtag of the topic, e.g. `#SexualMisconduct` of "Sexual Misconduct".
+fn hashtag(topic: &str) -> String {
+    let mut hashtag = String::from("#");
+    for word in topic.split(|c: char| !c.is_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            hashtag.extend(first.to_uppercase());
+            hashtag.extend(chars);
+        }
+    }
+    hashtag
+}
+
+fn len(text: &str) -> usize {
+    text.chars().count()
+}
+
+fn truncate(text: &str, limit: usize) -> String {
+    if len(text) <= limit {
+        return text.to_owned();
+    }
+    let mut truncated: String = text.chars().take(limit - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{story, STORY_URL};
+    use crate::mock_server::MockServer;
+    use crate::tg_bot::ReplyTo;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn short_story_is_a_single_status() {
+        let statuses = format_statuses(&story(), STORY_URL);
+        assert_eq!(
+            statuses,
+            vec![
+                "NY Gov. Cuomo Accused of Sexual Harrassment; Less Coverage from Left-Rated Outlets\n\
+                 https://www.allsides.com/story/cuomo\n\
+                 \n\
+                 🟦 Vox: The sexual harassment allegation against Gov. Andrew Cuomo, explained\n\
+                 🔴 Fox News (Online News): Mainstream media ignores sexual harassment allegations against Gov. Andrew Cuomo\n\
+                 🟥 New York Post (Opinion): MeToo double standard: Evidence required when accused is a Democrat\n\
+                 \n\
+                 #SexualMisconduct"
+                    .to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn long_story_is_threaded() {
+        let mut story = story();
+        let article = story.articles[0].clone();
+        story.articles.extend(std::iter::repeat_n(article, 8));
+        let statuses = format_statuses(&story, STORY_URL);
+        assert!(statuses.len() > 1);
+        assert!(statuses.iter().all(|status| len(status) <= STATUS_LIMIT));
+        assert!(statuses.last().unwrap().ends_with("#SexualMisconduct"));
+    }
+
+    #[test]
+    fn long_title_keeps_url() {
+        let mut story = story();
+        story.title = "Long title ".repeat(100);
+        let statuses = format_statuses(&story, STORY_URL);
+        assert!(len(&statuses[0]) <= STATUS_LIMIT);
+        let first_block = statuses[0].split('\n').take(2).collect::<Vec<_>>();
+        assert!(first_block[0].ends_with('…'));
+        assert_eq!(first_block[1], STORY_URL);
+    }
+
+    #[test]
+    fn rate_limit_delay() {
+        let now = "2021-03-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "30")]), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[("x-ratelimit-reset", "2021-03-01T12:05:00.000Z")]),
+                now
+            ),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[("x-ratelimit-reset", "2021-03-01T11:55:00.000Z")]),
+                now
+            ),
+            None
+        );
+        assert_eq!(retry_after(&headers(&[]), now), None);
+    }
+
+    #[tokio::test]
+    async fn statuses_reply_to_previous() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"id":"1"}"#), (200, r#"{"id":"2"}"#)]);
+        let mastodon = Mastodon::try_new(&MastodonOptions {
+            instance: Some(server.url.clone()),
+            token: Some("TOKEN".to_owned()),
+            visibility: Visibility::Unlisted,
+            content_warning: Some("politics".to_owned()),
+        })?;
+        let mut story = story();
+        let article = story.articles[0].clone();
+        story.articles.extend(std::iter::repeat_n(article, 3));
+        let post = Post {
+            messages: vec![],
+            keyboard: None,
+            reply_to: ReplyTo::Previous,
+            silent: false,
+        };
+        let receipt = mastodon
+            .publish(Some((STORY_URL, &story)), &post, "1", &mut None)
+            .await?;
+        assert_eq!(
+            receipt,
+            Receipt::Mastodon {
+                status_ids: vec!["1".to_owned(), "2".to_owned()]
+            }
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/v1/statuses");
+        assert_eq!(requests[0].header("authorization"), Some("Bearer TOKEN"));
+        assert_eq!(requests[0].header("idempotency-key"), Some("astg-1-0"));
+        assert_eq!(requests[1].header("idempotency-key"), Some("astg-1-1"));
+        let first: serde_json::Value = serde_json::from_str(&requests[0].body)?;
+        assert_eq!(first["visibility"], "unlisted");
+        assert_eq!(first["spoiler_text"], "politics");
+        assert_eq!(first.get("in_reply_to_id"), None);
+        let second: serde_json::Value = serde_json::from_str(&requests[1].body)?;
+        assert_eq!(second["in_reply_to_id"], "1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn thread_is_resumed_after_recorded_statuses() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"id":"2"}"#)]);
+        let mastodon = Mastodon::try_new(&MastodonOptions {
+            instance: Some(server.url.clone()),
+            token: Some("TOKEN".to_owned()),
+            visibility: Visibility::Public,
+            content_warning: None,
+        })?;
+        let mut story = story();
+        let article = story.articles[0].clone();
+        story.articles.extend(std::iter::repeat_n(article, 3));
+        let post = Post {
+            messages: vec![],
+            keyboard: None,
+            reply_to: ReplyTo::Previous,
+            silent: false,
+        };
+        let mut progress = Some(Receipt::Mastodon {
+            status_ids: vec!["1".to_owned()],
+        });
+        let receipt = mastodon
+            .publish(Some((STORY_URL, &story)), &post, "1", &mut progress)
+            .await?;
+        let expected = Receipt::Mastodon {
+            status_ids: vec!["1".to_owned(), "2".to_owned()],
+        };
+        assert_eq!(receipt, expected);
+        assert_eq!(progress, Some(expected));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("idempotency-key"), Some("astg-1-1"));
+        let second: serde_json::Value = serde_json::from_str(&requests[0].body)?;
+        assert_eq!(second["in_reply_to_id"], "1");
+        Ok(())
+    }
+
+    #[test]
+    fn topics_are_hashtags() {
+        assert_eq!(hashtag("Sexual Misconduct"), "#SexualMisconduct");
+        assert_eq!(hashtag("Coronavirus, vaccines"), "#CoronavirusVaccines");
+        assert_eq!(hashtag("2020 Elections"), "#2020Elections");
+    }
+}
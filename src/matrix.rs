@@ -0,0 +1,285 @@
+use crate::config::MatrixOptions;
+use crate::publisher::{ApiError, Progress, Publisher, Receipt};
+use crate::scraper::Story;
+use crate::tg_bot::Post;
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use reqwest::Url;
+use select::document::Document;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Makes the transaction ids of the sent events unique within the process.
+static TRANSACTION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Deserialize)]
+struct EventResponse {
+    event_id: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    errcode: String,
+    error: Option<String>,
+    retry_after_ms: Option<u64>,
+}
+
+/// Client of the Matrix client-server API, see https://spec.matrix.org/latest/client-server-api/
+#[derive(Clone)]
+pub struct Matrix {
+    client: reqwest::Client,
+    homeserver: Url,
+    token: String,
+}
+
+impl Matrix {
+    pub fn try_new(opts: &MatrixOptions) -> anyhow::Result<Self> {
+        let (homeserver, token) = match (&opts.homeserver, &opts.token) {
+            (Some(homeserver), Some(token)) => (homeserver, token),
+            _ => bail!(
+                "ASTG_MATRIX_HOMESERVER and ASTG_MATRIX_TOKEN are required by matrix destinations"
+            ),
+        };
+        Ok(Matrix {
+            client: reqwest::Client::new(),
+            homeserver: Url::parse(homeserver)?,
+            token: token.clone(),
+        })
+    }
+
+    /// Returns the publisher posting to the room, given by its id, e.g. `!abc:matrix.org`.
+    pub fn room(&self, room_id: &str) -> MatrixRoom {
+        MatrixRoom {
+            matrix: self.clone(),
+            room_id: room_id.to_owned(),
+        }
+    }
+
+    /// Sends the event to the room, returns its id.
+    ///
+    /// The homeserver returns the event sent before for a transaction id repeated shortly after,
+    /// which covers the retries of a request but not a later recovery, so interrupted posts
+    /// aren't sent again.
+    async fn send(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        content: &Value,
+        transaction_id: &str,
+    ) -> anyhow::Result<String> {
+        let url = self.url(&["rooms", room_id, "send", event_type, transaction_id])?;
+        let response: EventResponse = self.call(self.client.put(url).json(content)).await?;
+        Ok(response.event_id)
+    }
+
+    async fn redact(&self, room_id: &str, event_id: &str) -> anyhow::Result<()> {
+        let url = self.url(&["rooms", room_id, "redact", event_id, &transaction_id()])?;
+        let _: EventResponse = self.call(self.client.put(url).json(&json!({}))).await?;
+        Ok(())
+    }
+
+    /// Makes the url of the endpoint, percent-encoding the path segments.
+    fn url(&self, path: &[&str]) -> anyhow::Result<Url> {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid matrix homeserver url: {}", self.homeserver))?
+            .pop_if_empty()
+            .extend(&["_matrix", "client", "r0"])
+            .extend(path);
+        Ok(url)
+    }
+
+    async fn call<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<T> {
+        let response = request.bearer_auth(&self.token).send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            return Err(match serde_json::from_slice::<ErrorResponse>(&body) {
+                Ok(error) => ApiError {
+                    message: format!(
+                        "matrix: {}: {}",
+                        error.errcode,
+                        error.error.unwrap_or_default()
+                    ),
+                    status,
+                    retry_after: error.retry_after_ms.map(Duration::from_millis),
+                },
+                Err(_) => ApiError {
+                    message: format!("matrix: {}", status),
+                    status,
+                    retry_after: None,
+                },
+            }
+            .into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Publishes posts to a Matrix room, the post keyboards aren't supported and are dropped.
+pub struct MatrixRoom {
+    matrix: Matrix,
+    room_id: String,
+}
+
+impl MatrixRoom {
+    fn receipt(&self, event_ids: &[String]) -> Receipt {
+        Receipt::Matrix {
+            room_id: self.room_id.clone(),
+            event_ids: event_ids.to_vec(),
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for MatrixRoom {
+    async fn publish(
+        &self,
+        _story: Option<(&str, &Story)>,
+        post: &Post,
+        key: &str,
+        progress: &mut dyn Progress,
+    ) -> anyhow::Result<Receipt> {
+        let mut event_ids = match progress.published() {
+            Some(Receipt::Matrix { event_ids, .. }) => event_ids.clone(),
+            Some(receipt) => bail!("not a matrix post: {:?}", receipt),
+            None => Vec::with_capacity(post.messages.len()),
+        };
+        let resumed = event_ids.len();
+        for (idx, html) in post.messages.iter().enumerate().skip(resumed) {
+            let mut content = message_content(html);
+            if let Some(parent) = post.reply_to.parent(&event_ids) {
+                content["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": parent } });
+            }
+            let event_id = self
+                .matrix
+                .send(
+                    &self.room_id,
+                    "m.room.message",
+                    &content,
+                    &format!("astg-{}-{}", key, idx),
+                )
+                .await?;
+            event_ids.push(event_id);
+            progress.record(&self.receipt(&event_ids)).await?;
+        }
+        Ok(self.receipt(&event_ids))
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> anyhow::Result<()> {
+        let (room_id, event_ids) = match receipt {
+            Receipt::Matrix { room_id, event_ids } => (room_id, event_ids),
+            _ => bail!("not a matrix post: {:?}", receipt),
+        };
+        for event_id in event_ids {
+            self.matrix.redact(room_id, event_id).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Makes a message with the HTML as its formatted body, and the HTML text as its plain body.
+fn message_content(html: &str) -> Value {
+    let document = Document::from(html);
+    let body = document.nth(0).map(|root| root.text()).unwrap_or_default();
+    json!({
+        "msgtype": "m.text",
+        "body": body,
+        "format": "org.matrix.custom.html",
+        "formatted_body": html.replace('\n', "<br>"),
+    })
+}
+
+fn transaction_id() -> String {
+    format!(
+        "astg-{}-{}",
+        chrono::Utc::now().timestamp_millis(),
+        TRANSACTION_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use crate::tg_bot::ReplyTo;
+    use pretty_assertions::assert_eq;
+
+    fn room(server: &MockServer) -> MatrixRoom {
+        let matrix = Matrix::try_new(&MatrixOptions {
+            homeserver: Some(server.url.clone()),
+            token: Some("TOKEN".to_owned()),
+        })
+        .unwrap();
+        matrix.room("!room:localhost")
+    }
+
+    #[tokio::test]
+    async fn publish_thread() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            (200, r#"{"event_id":"$first"}"#),
+            (200, r#"{"event_id":"$second"}"#),
+        ]);
+        let post = Post {
+            messages: vec![
+                "<b>Title</b> &amp; more\nline".to_owned(),
+                "<a href=\"https://example.com\">article</a>".to_owned(),
+            ],
+            keyboard: None,
+            reply_to: ReplyTo::First,
+            silent: false,
+        };
+        let receipt = room(&server).publish(None, &post, "1", &mut None).await?;
+        assert_eq!(
+            receipt,
+            Receipt::Matrix {
+                room_id: "!room:localhost".to_owned(),
+                event_ids: vec!["$first".to_owned(), "$second".to_owned()],
+            }
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(
+            requests[0].path,
+            "/_matrix/client/r0/rooms/!room:localhost/send/m.room.message/astg-1-0"
+        );
+        assert!(requests[1].path.ends_with("/astg-1-1"));
+        assert_eq!(requests[0].header("authorization"), Some("Bearer TOKEN"));
+
+        let first: Value = serde_json::from_str(&requests[0].body)?;
+        assert_eq!(first["body"], "Title & more\nline");
+        assert_eq!(first["formatted_body"], "<b>Title</b> &amp; more<br>line");
+        assert_eq!(first.get("m.relates_to"), None);
+        let second: Value = serde_json::from_str(&requests[1].body)?;
+        assert_eq!(
+            second["m.relates_to"]["m.in_reply_to"]["event_id"],
+            "$first"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn api_error() {
+        let server = MockServer::start(vec![(
+            403,
+            r#"{"errcode":"M_FORBIDDEN","error":"not in the room"}"#,
+        )]);
+        let post = Post {
+            messages: vec!["text".to_owned()],
+            keyboard: None,
+            reply_to: ReplyTo::Previous,
+            silent: false,
+        };
+        let err = room(&server)
+            .publish(None, &post, "1", &mut None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "matrix: M_FORBIDDEN: not in the room");
+    }
+}
@@ -0,0 +1,684 @@
+use crate::publisher::{Failure, Progress, Receipt};
+use crate::routing::Route;
+use crate::schedule::Schedule;
+use crate::scraper::Story;
+use crate::state::{OutboxItem, PublishedPost, State, Subject};
+use crate::tg_bot::{Post, ReplyTo};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Attempts to send a post before it's kept in the outbox as failed.
+const ATTEMPTS: u32 = 8;
+/// Delay after the first failed attempt to send a post, doubled after every attempt.
+const RETRY_DELAY_SECS: i64 = 30;
+const MAX_RETRY_DELAY_SECS: i64 = 60 * 60;
+/// Shortest wait after a rate limit, so a platform asking to retry right away isn't flooded.
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// What became of an attempt to send a post of the outbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The post is published and recorded, it left the outbox
+    Sent,
+    /// Sending failed or was rate-limited, the post is sent again later
+    Retrying,
+    /// Sending failed for good with the error, the post is kept for the admin
+    Failed(String),
+    /// Sending the post was interrupted by a crash or an error at the time, before any of its
+    /// messages was recorded, and sending it again could publish it twice,
+    /// the post is kept for the admin to check
+    Interrupted(DateTime<Utc>),
+}
+
+/// An attempt to send a post of the outbox.
+#[derive(Debug)]
+pub struct Attempt {
+    pub id: u64,
+    pub item: OutboxItem,
+    pub outcome: Outcome,
+}
+
+/// Sends the rendered posts queued in the state to their destinations.
+///
+/// The posts to a destination are sent in order: a post waiting to be sent again,
+/// after a failure or a rate limit, holds back the later posts to its destination.
+/// A failed post doesn't, it waits aside for the admin to retry or drop it.
+///
+/// A post is marked as being sent before it's sent, its messages are recorded as they're
+/// published, and it's removed from the outbox as it's recorded as published. A post still
+/// marked when it's picked again was interrupted by a crash or an error: it's resumed after
+/// its recorded messages if any were, otherwise it's held for the admin as it may
+/// have been published.
+pub struct Outbox {
+    state: State,
+    routes: Arc<Vec<Route>>,
+    schedule: Schedule,
+}
+
+impl Outbox {
+    pub fn new(state: &State, routes: &Arc<Vec<Route>>, schedule: &Schedule) -> Self {
+        Outbox {
+            state: state.clone(),
+            routes: routes.clone(),
+            schedule: schedule.clone(),
+        }
+    }
+
+    /// Queues the post of the story to the destination, returns its id.
+    pub async fn queue(
+        &mut self,
+        story: &Story,
+        url: &str,
+        destination: &str,
+        post: Post,
+    ) -> anyhow::Result<u64> {
+        let subject = Subject::Story {
+            url: url.to_owned(),
+            story: story.clone(),
+        };
+        let item = OutboxItem::new(subject, destination, post);
+        self.state.add_to_outbox(&item).await
+    }
+
+    /// Queues the digests of the destinations in the digest mode which are due at `now`,
+    /// returns the number of queued digests.
+    ///
+    /// A digest is due once its time of the day has passed since the previous one was sent.
+    /// It's recorded as sent as it's queued, along with the stories it's the digest of,
+    /// so it's queued only once. A due digest without stories is recorded as sent too.
+    pub async fn queue_digests(&mut self, now: DateTime<Utc>) -> anyhow::Result<usize> {
+        let mut queued = 0;
+        let routes = self.routes.clone();
+        for route in routes.iter() {
+            let destination = &route.destination;
+            let due = match destination.digest_at {
+                Some(digest_at) => self.schedule.last_occurrence(digest_at, now),
+                None => continue,
+            };
+            if self
+                .state
+                .digest_sent_at(&destination.name)?
+                .is_some_and(|sent_at| sent_at >= due)
+            {
+                continue;
+            }
+
+            let entries = self.state.digest(&destination.name)?;
+            let item = if entries.is_empty() {
+                None
+            } else {
+                let stories = entries
+                    .iter()
+                    .map(|(_, entry)| (entry.url.clone(), entry.story.clone()))
+                    .collect::<Vec<_>>();
+                let post = Post {
+                    messages: route
+                        .renderer
+                        .format_digest(&stories, self.schedule.local_date(due))?,
+                    keyboard: None,
+                    reply_to: ReplyTo::Previous,
+                    // Decided by the schedule as the post is sent
+                    silent: false,
+                };
+                let subject = Subject::Digest {
+                    urls: stories.into_iter().map(|(url, _)| url).collect(),
+                };
+                Some(OutboxItem::new(subject, &destination.name, post))
+            };
+            self.state
+                .digest_sent(&destination.name, &entries, item.as_ref(), now)
+                .await?;
+            if item.is_some() {
+                log::info!(
+                    "queued the digest of {} stories to {}",
+                    entries.len(),
+                    destination.name
+                );
+                queued += 1;
+            }
+        }
+        Ok(queued)
+    }
+
+    /// Makes the post due right away, starting its attempts over, returns it if it's queued.
+    pub async fn retry(&mut self, id: u64) -> anyhow::Result<Option<OutboxItem>> {
+        let mut item = match self.state.outbox_item(id)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        item.failed = false;
+        item.attempts = 0;
+        item.sending_since = None;
+        item.next_attempt_at = Utc::now();
+        self.state.update_outbox(id, &item).await?;
+        Ok(Some(item))
+    }
+
+    /// Returns when the next post can be sent, if any post is waiting.
+    ///
+    /// Only the first post waiting for each destination counts,
+    /// as the later ones are held back until it's sent.
+    pub fn next_attempt(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let mut next = None;
+        for (_, item) in self.waiting()? {
+            let due_at = self.due_at(&item)?;
+            next = Some(next.map_or(due_at, |next: DateTime<Utc>| next.min(due_at)));
+        }
+        Ok(next)
+    }
+
+    /// Sends the oldest post which is due, returns what became of it,
+    /// or `None` if no post is due.
+    pub async fn send_next(&mut self) -> anyhow::Result<Option<Attempt>> {
+        let now = Utc::now();
+        for (id, mut item) in self.waiting()? {
+            if self.due_at(&item)? <= now {
+                let outcome = self.send(id, &mut item).await?;
+                return Ok(Some(Attempt { id, item, outcome }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the first post waiting to be sent to each destination, oldest first.
+    fn waiting(&self) -> anyhow::Result<Vec<(u64, OutboxItem)>> {
+        let mut destinations = HashSet::new();
+        Ok(self
+            .state
+            .outbox()?
+            .into_iter()
+            .filter(|(_, item)| !item.failed && destinations.insert(item.destination.clone()))
+            .collect())
+    }
+
+    async fn send(&mut self, id: u64, item: &mut OutboxItem) -> anyhow::Result<Outcome> {
+        let routes = self.routes.clone();
+        let route = routes
+            .iter()
+            .find(|route| route.destination.name == item.destination);
+        let result = match route {
+            Some(_) if item.sending_since.is_some() && item.published.is_none() => {
+                return self.hold_interrupted(id, item).await;
+            }
+            Some(route) => {
+                let now = Utc::now();
+                item.sending_since = Some(now);
+                self.state.update_outbox(id, item).await?;
+                let subject = item.subject.clone();
+                let story = match &subject {
+                    Subject::Story { url, story } => Some((url.as_str(), story)),
+                    Subject::Digest { .. } | Subject::Stats => None,
+                };
+                let mut post = item.post.clone();
+                post.silent = self.schedule.is_silent(now);
+                let mut progress = ItemProgress {
+                    state: &mut self.state,
+                    id,
+                    item,
+                };
+                route
+                    .publisher
+                    .publish(story, &post, &id.to_string(), &mut progress)
+                    .await
+            }
+            None => Err(anyhow!("unknown destination: {}", item.destination)),
+        };
+        let err = match result {
+            Ok(receipt) => {
+                self.state
+                    .set_last_post_at(&item.destination, Utc::now())
+                    .await?;
+                let post = PublishedPost {
+                    destination: item.destination.clone(),
+                    receipt,
+                };
+                // An error here leaves the post marked as being sent
+                self.state.confirm_post(id, &item.subject, post).await?;
+                return Ok(Outcome::Sent);
+            }
+            Err(e) => e,
+        };
+
+        let now = Utc::now();
+        item.sending_since = None;
+        item.last_error = Some(format!("{:#}", err));
+        match Failure::of(&err) {
+            Failure::RetryAfter(delay) => {
+                log::warn!("{} is rate-limited for {:?}", item.destination, delay);
+                item.next_attempt_at =
+                    now + chrono::Duration::from_std(delay.max(MIN_RETRY_AFTER))?;
+            }
+            Failure::Transient if item.attempts + 1 < ATTEMPTS => {
+                log::warn!(
+                    "failed to publish {} to {}, retrying: {:#}",
+                    item.subject,
+                    item.destination,
+                    err
+                );
+                let delay = (RETRY_DELAY_SECS << item.attempts.min(16)).min(MAX_RETRY_DELAY_SECS);
+                item.attempts += 1;
+                item.next_attempt_at = now + chrono::Duration::seconds(delay);
+            }
+            Failure::Transient | Failure::Permanent => {
+                log::error!(
+                    "failed to publish {} to {}: {:#}",
+                    item.subject,
+                    item.destination,
+                    err
+                );
+                item.attempts += 1;
+                item.failed = true;
+            }
+        }
+        self.state.update_outbox(id, item).await?;
+        Ok(if item.failed {
+            Outcome::Failed(format!("{:#}", err))
+        } else {
+            Outcome::Retrying
+        })
+    }
+
+    /// Keeps the post interrupted while it was being sent for the admin to check
+    /// whether it was published.
+    async fn hold_interrupted(
+        &mut self,
+        id: u64,
+        item: &mut OutboxItem,
+    ) -> anyhow::Result<Outcome> {
+        let since = item.sending_since.unwrap_or_else(Utc::now);
+        log::warn!(
+            "publishing {} to {} was interrupted at {}",
+            item.subject,
+            item.destination,
+            since
+        );
+        item.failed = true;
+        item.last_error = Some(format!(
+            "interrupted while publishing at {}, it may have been published",
+            since.to_rfc3339()
+        ));
+        self.state.update_outbox(id, item).await?;
+        Ok(Outcome::Interrupted(since))
+    }
+
+    /// Returns when the post can be sent: once it's due to be retried,
+    /// outside of the quiet hours and spaced from the previous post to its destination.
+    fn due_at(&self, item: &OutboxItem) -> anyhow::Result<DateTime<Utc>> {
+        let last_post_at = self.state.last_post_at(&item.destination)?;
+        Ok(self.schedule.next_slot(item.next_attempt_at, last_post_at))
+    }
+}
+
+/// Records the published messages of a post in its outbox item.
+struct ItemProgress<'a> {
+    state: &'a mut State,
+    id: u64,
+    item: &'a mut OutboxItem,
+}
+
+#[async_trait]
+impl Progress for ItemProgress<'_> {
+    fn published(&self) -> Option<&Receipt> {
+        self.item.published.as_ref()
+    }
+
+    async fn record(&mut self, receipt: &Receipt) -> anyhow::Result<()> {
+        self.item.published = Some(receipt.clone());
+        self.state.update_outbox(self.id, self.item).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Destination, MatrixOptions, ScheduleOptions};
+    use crate::discord::DiscordWebhook;
+    use crate::fixtures::{self, STORY_URL};
+    use crate::matrix::Matrix;
+    use crate::mock_server::MockServer;
+    use crate::render::Renderer;
+    use crate::tg_bot::ReplyTo;
+    use pretty_assertions::assert_eq;
+
+    fn discord_route(server: &MockServer) -> Route {
+        let destination: Destination = serde_json::from_value(serde_json::json!({
+            "name": "discord",
+            "platform": "discord",
+            "chat": server.url,
+        }))
+        .unwrap();
+        Route {
+            renderer: Renderer::try_new().unwrap(),
+            publisher: Box::new(DiscordWebhook::new(&server.url)),
+            destination,
+        }
+    }
+
+    fn matrix_route(server: &MockServer) -> Route {
+        let destination: Destination = serde_json::from_value(serde_json::json!({
+            "name": "matrix",
+            "platform": "matrix",
+            "chat": "!room:localhost",
+        }))
+        .unwrap();
+        let matrix = Matrix::try_new(&MatrixOptions {
+            homeserver: Some(server.url.clone()),
+            token: Some("TOKEN".to_owned()),
+        })
+        .unwrap();
+        Route {
+            renderer: Renderer::try_new().unwrap(),
+            publisher: Box::new(matrix.room("!room:localhost")),
+            destination,
+        }
+    }
+
+    fn digest_route(server: &MockServer) -> Route {
+        let mut route = matrix_route(server);
+        route.destination = serde_json::from_value(serde_json::json!({
+            "name": "daily",
+            "platform": "matrix",
+            "chat": "!room:localhost",
+            "digest_at": "08:00:00",
+        }))
+        .unwrap();
+        route
+    }
+
+    fn matrix_receipt(event_ids: &[&str]) -> Receipt {
+        Receipt::Matrix {
+            room_id: "!room:localhost".to_owned(),
+            event_ids: event_ids.iter().map(|&id| id.to_owned()).collect(),
+        }
+    }
+
+    fn outbox(state: &State, routes: Vec<Route>) -> Outbox {
+        spaced_outbox(state, routes, 0)
+    }
+
+    fn spaced_outbox(state: &State, routes: Vec<Route>, spacing: u32) -> Outbox {
+        let schedule = Schedule::new(&ScheduleOptions {
+            timezone: chrono_tz::UTC,
+            quiet_hours: None,
+            silent_hours: None,
+            spacing,
+        });
+        Outbox::new(state, &Arc::new(routes), &schedule)
+    }
+
+    fn post() -> Post {
+        Post {
+            messages: vec!["post".to_owned()],
+            keyboard: None,
+            reply_to: ReplyTo::Previous,
+            silent: false,
+        }
+    }
+
+    fn thread() -> Post {
+        Post {
+            messages: vec!["first".to_owned(), "second".to_owned()],
+            ..post()
+        }
+    }
+
+    /// Marks the post as interrupted while it was being sent, after the published messages.
+    async fn interrupt(
+        state: &mut State,
+        id: u64,
+        published: Option<Receipt>,
+    ) -> anyhow::Result<DateTime<Utc>> {
+        let mut item = state.outbox_item(id)?.unwrap();
+        let since = Utc::now();
+        item.sending_since = Some(since);
+        item.published = published;
+        state.update_outbox(id, &item).await?;
+        Ok(since)
+    }
+
+    #[tokio::test]
+    async fn sent_post_is_recorded() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"id":"1"}"#)]);
+        let state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![discord_route(&server)]);
+        let story = fixtures::story();
+
+        let id = outbox.queue(&story, STORY_URL, "discord", post()).await?;
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!((attempt.id, attempt.outcome), (id, Outcome::Sent));
+        assert!(outbox.send_next().await?.is_none());
+        assert_eq!(outbox.next_attempt()?, None);
+        assert!(state.outbox()?.is_empty());
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(
+            publication.post("discord").unwrap().receipt,
+            Receipt::Discord {
+                webhook_message_ids: vec!["1".to_owned()]
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spacing_survives_restart() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"id":"1"}"#)]);
+        let state = State::try_new_temporary()?;
+        let story = fixtures::story();
+
+        let mut outbox = spaced_outbox(&state, vec![discord_route(&server)], 60);
+        outbox.queue(&story, STORY_URL, "discord", post()).await?;
+        outbox.send_next().await?.unwrap();
+
+        let mut outbox = spaced_outbox(&state, vec![discord_route(&server)], 60);
+        let later = "https://www.allsides.com/story/later";
+        outbox.queue(&story, later, "discord", post()).await?;
+        assert!(outbox.send_next().await?.is_none());
+        let next_attempt = outbox.next_attempt()?.unwrap();
+        assert!(next_attempt > Utc::now() + chrono::Duration::minutes(59));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limited_post_holds_back_later_posts() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            (
+                429,
+                r#"{"message":"You are being rate limited.","retry_after":60.0}"#,
+            ),
+            (200, r#"{"id":"1"}"#),
+        ]);
+        let state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![discord_route(&server)]);
+        let story = fixtures::story();
+
+        outbox.queue(&story, STORY_URL, "discord", post()).await?;
+        outbox
+            .queue(
+                &story,
+                "https://www.allsides.com/story/later",
+                "discord",
+                post(),
+            )
+            .await?;
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(attempt.outcome, Outcome::Retrying);
+        assert_eq!(attempt.item.subject.url(), Some(STORY_URL));
+
+        // The later post is due, but waits for the rate-limited one
+        assert!(outbox.send_next().await?.is_none());
+        assert_eq!(server.requests().len(), 1);
+        let next_attempt = outbox.next_attempt()?.unwrap();
+        assert!(next_attempt > Utc::now() + chrono::Duration::seconds(50));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_post_waits_aside() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            (403, r#"{"message":"Missing Access"}"#),
+            (200, r#"{"id":"2"}"#),
+        ]);
+        let state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![discord_route(&server)]);
+        let story = fixtures::story();
+
+        let failed = outbox.queue(&story, STORY_URL, "discord", post()).await?;
+        let later = "https://www.allsides.com/story/later";
+        outbox.queue(&story, later, "discord", post()).await?;
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(
+            attempt.outcome,
+            Outcome::Failed("discord: 403 Forbidden: Missing Access".to_owned())
+        );
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(
+            (attempt.item.subject.url(), attempt.outcome),
+            (Some(later), Outcome::Sent)
+        );
+
+        assert_eq!(outbox.next_attempt()?, None);
+        assert!(state.is_queued(STORY_URL)?);
+        assert!(outbox.retry(failed).await?.is_some());
+        assert!(outbox.next_attempt()?.unwrap() <= Utc::now());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_post_is_held() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"id":"1"}"#)]);
+        let mut state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![discord_route(&server)]);
+
+        let id = outbox
+            .queue(&fixtures::story(), STORY_URL, "discord", post())
+            .await?;
+        let since = interrupt(&mut state, id, None).await?;
+
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(attempt.outcome, Outcome::Interrupted(since));
+        assert!(server.requests().is_empty());
+        assert!(state.outbox_item(id)?.unwrap().failed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn messages_are_recorded_as_sent() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            (200, r#"{"event_id":"$first"}"#),
+            (
+                403,
+                r#"{"errcode":"M_FORBIDDEN","error":"not in the room"}"#,
+            ),
+            (200, r#"{"event_id":"$second"}"#),
+        ]);
+        let state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![matrix_route(&server)]);
+
+        let id = outbox
+            .queue(&fixtures::story(), STORY_URL, "matrix", thread())
+            .await?;
+        let attempt = outbox.send_next().await?.unwrap();
+        assert!(matches!(attempt.outcome, Outcome::Failed(_)));
+        assert_eq!(
+            state.outbox_item(id)?.unwrap().published,
+            Some(matrix_receipt(&["$first"]))
+        );
+
+        // The retry resumes the thread after the published message
+        outbox.retry(id).await?;
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(attempt.outcome, Outcome::Sent);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].path.ends_with(&format!("/astg-{}-1", id)));
+        assert!(requests[2].body.contains("$first"));
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(
+            publication.post("matrix").unwrap().receipt,
+            matrix_receipt(&["$first", "$second"])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_thread_is_resumed() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"event_id":"$second"}"#)]);
+        let mut state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![matrix_route(&server)]);
+
+        let id = outbox
+            .queue(&fixtures::story(), STORY_URL, "matrix", thread())
+            .await?;
+        interrupt(&mut state, id, Some(matrix_receipt(&["$first"]))).await?;
+
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(attempt.outcome, Outcome::Sent);
+        assert_eq!(server.requests().len(), 1);
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(
+            publication.post("matrix").unwrap().receipt,
+            matrix_receipt(&["$first", "$second"])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn published_thread_is_settled() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"event_id":"$third"}"#)]);
+        let mut state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![matrix_route(&server)]);
+
+        let id = outbox
+            .queue(&fixtures::story(), STORY_URL, "matrix", thread())
+            .await?;
+        let receipt = matrix_receipt(&["$first", "$second"]);
+        interrupt(&mut state, id, Some(receipt.clone())).await?;
+
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(attempt.outcome, Outcome::Sent);
+        assert!(server.requests().is_empty());
+        assert!(state.outbox()?.is_empty());
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(publication.post("matrix").unwrap().receipt, receipt);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn digest_is_queued_once_a_day() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, r#"{"event_id":"$digest"}"#)]);
+        let mut state = State::try_new_temporary()?;
+        let mut outbox = outbox(&state, vec![digest_route(&server)]);
+        let at = |at: &str| at.parse::<DateTime<Utc>>().unwrap();
+        let story = fixtures::story();
+        state.add_to_digest("daily", STORY_URL, &story).await?;
+        state.set_published(STORY_URL, &story).await?;
+
+        assert_eq!(outbox.queue_digests(at("2021-03-01T09:00:00Z")).await?, 1);
+        assert!(state.digest("daily")?.is_empty());
+        assert_eq!(outbox.queue_digests(at("2021-03-01T10:00:00Z")).await?, 0);
+
+        // Collected after the digest, the story waits for the next day
+        let later = "https://www.allsides.com/story/later";
+        state.add_to_digest("daily", later, &story).await?;
+        assert_eq!(outbox.queue_digests(at("2021-03-01T23:00:00Z")).await?, 0);
+        assert_eq!(state.digest("daily")?.len(), 1);
+
+        let attempt = outbox.send_next().await?.unwrap();
+        assert_eq!(attempt.outcome, Outcome::Sent);
+        assert!(server.requests()[0].body.contains("Cuomo"));
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(
+            publication.post("daily").unwrap().receipt,
+            matrix_receipt(&["$digest"])
+        );
+        assert!(!publication.partial);
+
+        assert_eq!(outbox.queue_digests(at("2021-03-02T09:00:00Z")).await?, 1);
+        assert!(state.digest("daily")?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Separates the url from the fetch time in page keys.
+const KEY_SEPARATOR: u8 = 0xff;
+
+/// Number of the latest distinct copies of a page kept, the older ones are dropped.
+const KEPT_COPIES: usize = 10;
+
+/// Raw HTML of the loaded pages, gzipped and keyed by url and fetch time,
+/// so pages can be re-parsed after the scraper changes. Only the latest copies
+/// of a page are kept, the main page changes with every new story.
+#[derive(Debug, Clone)]
+pub struct PageArchive {
+    pages: sled::Db,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchivedPage {
+    pub url: String,
+    pub fetched_at: DateTime<Utc>,
+    pub source: String,
+}
+
+impl PageArchive {
+    pub fn try_new(path: &Path) -> anyhow::Result<Self> {
+        let pages = crate::state::open_db(path)?;
+        Ok(PageArchive { pages })
+    }
+
+    /// Stores the page, unless it is identical to the latest stored copy of the same url,
+    /// and drops the copies of the url beyond [`KEPT_COPIES`].
+    pub async fn insert(
+        &self,
+        url: &str,
+        fetched_at: DateTime<Utc>,
+        source: &str,
+    ) -> anyhow::Result<()> {
+        if let Some(latest) = self.pages.scan_prefix(url_prefix(url)).values().next_back() {
+            if decompress(&latest?)? == source {
+                return Ok(());
+            }
+        }
+
+        let mut key = url_prefix(url);
+        key.extend_from_slice(&fetched_at.timestamp_millis().to_be_bytes());
+        self.pages.insert(key, compress(source)?)?;
+        let keys = self
+            .pages
+            .scan_prefix(url_prefix(url))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in keys.iter().rev().skip(KEPT_COPIES) {
+            self.pages.remove(key)?;
+        }
+        self.pages.flush_async().await?;
+        Ok(())
+    }
+
+    /// Iterates over the stored pages ordered by url and fetch time.
+    pub fn pages(&self) -> impl Iterator<Item = anyhow::Result<ArchivedPage>> {
+        self.pages.iter().map(|entry| {
+            let (key, value) = entry?;
+            // The fetch time is a fixed-size suffix, it may contain the separator byte itself
+            let separator = key
+                .len()
+                .checked_sub(9)
+                .filter(|&idx| key[idx] == KEY_SEPARATOR)
+                .ok_or_else(|| anyhow::anyhow!("malformed page archive key"))?;
+            let mut millis = [0; 8];
+            millis.copy_from_slice(&key[separator + 1..]);
+            Ok(ArchivedPage {
+                url: String::from_utf8(key[..separator].to_vec())?,
+                fetched_at: Utc.timestamp_millis(i64::from_be_bytes(millis)),
+                source: decompress(&value)?,
+            })
+        })
+    }
+}
+
+fn url_prefix(url: &str) -> Vec<u8> {
+    let mut key = url.as_bytes().to_vec();
+    key.push(KEY_SEPARATOR);
+    key
+}
+
+fn compress(source: &str) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(source.as_bytes())?;
+    Ok(encoder.finish()?)
+}
+
+fn decompress(data: &[u8]) -> anyhow::Result<String> {
+    let mut source = String::new();
+    GzDecoder::new(data).read_to_string(&mut source)?;
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn pages_roundtrip() -> anyhow::Result<()> {
+        let archive = PageArchive {
+            pages: sled::Config::new().temporary(true).open()?,
+        };
+        let url = "https://www.allsides.com/story/test";
+        // 0xff in the timestamp bytes must not be mistaken for the separator
+        let fetched_at = Utc.timestamp_millis(0xff_ff);
+        archive.insert(url, fetched_at, "<html>1</html>").await?;
+        archive.insert(url, fetched_at, "<html>1</html>").await?;
+        archive
+            .insert(url, Utc.timestamp_millis(0x1_00_00), "<html>2</html>")
+            .await?;
+
+        let pages = archive.pages().collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].url, url);
+        assert_eq!(pages[0].fetched_at, fetched_at);
+        assert_eq!(pages[0].source, "<html>1</html>");
+        assert_eq!(pages[1].source, "<html>2</html>");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn latest_copies_are_kept() -> anyhow::Result<()> {
+        let archive = PageArchive {
+            pages: sled::Config::new().temporary(true).open()?,
+        };
+        let url = "https://www.allsides.com/unbiased-balanced-news";
+        for idx in 0..KEPT_COPIES + 2 {
+            archive
+                .insert(
+                    url,
+                    Utc.timestamp_millis(idx as i64),
+                    &format!("<html>{}</html>", idx),
+                )
+                .await?;
+        }
+        archive
+            .insert(
+                "https://www.allsides.com/story/test",
+                Utc.timestamp_millis(0),
+                "<html>story</html>",
+            )
+            .await?;
+
+        let pages = archive.pages().collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(pages.len(), KEPT_COPIES + 1);
+        assert_eq!(pages[0].source, "<html>story</html>");
+        assert_eq!(pages[1].source, "<html>2</html>");
+        assert_eq!(
+            pages[KEPT_COPIES].fetched_at,
+            Utc.timestamp_millis(KEPT_COPIES as i64 + 1)
+        );
+        Ok(())
+    }
+}
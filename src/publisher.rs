@@ -0,0 +1,174 @@
+use crate::scraper::Story;
+use crate::tg_bot::Post;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Publishes stories to a chat of a messaging platform.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    /// Publishes the post, returns where its messages were published.
+    ///
+    /// The post is rendered with the templates of the destination. The url and the story
+    /// are set for the post of a single story, publishers with their own formatting build
+    /// their messages from the story instead, and reject the posts of several stories.
+    /// The key is the same for every attempt to publish the post, for the ids
+    /// of the requests the platforms deduplicate for a short while.
+    ///
+    /// Publishers sending a post as several messages record them in the progress as they're
+    /// sent, and resume the post after the messages recorded by the previous attempts.
+    async fn publish(
+        &self,
+        story: Option<(&str, &Story)>,
+        post: &Post,
+        key: &str,
+        progress: &mut dyn Progress,
+    ) -> anyhow::Result<Receipt>;
+
+    /// Deletes the messages of a post published before.
+    async fn delete(&self, receipt: &Receipt) -> anyhow::Result<()>;
+}
+
+/// The messages of a post published so far, see [`Publisher::publish`].
+#[async_trait]
+pub trait Progress: Send {
+    /// The messages published by the previous attempts to publish the post.
+    fn published(&self) -> Option<&Receipt>;
+
+    /// Records the messages published so far, including the previous attempts.
+    async fn record(&mut self, receipt: &Receipt) -> anyhow::Result<()>;
+}
+
+/// Progress kept only in memory, for the posts which aren't resumed.
+#[async_trait]
+impl Progress for Option<Receipt> {
+    fn published(&self) -> Option<&Receipt> {
+        self.as_ref()
+    }
+
+    async fn record(&mut self, receipt: &Receipt) -> anyhow::Result<()> {
+        *self = Some(receipt.clone());
+        Ok(())
+    }
+}
+
+/// The messages a post was published as, addressed the way their platform does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Receipt {
+    Telegram {
+        chat_id: i64,
+        messages: Vec<i32>,
+    },
+    Matrix {
+        room_id: String,
+        event_ids: Vec<String>,
+    },
+    Discord {
+        webhook_message_ids: Vec<String>,
+    },
+    Mastodon {
+        status_ids: Vec<String>,
+    },
+}
+
+/// Error response of the API of a platform, for the platforms without a client library.
+#[derive(Debug)]
+pub struct ApiError {
+    pub message: String,
+    pub status: reqwest::StatusCode,
+    /// How long the platform asked to wait before trying again, if it rate-limited the request
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// How a failed publication is to be retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The platform rate-limited the request, it can be sent again after the delay
+    RetryAfter(Duration),
+    /// The platform or the network failed, the request can be sent again later
+    Transient,
+    /// The request was rejected, sending it again won't help
+    Permanent,
+}
+
+impl Failure {
+    /// Classifies the error of [`Publisher::publish`] by its causes.
+    pub fn of(err: &anyhow::Error) -> Failure {
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<teloxide::RequestError>() {
+                return match err {
+                    teloxide::RequestError::RetryAfter(secs) => {
+                        Failure::RetryAfter(Duration::from_secs((*secs).max(0) as u64))
+                    }
+                    teloxide::RequestError::NetworkError(_) => Failure::Transient,
+                    teloxide::RequestError::ApiError { status_code, .. }
+                        if status_code.is_server_error() =>
+                    {
+                        Failure::Transient
+                    }
+                    _ => Failure::Permanent,
+                };
+            }
+            if let Some(err) = cause.downcast_ref::<ApiError>() {
+                return match err.retry_after {
+                    Some(delay) => Failure::RetryAfter(delay),
+                    None if err.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || err.status.is_server_error() =>
+                    {
+                        Failure::Transient
+                    }
+                    None => Failure::Permanent,
+                };
+            }
+            if cause.is::<reqwest::Error>() || cause.is::<std::io::Error>() {
+                return Failure::Transient;
+            }
+        }
+        Failure::Permanent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn failures_are_classified() {
+        let api_error = |status: u16, retry_after: Option<Duration>| {
+            anyhow::Error::new(ApiError {
+                message: "error".to_owned(),
+                status: reqwest::StatusCode::from_u16(status).unwrap(),
+                retry_after,
+            })
+        };
+        assert_eq!(
+            Failure::of(&anyhow::Error::new(teloxide::RequestError::RetryAfter(30))),
+            Failure::RetryAfter(Duration::from_secs(30))
+        );
+        assert_eq!(
+            Failure::of(&api_error(429, Some(Duration::from_millis(1500)))),
+            Failure::RetryAfter(Duration::from_millis(1500))
+        );
+        assert_eq!(Failure::of(&api_error(502, None)), Failure::Transient);
+        assert_eq!(Failure::of(&api_error(403, None)), Failure::Permanent);
+        assert_eq!(
+            Failure::of(&api_error(500, None).context("cannot publish")),
+            Failure::Transient
+        );
+        assert_eq!(
+            Failure::of(&anyhow::anyhow!("template error")),
+            Failure::Permanent
+        );
+    }
+}
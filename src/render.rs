@@ -0,0 +1,585 @@
+use crate::config::{Destination, Layout};
+use crate::scraper::{Article, Side, Story};
+use anyhow::bail;
+use chrono::NaiveDate;
+use handlebars::Handlebars;
+use itertools::Itertools;
+use serde_json::json;
+
+/// Maximum length of a Telegram message.
+pub const MESSAGE_LIMIT: usize = 4096;
+
+pub struct Renderer {
+    template: Handlebars<'static>,
+}
+
+impl Renderer {
+    pub fn try_new() -> anyhow::Result<Self> {
+        let mut template = Handlebars::new();
+        template
+            .register_template_string("main", include_str!("../data/post-template.handlebars"))?;
+        template.register_template_string(
+            "teaser",
+            include_str!("../data/teaser-template.handlebars"),
+        )?;
+        template.register_template_string(
+            "compact",
+            include_str!("../data/compact-template.handlebars"),
+        )?;
+        template.register_template_string(
+            "article",
+            include_str!("../data/article-template.handlebars"),
+        )?;
+        template.register_template_string(
+            "digest",
+            include_str!("../data/digest-template.handlebars"),
+        )?;
+        Ok(Renderer { template })
+    }
+
+    /// Makes a renderer using the template of the destination, if it has one,
+    /// instead of the default template of its layout, or of the digest in the digest mode.
+    pub fn for_destination(destination: &Destination) -> anyhow::Result<Self> {
+        let mut renderer = Renderer::try_new()?;
+        if let Some(path) = &destination.template {
+            let name = match destination.layout {
+                _ if destination.digest_at.is_some() => "digest",
+                Layout::Full | Layout::Thread => "main",
+                Layout::Telegraph => "teaser",
+                Layout::Compact => "compact",
+            };
+            renderer.template.register_template_file(name, path)?;
+        }
+        Ok(renderer)
+    }
+
+    /// Renders the story into one or more messages, each fitting into [`MESSAGE_LIMIT`].
+    ///
+    /// The story is split at article boundaries: the first message holds the story summary
+    /// and as many articles as fit, the rest of the articles go into continuation messages.
+    /// Only an article too long for a message on its own is split inside.
+    pub fn format_story(&self, story: &Story, url: &str) -> anyhow::Result<Vec<String>> {
+        let side_stories = story
+            .articles
+            .iter()
+            .map(|article| side_story(article, true))
+            .collect::<Vec<_>>();
+
+        let mut messages = Vec::new();
+        let mut start = 0;
+        while start < side_stories.len() || messages.is_empty() {
+            let continuation = !messages.is_empty();
+            let render = |end: usize| {
+                let last = end == side_stories.len();
+                self.render(story, url, &side_stories[start..end], continuation, last)
+            };
+
+            let mut end = start;
+            let mut rendered = None;
+            while end < side_stories.len() {
+                let candidate = render(end + 1)?;
+                if message_len(&candidate) > MESSAGE_LIMIT {
+                    break;
+                }
+                rendered = Some(candidate);
+                end += 1;
+            }
+
+            match rendered {
+                Some(rendered) => messages.push(rendered),
+                None => {
+                    // Not even a single article fits alongside the rest of the message
+                    end = (start + 1).min(side_stories.len());
+                    messages.extend(split_html(&render(end)?, MESSAGE_LIMIT));
+                }
+            }
+            start = end;
+        }
+        Ok(messages)
+    }
+
+    /// Renders the story as a thread: the root message with the story summary,
+    /// followed by a message per article, with the articles ordered by `side_order`.
+    ///
+    /// Articles of the sides missing from `side_order` go last, from left to right.
+    pub fn format_thread(
+        &self,
+        story: &Story,
+        url: &str,
+        side_order: &[Side],
+    ) -> anyhow::Result<Vec<String>> {
+        let mut messages = split_html(&self.render(story, url, &[], false, true)?, MESSAGE_LIMIT);
+
+        let articles = story.articles.iter().sorted_by_key(|article| {
+            let position = side_order.iter().position(|&side| side == article.side);
+            (position.unwrap_or(side_order.len()), article.side)
+        });
+        for article in articles {
+            let rendered = self
+                .template
+                .render("article", &side_story(article, true))?
+                .trim()
+                .to_owned();
+            messages.extend(split_html(&rendered, MESSAGE_LIMIT));
+        }
+        Ok(messages)
+    }
+
+    /// Renders a short teaser linking to the full story published elsewhere.
+    pub fn format_teaser(&self, story: &Story, page_url: &str) -> anyhow::Result<String> {
+        let side_stories = story
+            .articles
+            .iter()
+            .map(|article| {
+                json!({
+                    "side_story_emoji": article.side.emoji(),
+                    "side_story_source": article.source,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let data = json!({
+            "story_title": story.title,
+            "story_content": story.summary.first().map(|p| p.telegram_html()),
+            "page_url": page_url,
+            "story_date": format!("{}", story.datetime.date().format("%Y-%m-%d")),
+            "side_stories": side_stories,
+        });
+
+        Ok(self.template.render("teaser", &data)?.trim().to_owned())
+    }
+
+    /// Renders the story with a headline per side, revealing the articles
+    /// of the `expanded` side with their summaries.
+    ///
+    /// The post is edited in place to reveal a side, so it must fit into a single message:
+    /// the summary is cut short if it doesn't. Only a revealed side too long for a message
+    /// on its own is an error.
+    pub fn format_compact(
+        &self,
+        story: &Story,
+        url: &str,
+        expanded: Option<Side>,
+    ) -> anyhow::Result<String> {
+        let side_stories = story
+            .articles
+            .iter()
+            .sorted_by_key(|article| article.side)
+            .group_by(|article| article.side)
+            .into_iter()
+            .map(|(side, articles)| {
+                let articles = articles.collect::<Vec<_>>();
+                let mut headline = side_story(articles[0], false);
+                headline["side_story_more"] = json!(articles.len() - 1);
+                if Some(side) == expanded {
+                    headline["side_articles"] = articles
+                        .iter()
+                        .map(|article| side_story(article, true))
+                        .collect();
+                }
+                headline
+            })
+            .collect::<Vec<_>>();
+
+        let render = |content: &str| {
+            let data = json!({
+                "story_title": story.title,
+                "story_content": content,
+                "story_url": url,
+                "story_date": format!("{}", story.datetime.date().format("%Y-%m-%d")),
+                "side_stories": side_stories,
+            });
+            Ok::<_, anyhow::Error>(self.template.render("compact", &data)?.trim().to_owned())
+        };
+
+        let content = story.summary.iter().map(|p| p.telegram_html()).join("\n\n");
+        let mut rendered = render(&content)?;
+        if message_len(&rendered) > MESSAGE_LIMIT {
+            // The summary is cut to what fits, with room left for the ellipsis
+            let budget = MESSAGE_LIMIT.saturating_sub(message_len(&render("")?) + 1);
+            if let Some(cut) = split_html(&content, budget).into_iter().next() {
+                rendered = render(&format!("{}…", cut))?;
+            }
+        }
+        if message_len(&rendered) > MESSAGE_LIMIT {
+            bail!(
+                "the compact post is {} characters long, more than a message holds",
+                message_len(&rendered)
+            );
+        }
+        Ok(rendered)
+    }
+
+    /// Renders the digest of the stories, each with its headline per article,
+    /// packing as many stories into a message as fit into [`MESSAGE_LIMIT`].
+    pub fn format_digest(
+        &self,
+        stories: &[(String, Story)],
+        date: NaiveDate,
+    ) -> anyhow::Result<Vec<String>> {
+        let stories = stories
+            .iter()
+            .map(|(url, story)| {
+                let side_stories = story
+                    .articles
+                    .iter()
+                    .map(|article| side_story(article, false))
+                    .collect::<Vec<_>>();
+                json!({
+                    "story_title": story.title,
+                    "story_url": url,
+                    "side_stories": side_stories,
+                })
+            })
+            .collect::<Vec<_>>();
+        let render = |stories: &[serde_json::Value], continuation: bool| {
+            let data = json!({
+                "digest_date": date.format("%Y-%m-%d").to_string(),
+                "stories": stories,
+                "continuation": continuation,
+            });
+            Ok::<_, anyhow::Error>(self.template.render("digest", &data)?.trim().to_owned())
+        };
+
+        let mut messages = Vec::new();
+        let mut start = 0;
+        while start < stories.len() {
+            let continuation = !messages.is_empty();
+            let mut end = start + 1;
+            let mut rendered = render(&stories[start..end], continuation)?;
+            while end < stories.len() {
+                let candidate = render(&stories[start..=end], continuation)?;
+                if message_len(&candidate) > MESSAGE_LIMIT {
+                    break;
+                }
+                rendered = candidate;
+                end += 1;
+            }
+            // A story too long for a message on its own is split inside
+            messages.extend(split_html(&rendered, MESSAGE_LIMIT));
+            start = end;
+        }
+        Ok(messages)
+    }
+
+    fn render(
+        &self,
+        story: &Story,
+        url: &str,
+        side_stories: &[serde_json::Value],
+        continuation: bool,
+        last: bool,
+    ) -> anyhow::Result<String> {
+        let story_content = story.summary.iter().map(|p| p.telegram_html()).join("\n\n");
+        let data = json!({
+            "story_title": story.title,
+            "story_content": story_content,
+            "story_url": url,
+            "story_date": format!("{}", story.datetime.date().format("%Y-%m-%d")),
+            "side_stories": side_stories,
+            "continuation": continuation,
+            "last": last,
+        });
+
+        Ok(self.template.render("main", &data)?.trim().to_owned())
+    }
+}
+
+fn side_story(article: &Article, with_content: bool) -> serde_json::Value {
+    let article_content = if with_content {
+        article
+            .summary
+            .iter()
+            .take_while(|para| !para.text().ends_with("..."))
+            .map(|p| p.telegram_html())
+            .join("\n\n")
+    } else {
+        String::new()
+    };
+
+    json!({
+        "side_story_emoji": article.side.emoji(),
+        "side_story_title": article.title,
+        "side_story_url": article.url,
+        "side_story_source": article.source,
+        "side_story_content": article_content,
+    })
+}
+
+/// Length of the message as Telegram counts it.
+///
+/// Telegram counts UTF-16 code units of the text without markup, so counting the markup
+/// as well never underestimates.
+pub fn message_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Token<'a> {
+    Open {
+        name: &'a str,
+        raw: &'a str,
+    },
+    Close {
+        raw: &'a str,
+    },
+    /// A single character or an entity
+    Text {
+        raw: &'a str,
+    },
+}
+
+impl<'a> Token<'a> {
+    fn raw(&self) -> &'a str {
+        match *self {
+            Token::Open { raw, .. } | Token::Close { raw } | Token::Text { raw } => raw,
+        }
+    }
+}
+
+fn tokenize(html: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '<' => rest.find('>').map(|idx| idx + 1),
+            '&' => rest.find(';').filter(|&idx| {
+                rest[1..idx]
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '#')
+            }),
+            _ => None,
+        }
+        .map(|len| len.max(1))
+        .unwrap_or_else(|| c.len_utf8());
+
+        let raw = &rest[..len];
+        let token = if raw.starts_with("</") {
+            Token::Close { raw }
+        } else if raw.starts_with('<') && raw.len() > 1 {
+            let name = raw[1..]
+                .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                .next()
+                .unwrap_or_default();
+            Token::Open { name, raw }
+        } else {
+            Token::Text { raw }
+        };
+        tokens.push(token);
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+fn closing_tags(open: &[Token]) -> String {
+    open.iter()
+        .rev()
+        .filter_map(|token| match token {
+            Token::Open { name, .. } => Some(format!("</{}>", name)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Splits HTML text into chunks of at most `limit` characters.
+///
+/// Chunks are cut at line breaks or spaces when possible and never inside a tag or an entity.
+/// Tags open at a cut are closed at the end of the chunk and reopened in the next one.
+pub fn split_html(html: &str, limit: usize) -> Vec<String> {
+    let tokens = tokenize(html);
+    let mut chunks = Vec::new();
+    let mut open: Vec<Token> = Vec::new();
+    let mut idx = 0;
+
+    while idx < tokens.len() {
+        let start = idx;
+        let mut chunk: String = open.iter().map(Token::raw).collect();
+        let mut chunk_open = open.clone();
+        // (token index, chunk length, open tags) right after the last line break and space
+        let mut line_break = None;
+        let mut space = None;
+
+        while idx < tokens.len() {
+            let token = tokens[idx];
+            let mut token_open = chunk_open.clone();
+            match token {
+                Token::Open { .. } => token_open.push(token),
+                Token::Close { .. } => {
+                    token_open.pop();
+                }
+                Token::Text { .. } => {}
+            }
+
+            let len = message_len(&chunk)
+                + message_len(token.raw())
+                + message_len(&closing_tags(&token_open));
+            if len > limit && idx > start {
+                break;
+            }
+
+            chunk.push_str(token.raw());
+            chunk_open = token_open;
+            idx += 1;
+
+            match token.raw() {
+                "\n" => line_break = Some((idx, chunk.len(), chunk_open.clone())),
+                " " => space = Some((idx, chunk.len(), chunk_open.clone())),
+                _ => {}
+            }
+        }
+
+        if idx < tokens.len() {
+            // Prefer a line break unless it leaves the chunk less than half full
+            let cut = match (line_break, space) {
+                (Some(line_break), _) if line_break.1 * 2 >= chunk.len() => Some(line_break),
+                (_, Some(space)) => Some(space),
+                (line_break, None) => line_break,
+            };
+            if let Some((cut_idx, cut_len, cut_open)) = cut {
+                idx = cut_idx;
+                chunk.truncate(cut_len);
+                chunk_open = cut_open;
+            }
+        }
+
+        let mut chunk = chunk.trim().to_owned();
+        chunk.push_str(&closing_tags(&chunk_open));
+        chunks.push(chunk);
+        open = chunk_open;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn split_keeps_tags_balanced() {
+        let chunks = split_html("<b>one two</b> <a href=\"x\">three &amp; four</a>", 26);
+        assert_eq!(
+            chunks,
+            vec![
+                "<b>one two</b>",
+                "<a href=\"x\">three</a>",
+                "<a href=\"x\">&amp; four</a>",
+            ]
+        );
+    }
+
+    #[test]
+    fn split_never_cuts_entities() {
+        let chunks = split_html("&amp;&amp;&amp;", 6);
+        assert_eq!(chunks, vec!["&amp;", "&amp;", "&amp;"]);
+    }
+
+    #[test]
+    fn long_story_is_split_at_articles() -> anyhow::Result<()> {
+        let mut story = fixtures::story();
+        let articles = story.articles.clone();
+        for _ in 0..10 {
+            story.articles.extend(articles.iter().cloned());
+        }
+
+        let renderer = Renderer::try_new()?;
+        let url = "https://www.allsides.com/story/test";
+        let messages = renderer.format_story(&story, url)?;
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| message_len(m) <= MESSAGE_LIMIT));
+        assert!(messages[0].starts_with("<b>"));
+        assert!(messages[1].starts_with("***"));
+        assert!(messages.iter().all(|m| m.matches("***").count() > 0));
+        assert!(messages.last().unwrap().ends_with("</i>"));
+        Ok(())
+    }
+
+    #[test]
+    fn compact_reveals_expanded_side_only() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let renderer = Renderer::try_new()?;
+        let url = "https://www.allsides.com/story/test";
+
+        let collapsed = renderer.format_compact(&story, url, None)?;
+        assert!(collapsed.contains("Mainstream media ignores sexual harassment allegations"));
+        assert!(!collapsed.contains("So said Lindsey Boylan"));
+
+        let expanded = renderer.format_compact(&story, url, Some(Side::Left))?;
+        assert!(expanded.contains("So said Lindsey Boylan"));
+        assert!(!expanded.contains("New York City politico Lindsey Boylan"));
+        Ok(())
+    }
+
+    #[test]
+    fn compact_shows_headline_per_side() -> anyhow::Result<()> {
+        let mut story = fixtures::story();
+        let mut second = story.articles[0].clone();
+        second.title = "Another take from the left".to_owned();
+        second.source = "CNN".to_owned();
+        story.articles.push(second);
+        let renderer = Renderer::try_new()?;
+        let url = "https://www.allsides.com/story/test";
+
+        let collapsed = renderer.format_compact(&story, url, None)?;
+        assert_eq!(collapsed.matches(Side::Left.emoji()).count(), 1);
+        assert!(collapsed.contains(">Vox</a> and 1 more\n"));
+        assert!(!collapsed.contains("Another take from the left"));
+
+        let expanded = renderer.format_compact(&story, url, Some(Side::Left))?;
+        assert_eq!(expanded.matches(Side::Left.emoji()).count(), 2);
+        assert!(expanded.contains("Another take from the left"));
+        assert!(!expanded.contains(" and 1 more"));
+
+        story.summary = std::iter::repeat_n(story.summary[0].clone(), 50).collect();
+        let collapsed = renderer.format_compact(&story, url, None)?;
+        assert!(message_len(&collapsed) <= MESSAGE_LIMIT);
+        assert!(
+            collapsed.contains("…\n<a href=\"https://www.allsides.com/story/test\">Read More</a>")
+        );
+        assert!(collapsed.contains(">Vox</a> and 1 more\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn thread_follows_side_order() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let renderer = Renderer::try_new()?;
+        let url = "https://www.allsides.com/story/test";
+
+        let messages = renderer.format_thread(&story, url, &[Side::Right])?;
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].starts_with("<b>NY Gov. Cuomo"));
+        assert!(!messages[0].contains("***"));
+        assert!(messages[1].starts_with(Side::Right.emoji()));
+        assert!(messages[2].starts_with(Side::Left.emoji()));
+        assert!(messages[3].starts_with(Side::CenterRight.emoji()));
+        Ok(())
+    }
+
+    #[test]
+    fn digest_packs_stories_into_messages() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let renderer = Renderer::try_new()?;
+        let date = NaiveDate::from_ymd(2020, 12, 15);
+
+        let stories = vec![("https://www.allsides.com/story/test".to_owned(), story)];
+        let messages = renderer.format_digest(&stories, date)?;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("<b>AllSides digest, 2020-12-15</b>\n\n"));
+        assert!(messages[0]
+            .contains("<a href=\"https://www.allsides.com/story/test\"><b>NY Gov. Cuomo"));
+        assert!(messages[0].contains(&format!(
+            "\n{} <a href=\"https://www.vox.com/",
+            Side::Left.emoji()
+        )));
+
+        let stories = std::iter::repeat_n(stories[0].clone(), 20).collect::<Vec<_>>();
+        let messages = renderer.format_digest(&stories, date)?;
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| message_len(m) <= MESSAGE_LIMIT));
+        assert!(!messages[1].contains("AllSides digest"));
+        assert!(messages[1].starts_with("<a href="));
+        Ok(())
+    }
+}
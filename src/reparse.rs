@@ -0,0 +1,141 @@
+use crate::page_archive::{ArchivedPage, PageArchive};
+use crate::scraper::{FromHTML, MainPage, Story};
+use crate::state::State;
+use crate::ALL_SIDES_MAINPAGE;
+use chrono::{DateTime, Utc};
+use select::document::Document;
+
+#[derive(Debug, Default)]
+pub struct ReparseReport {
+    pub parsed: usize,
+    /// The pages which fail to parse, with the error
+    pub failed: Vec<PageReport>,
+    /// The story pages which parse differently from the published story, with the differences
+    pub changed: Vec<PageReport>,
+}
+
+/// An archived page which fails to parse or parses differently.
+#[derive(Debug)]
+pub struct PageReport {
+    pub url: String,
+    pub fetched_at: DateTime<Utc>,
+    pub details: Vec<String>,
+}
+
+impl PageReport {
+    fn new(page: &ArchivedPage, details: Vec<String>) -> Self {
+        PageReport {
+            url: page.url.clone(),
+            fetched_at: page.fetched_at,
+            details,
+        }
+    }
+}
+
+/// Runs the current parsers over every archived page and reports the pages that
+/// fail to parse or parse differently from the published story.
+pub fn reparse(archive: &PageArchive, state: &State) -> anyhow::Result<ReparseReport> {
+    let mut report = ReparseReport::default();
+    for page in archive.pages() {
+        let page = page?;
+        let document = Document::from(page.source.as_str());
+
+        if page.url == ALL_SIDES_MAINPAGE {
+            match MainPage::from_html(&document) {
+                Ok(_) => report.parsed += 1,
+                Err(e) => report
+                    .failed
+                    .push(PageReport::new(&page, vec![e.to_string()])),
+            }
+            continue;
+        }
+
+        let story = match Story::from_html(&document) {
+            Ok(story) => story,
+            Err(e) => {
+                report
+                    .failed
+                    .push(PageReport::new(&page, vec![e.to_string()]));
+                continue;
+            }
+        };
+        report.parsed += 1;
+
+        if let Some(publication) = state.publication(&page.url)? {
+            let differences = story_differences(&publication.story, &story);
+            if !differences.is_empty() {
+                report.changed.push(PageReport::new(&page, differences));
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn story_differences(published: &Story, reparsed: &Story) -> Vec<String> {
+    let mut differences = Vec::new();
+    if published.title != reparsed.title {
+        differences.push(format!(
+            "title: {:?} -> {:?}",
+            published.title, reparsed.title
+        ));
+    }
+    if published.datetime != reparsed.datetime {
+        differences.push(format!(
+            "datetime: {} -> {}",
+            published.datetime, reparsed.datetime
+        ));
+    }
+    if published.topics != reparsed.topics {
+        differences.push(format!(
+            "topics: {:?} -> {:?}",
+            published.topics, reparsed.topics
+        ));
+    }
+    if published.summary != reparsed.summary {
+        differences.push("summary differs".to_owned());
+    }
+    if published.articles.len() != reparsed.articles.len() {
+        differences.push(format!(
+            "articles: {} -> {}",
+            published.articles.len(),
+            reparsed.articles.len()
+        ));
+    }
+    for (idx, (a, b)) in published
+        .articles
+        .iter()
+        .zip(&reparsed.articles)
+        .enumerate()
+    {
+        if a != b {
+            differences.push(format!("article #{} ({}) differs", idx, a.source));
+        }
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::story;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn differences_of_reparsed_story() {
+        let published = story();
+        assert!(story_differences(&published, &published).is_empty());
+
+        let mut reparsed = published.clone();
+        reparsed.title = "Cuomo".to_owned();
+        reparsed.articles[1].title.push('!');
+        reparsed.articles.pop();
+        assert_eq!(
+            story_differences(&published, &reparsed),
+            vec![
+                format!("title: {:?} -> \"Cuomo\"", published.title),
+                "articles: 3 -> 2".to_owned(),
+                "article #1 (Fox News (Online News)) differs".to_owned(),
+            ]
+        );
+    }
+}
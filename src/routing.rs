@@ -0,0 +1,41 @@
+use crate::config::{Config, Destination, Platform};
+use crate::discord::DiscordWebhook;
+use crate::mastodon::Mastodon;
+use crate::matrix::Matrix;
+use crate::publisher::Publisher;
+use crate::render::Renderer;
+use crate::tg_bot::Bot;
+
+/// A destination along with the renderer and the publisher of its posts.
+pub struct Route {
+    pub destination: Destination,
+    pub renderer: Renderer,
+    pub publisher: Box<dyn Publisher>,
+}
+
+impl Route {
+    /// Makes the routes of all the configured destinations.
+    pub fn all(cfg: &Config, bot: &Bot) -> anyhow::Result<Vec<Route>> {
+        let mut matrix = None;
+        let mut routes = Vec::new();
+        for destination in cfg.destinations()? {
+            let publisher: Box<dyn Publisher> = match destination.platform {
+                Platform::Telegram => Box::new(bot.chat(&destination.chat)),
+                Platform::Matrix => {
+                    if matrix.is_none() {
+                        matrix = Some(Matrix::try_new(&cfg.matrix)?);
+                    }
+                    Box::new(matrix.as_ref().unwrap().room(&destination.chat))
+                }
+                Platform::Discord => Box::new(DiscordWebhook::new(&destination.chat)),
+                Platform::Mastodon => Box::new(Mastodon::try_new(&cfg.mastodon)?),
+            };
+            routes.push(Route {
+                renderer: Renderer::for_destination(&destination)?,
+                destination,
+                publisher,
+            });
+        }
+        Ok(routes)
+    }
+}
@@ -0,0 +1,241 @@
+use crate::config::ScheduleOptions;
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use std::str::FromStr;
+
+/// A daily range of hours, e.g. `23:00-07:00`, which may span midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Hours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Returns the first time the hours end at after the given local time.
+    fn end_after(&self, local: DateTime<Tz>) -> DateTime<Utc> {
+        let mut day = local.naive_local().date();
+        if local.time() >= self.end {
+            day = day.succ();
+        }
+        from_local(local.timezone(), day.and_time(self.end))
+    }
+}
+
+fn from_local(timezone: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        // The time is skipped by a daylight saving transition
+        .unwrap_or_else(|| timezone.from_utc_datetime(&local))
+        .with_timezone(&Utc)
+}
+
+impl FromStr for Hours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("hours must look like 23:00-07:00: {}", s))?;
+        let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M");
+        Ok(Hours {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+/// When the posts of the outbox are sent: outside of the quiet hours, evenly spaced,
+/// and without a notification during the silent hours. The digests are posted
+/// at their time of the day in the same timezone.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    timezone: Tz,
+    quiet_hours: Option<Hours>,
+    silent_hours: Option<Hours>,
+    spacing: chrono::Duration,
+}
+
+impl Schedule {
+    pub fn new(opts: &ScheduleOptions) -> Self {
+        Schedule {
+            timezone: opts.timezone,
+            quiet_hours: opts.quiet_hours,
+            silent_hours: opts.silent_hours,
+            spacing: chrono::Duration::minutes(i64::from(opts.spacing)),
+        }
+    }
+
+    /// Returns the earliest time from `at` a post can be sent at,
+    /// given when the previous post to the same destination was sent.
+    pub fn next_slot(&self, at: DateTime<Utc>, last_post: Option<DateTime<Utc>>) -> DateTime<Utc> {
+        let at = match last_post {
+            Some(last_post) => at.max(last_post + self.spacing),
+            None => at,
+        };
+        let local = at.with_timezone(&self.timezone);
+        match self.quiet_hours {
+            Some(quiet_hours) if quiet_hours.contains(local.time()) => quiet_hours.end_after(local),
+            _ => at,
+        }
+    }
+
+    /// Returns the last time up to `now` the local time of the day was at `time`.
+    pub fn last_occurrence(&self, time: NaiveTime, now: DateTime<Utc>) -> DateTime<Utc> {
+        let local = now.with_timezone(&self.timezone);
+        let mut day = local.naive_local().date();
+        if local.time() < time {
+            day = day.pred();
+        }
+        from_local(self.timezone, day.and_time(time))
+    }
+
+    /// Returns the last time up to `now` it was the weekday at the local time of the day.
+    pub fn last_weekly_occurrence(
+        &self,
+        weekday: Weekday,
+        time: NaiveTime,
+        now: DateTime<Utc>,
+    ) -> DateTime<Utc> {
+        let local = now.with_timezone(&self.timezone);
+        let mut day = local.naive_local().date();
+        while day.weekday() != weekday || (day == local.naive_local().date() && local.time() < time)
+        {
+            day = day.pred();
+        }
+        from_local(self.timezone, day.and_time(time))
+    }
+
+    pub fn local_date(&self, at: DateTime<Utc>) -> NaiveDate {
+        at.with_timezone(&self.timezone).naive_local().date()
+    }
+
+    /// Whether a post sent at the time is sent without a notification.
+    pub fn is_silent(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        self.silent_hours
+            .is_some_and(|silent_hours| silent_hours.contains(local.time()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn schedule() -> Schedule {
+        Schedule {
+            timezone: chrono_tz::Europe::Berlin,
+            quiet_hours: Some("23:00-07:00".parse().unwrap()),
+            silent_hours: Some("21:00-09:00".parse().unwrap()),
+            spacing: chrono::Duration::minutes(15),
+        }
+    }
+
+    #[test]
+    fn hours_span_midnight() {
+        let hours: Hours = "23:00-07:00".parse().unwrap();
+        assert!(hours.contains(NaiveTime::from_hms(23, 30, 0)));
+        assert!(hours.contains(NaiveTime::from_hms(6, 59, 0)));
+        assert!(!hours.contains(NaiveTime::from_hms(7, 0, 0)));
+        assert!("23:00".parse::<Hours>().is_err());
+    }
+
+    #[test]
+    fn quiet_hours_delay_posts() {
+        let schedule = schedule();
+        // 23:30 in Berlin, in winter
+        let night = Utc.ymd(2020, 12, 15).and_hms(22, 30, 0);
+        assert_eq!(
+            schedule.next_slot(night, None),
+            Utc.ymd(2020, 12, 16).and_hms(6, 0, 0)
+        );
+        // 02:00 in Berlin, in summer
+        let night = Utc.ymd(2020, 7, 16).and_hms(0, 0, 0);
+        assert_eq!(
+            schedule.next_slot(night, None),
+            Utc.ymd(2020, 7, 16).and_hms(5, 0, 0)
+        );
+        let day = Utc.ymd(2020, 12, 15).and_hms(12, 0, 0);
+        assert_eq!(schedule.next_slot(day, None), day);
+    }
+
+    #[test]
+    fn posts_are_spaced() {
+        let schedule = schedule();
+        let day = Utc.ymd(2020, 12, 15).and_hms(12, 0, 0);
+        let last_post = day - chrono::Duration::minutes(5);
+        assert_eq!(
+            schedule.next_slot(day, Some(last_post)),
+            day + chrono::Duration::minutes(10)
+        );
+        // Spacing the post pushes it into the quiet hours
+        let evening = Utc.ymd(2020, 12, 15).and_hms(21, 55, 0);
+        assert_eq!(
+            schedule.next_slot(evening, Some(evening)),
+            Utc.ymd(2020, 12, 16).and_hms(6, 0, 0)
+        );
+    }
+
+    #[test]
+    fn digest_time_in_timezone() {
+        let schedule = schedule();
+        let time = NaiveTime::from_hms(20, 0, 0);
+        // 20:30 and 19:30 in Berlin
+        let evening = Utc.ymd(2020, 12, 15).and_hms(19, 30, 0);
+        assert_eq!(
+            schedule.last_occurrence(time, evening),
+            Utc.ymd(2020, 12, 15).and_hms(19, 0, 0)
+        );
+        let before = Utc.ymd(2020, 12, 15).and_hms(18, 30, 0);
+        assert_eq!(
+            schedule.last_occurrence(time, before),
+            Utc.ymd(2020, 12, 14).and_hms(19, 0, 0)
+        );
+        // 00:30 on the next day in Berlin
+        let midnight = Utc.ymd(2020, 12, 15).and_hms(23, 30, 0);
+        assert_eq!(
+            schedule.local_date(midnight),
+            NaiveDate::from_ymd(2020, 12, 16)
+        );
+    }
+
+    #[test]
+    fn weekly_time_in_timezone() {
+        let schedule = schedule();
+        let time = NaiveTime::from_hms(18, 0, 0);
+        // Tuesday
+        let tuesday = Utc.ymd(2020, 12, 15).and_hms(12, 0, 0);
+        assert_eq!(
+            schedule.last_weekly_occurrence(Weekday::Sun, time, tuesday),
+            Utc.ymd(2020, 12, 13).and_hms(17, 0, 0)
+        );
+        // 17:30 and 18:30 in Berlin on Sunday
+        let sunday = Utc.ymd(2020, 12, 20).and_hms(16, 30, 0);
+        assert_eq!(
+            schedule.last_weekly_occurrence(Weekday::Sun, time, sunday),
+            Utc.ymd(2020, 12, 13).and_hms(17, 0, 0)
+        );
+        let sunday = Utc.ymd(2020, 12, 20).and_hms(17, 30, 0);
+        assert_eq!(
+            schedule.last_weekly_occurrence(Weekday::Sun, time, sunday),
+            Utc.ymd(2020, 12, 20).and_hms(17, 0, 0)
+        );
+    }
+
+    #[test]
+    fn off_peak_posts_are_silent() {
+        let schedule = schedule();
+        assert!(schedule.is_silent(Utc.ymd(2020, 12, 15).and_hms(20, 30, 0)));
+        assert!(!schedule.is_silent(Utc.ymd(2020, 12, 15).and_hms(12, 0, 0)));
+    }
+}
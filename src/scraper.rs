@@ -2,17 +2,21 @@ use anyhow::{anyhow, bail};
 use chrono::{DateTime, FixedOffset};
 use select::{
     document::Document,
-    node,
+    node::Node,
     predicate::{Attr, Class, Name},
 };
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
+const ALL_SIDES_HOST: &str = "https://www.allsides.com";
+
 #[derive(Debug, Clone)]
 pub struct MainPage {
     pub teasers: Vec<Teaser>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Political bias of a source, ordered from left to right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Side {
     Left,
     CenterLeft,
@@ -28,23 +32,38 @@ pub struct Teaser {
     pub img_url: String,
 }
 
-#[derive(Debug, Clone)]
-pub struct Paragraph<'a>(node::Node<'a>);
+/// A paragraph of rich text, detached from the document it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Paragraph(pub Vec<Inline>);
+
+/// Inline content of a paragraph: only the markup Telegram can render is kept,
+/// everything else is flattened into its text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Inline {
+    Text(String),
+    Link { url: String, content: Vec<Inline> },
+    Bold(Vec<Inline>),
+    Italic(Vec<Inline>),
+}
 
-#[derive(Debug, Clone)]
-pub struct Story<'a> {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Story {
     pub title: String,
-    pub summary: Vec<Paragraph<'a>>,
-    pub articles: Vec<Article<'a>>,
+    pub summary: Vec<Paragraph>,
+    pub articles: Vec<Article>,
     pub datetime: DateTime<FixedOffset>,
+    /// AllSides topics the articles are filed under, e.g. "Sexual Misconduct"
+    #[serde(default)]
+    pub topics: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Article<'a> {
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Article {
     pub side: Side,
     pub source: String,
     pub title: String,
-    pub summary: Vec<Paragraph<'a>>,
+    pub summary: Vec<Paragraph>,
     pub url: String,
 }
 
@@ -66,6 +85,40 @@ impl FromStr for Side {
 }
 
 impl Side {
+    pub const ALL: [Side; 5] = [
+        Side::Left,
+        Side::CenterLeft,
+        Side::Center,
+        Side::CenterRight,
+        Side::Right,
+    ];
+
+    /// Looks up a side by its [`Side::name`].
+    pub fn from_name(name: &str) -> Option<Side> {
+        Side::ALL.iter().copied().find(|side| side.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Side::Left => "left",
+            Side::CenterLeft => "center-left",
+            Side::Center => "center",
+            Side::CenterRight => "center-right",
+            Side::Right => "right",
+        }
+    }
+
+    /// Human-readable name, as AllSides rates the sources.
+    pub fn label(&self) -> &'static str {
+        match *self {
+            Side::Left => "Left",
+            Side::CenterLeft => "Lean Left",
+            Side::Center => "Center",
+            Side::CenterRight => "Lean Right",
+            Side::Right => "Right",
+        }
+    }
+
     pub fn emoji(&self) -> &'static str {
         match *self {
             Side::Left => "🟦",
@@ -77,35 +130,82 @@ impl Side {
     }
 }
 
-impl Paragraph<'_> {
+impl Paragraph {
+    fn from_node(node: Node) -> Self {
+        Paragraph(node.children().flat_map(Inline::from_node).collect())
+    }
+
     pub fn text(&self) -> String {
-        self.0.text()
+        self.0.iter().map(Inline::text).collect()
     }
 
     pub fn telegram_html(&self) -> String {
-        self.0
-            .children()
-            .map(|n| {
-                Some(n)
-                    .filter(|n| n.is(Name("a")))
-                    .and_then(|a| {
-                        Some(format!(
-                            r#"<a href="{url}">{text}</a>"#,
-                            url = a.attr("href")?,
-                            text = a.text()
-                        ))
-                    })
-                    .unwrap_or_else(|| n.text())
-            })
-            .collect::<String>()
+        self.0.iter().map(Inline::telegram_html).collect()
     }
 }
 
-pub trait FromHTML<'a>: 'a + Sized {
-    fn from_html(html: &'a Document) -> anyhow::Result<Self>;
+impl Inline {
+    fn from_node(node: Node) -> Vec<Self> {
+        if let Some(text) = node.as_text() {
+            return vec![Inline::Text(text.to_owned())];
+        }
+
+        let children = || node.children().flat_map(Inline::from_node).collect();
+        match node.name() {
+            Some("a") => match node.attr("href") {
+                Some(url) => vec![Inline::Link {
+                    url: url.to_owned(),
+                    content: children(),
+                }],
+                None => children(),
+            },
+            Some("b") | Some("strong") => vec![Inline::Bold(children())],
+            Some("i") | Some("em") => vec![Inline::Italic(children())],
+            _ => children(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            Inline::Text(text) => text.clone(),
+            Inline::Link { content, .. } | Inline::Bold(content) | Inline::Italic(content) => {
+                content.iter().map(Inline::text).collect()
+            }
+        }
+    }
+
+    pub fn telegram_html(&self) -> String {
+        let inner = |content: &[Inline]| {
+            content
+                .iter()
+                .map(Inline::telegram_html)
+                .collect::<String>()
+        };
+        match self {
+            Inline::Text(text) => escape_html(text),
+            Inline::Link { url, content } => format!(
+                r#"<a href="{url}">{text}</a>"#,
+                url = escape_html(url).replace('"', "&quot;"),
+                text = inner(content)
+            ),
+            Inline::Bold(content) => format!("<b>{}</b>", inner(content)),
+            Inline::Italic(content) => format!("<i>{}</i>", inner(content)),
+        }
+    }
+}
+
+/// Escapes the characters Telegram requires to be escaped in HTML-formatted messages.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub trait FromHTML: Sized {
+    fn from_html(html: &Document) -> anyhow::Result<Self>;
 }
 
-impl FromHTML<'_> for MainPage {
+impl FromHTML for MainPage {
     // TODO: get list of stories directly from AllSides API instead of this atrocity
     fn from_html(html: &Document) -> anyhow::Result<Self> {
         let mut teasers: Vec<_> = html.find(Class("view-story-id-single-story")).map(|block| {
@@ -124,7 +224,8 @@ impl FromHTML<'_> for MainPage {
 
             Ok(Teaser {
                 title,
-                url: normalize_allsides_url(url),
+                // The same url as the admin's links to the story
+                url: parse_story_url(url).unwrap_or_else(|| normalize_allsides_url(url)),
                 img_url: img_url.to_owned(),
             })
         }).collect::<Result<_, anyhow::Error>>()?;
@@ -140,8 +241,8 @@ impl FromHTML<'_> for MainPage {
     }
 }
 
-impl<'a> FromHTML<'a> for Story<'a> {
-    fn from_html(html: &'a Document) -> anyhow::Result<Self> {
+impl FromHTML for Story {
+    fn from_html(html: &Document) -> anyhow::Result<Self> {
         let story = html
             .find(Attr("id", "content"))
             .next()
@@ -172,7 +273,7 @@ impl<'a> FromHTML<'a> for Story<'a> {
         let paragraphs: Vec<_> = description
             .children()
             .filter(|node| node.is(Name("p")))
-            .map(Paragraph)
+            .map(Paragraph::from_node)
             .collect();
         if paragraphs.is_empty() {
             bail!("summary contains no paragraphs");
@@ -227,7 +328,7 @@ impl<'a> FromHTML<'a> for Story<'a> {
 
                 let side_shorthand = bias
                     .split(':')
-                    .last()
+                    .next_back()
                     .or_error_msg("bug: unexpected bias format (pre-check passed, parsing failed)")?
                     .trim();
 
@@ -240,7 +341,7 @@ impl<'a> FromHTML<'a> for Story<'a> {
                 let paragraphs: Vec<_> = description
                     .children()
                     .filter(|node| node.is(Name("p")))
-                    .map(Paragraph)
+                    .map(Paragraph::from_node)
                     .collect();
 
                 Ok(Article {
@@ -253,17 +354,46 @@ impl<'a> FromHTML<'a> for Story<'a> {
             })
             .collect::<Result<_, _>>()?;
 
+        let mut topics: Vec<String> = Vec::new();
+        for topic in story
+            .find(Class("news-topic"))
+            .flat_map(|node| node.find(Name("a")))
+        {
+            let topic = topic.text().trim().to_owned();
+            if !topic.is_empty() && !topics.contains(&topic) {
+                topics.push(topic);
+            }
+        }
+
         Ok(Story {
             title,
             summary: paragraphs,
             articles,
             datetime,
+            topics,
         })
     }
 }
 
 fn normalize_allsides_url(relative_url: &str) -> String {
-    ["https://www.allsides.com", relative_url].concat()
+    [ALL_SIDES_HOST, relative_url].concat()
+}
+
+/// Returns the url of an AllSides story page, accepting urls relative to the AllSides host.
+///
+/// The query, the fragment and a trailing slash are dropped, so every link to a story
+/// gives the same url.
+pub fn parse_story_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    let url = url.trim_end_matches('/');
+    let url = if url.starts_with('/') {
+        normalize_allsides_url(url)
+    } else {
+        url.replacen("http://", "https://", 1)
+            .replacen("https://allsides.com", ALL_SIDES_HOST, 1)
+    };
+    Some(url).filter(|url| url.starts_with(&[ALL_SIDES_HOST, "/story/"].concat()))
 }
 
 trait OrErrorMessage<T> {
@@ -322,6 +452,7 @@ mod tests {
             r#"Right-rated outlets reported the story more prominently than left- and center-rated outlets. Coverage from the right focused on the fact that many left-rated news sources, including CNN where Cuomo's brother Chris works as an anchor, had not covered the story, framing the sources as hypocritical and protective of Democrats. Some coverage from left- and center-rated outlets concentrated on Boylan's claims; others highlighted the governor's denial and other doubts about the allegations."#
         );
         assert_eq!(parsed.datetime.timestamp(), 1608079500);
+        assert_eq!(parsed.topics, vec!["Sexual Misconduct".to_owned()]);
 
         let article_0 = &parsed.articles[0];
         assert_eq!(
@@ -413,4 +544,48 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn story_serde_roundtrip() -> anyhow::Result<()> {
+        let html = Document::from(include_str!("../data/allsides-story.html"));
+        let parsed = Story::from_html(&html)?;
+        let serialized = serde_json::to_string(&parsed)?;
+        let deserialized: Story = serde_json::from_str(&serialized)?;
+        assert_eq!(parsed, deserialized);
+        Ok(())
+    }
+
+    #[test]
+    fn story_urls() {
+        let url = "https://www.allsides.com/story/mcconnell-recognizes-biden-president-elect";
+        assert_eq!(parse_story_url(url).as_deref(), Some(url));
+        assert_eq!(
+            parse_story_url("/story/mcconnell-recognizes-biden-president-elect").as_deref(),
+            Some(url)
+        );
+        assert_eq!(
+            parse_story_url("http://allsides.com/story/mcconnell-recognizes-biden-president-elect")
+                .as_deref(),
+            Some(url)
+        );
+        assert_eq!(
+            parse_story_url("https://www.allsides.com/unbiased-balanced-news"),
+            None
+        );
+        assert_eq!(parse_story_url("https://example.com/story/test"), None);
+        assert_eq!(parse_story_url(&format!("{}/", url)).as_deref(), Some(url));
+        assert_eq!(
+            parse_story_url(&format!("{}?utm_source=twitter", url)).as_deref(),
+            Some(url)
+        );
+        assert_eq!(
+            parse_story_url(&format!("{}#comments", url)).as_deref(),
+            Some(url)
+        );
+        assert_eq!(
+            parse_story_url(&format!("{}/?utm_source=twitter#comments", url)).as_deref(),
+            Some(url)
+        );
+        assert_eq!(parse_story_url("https://www.allsides.com/story/"), None);
+    }
 }
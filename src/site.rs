@@ -0,0 +1,387 @@
+use crate::scraper::{Article, Story};
+use crate::state::Publication;
+use handlebars::Handlebars;
+use itertools::Itertools;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Numbers of the pages written by [`Site::build`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SiteReport {
+    pub stories: usize,
+    pub topics: usize,
+    pub sources: usize,
+}
+
+/// Static website mirroring the archive: an index of the stories by date,
+/// a page per story, per topic and per source.
+pub struct Site {
+    template: Handlebars<'static>,
+}
+
+/// Where a story is published on the site, along with its data.
+struct Page<'a> {
+    url: &'a str,
+    path: String,
+    story: &'a Story,
+}
+
+impl Site {
+    pub fn try_new() -> anyhow::Result<Self> {
+        let mut template = Handlebars::new();
+        template.register_partial(
+            "site-header",
+            include_str!("../data/site-header.handlebars"),
+        )?;
+        template.register_partial(
+            "site-footer",
+            include_str!("../data/site-footer.handlebars"),
+        )?;
+        template.register_template_string(
+            "index",
+            include_str!("../data/site-index-template.handlebars"),
+        )?;
+        template.register_template_string(
+            "list",
+            include_str!("../data/site-list-template.handlebars"),
+        )?;
+        template.register_template_string(
+            "story",
+            include_str!("../data/site-story-template.handlebars"),
+        )?;
+        Ok(Site { template })
+    }
+
+    /// Renders the published stories into the directory, overwriting the pages built before
+    /// and removing those of the stories, topics and sources no longer published,
+    /// e.g. of the forgotten stories.
+    ///
+    /// The stories still to be published to some of their destinations are left out.
+    pub fn build(
+        &self,
+        publications: &[(String, Publication)],
+        dir: &Path,
+    ) -> anyhow::Result<SiteReport> {
+        let mut paths = HashSet::new();
+        let mut pages = publications
+            .iter()
+            .filter(|(_, publication)| !publication.partial)
+            .sorted_by_key(|(url, _)| url)
+            .map(|(url, publication)| Page {
+                url,
+                path: unique_path(&mut paths, &story_slug(url)),
+                story: &publication.story,
+            })
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|page| std::cmp::Reverse(page.story.datetime));
+
+        let mut topics: BTreeMap<String, (String, Vec<&Page>)> = BTreeMap::new();
+        let mut sources: BTreeMap<String, (String, Vec<&Page>)> = BTreeMap::new();
+        for page in &pages {
+            for topic in &page.story.topics {
+                let entry = topics
+                    .entry(slug(topic))
+                    .or_insert_with(|| (topic.clone(), Vec::new()));
+                entry.1.push(page);
+            }
+            for source in page.story.articles.iter().map(|a| a.source.trim()).unique() {
+                let entry = sources
+                    .entry(slug(source))
+                    .or_insert_with(|| (source.to_owned(), Vec::new()));
+                entry.1.push(page);
+            }
+        }
+
+        for page in &pages {
+            let data = story_data(page);
+            self.write(dir, &page.path, "story", &data)?;
+        }
+
+        let days = pages
+            .iter()
+            .group_by(|page| page.story.datetime.format("%Y-%m-%d").to_string())
+            .into_iter()
+            .map(|(day, pages)| {
+                let stories = pages
+                    .map(|page| json!({ "story_path": page.path, "story_title": page.story.title }))
+                    .collect::<Vec<_>>();
+                json!({ "day": day, "stories": stories })
+            })
+            .collect::<Vec<_>>();
+        let index = json!({ "page_title": "Stories", "root": "", "days": days });
+        self.write(dir, "index.html", "index", &index)?;
+
+        self.write_group(dir, "topics", "Topics", &topics)?;
+        self.write_group(dir, "sources", "Sources", &sources)?;
+
+        remove_stale(dir, "stories", |path| paths.contains(path))?;
+        remove_stale(dir, "topics", |path| is_group_page(&topics, "topics", path))?;
+        remove_stale(dir, "sources", |path| {
+            is_group_page(&sources, "sources", path)
+        })?;
+
+        Ok(SiteReport {
+            stories: pages.len(),
+            topics: topics.len(),
+            sources: sources.len(),
+        })
+    }
+
+    /// Writes a page per group listing its stories, and the index of the groups.
+    fn write_group(
+        &self,
+        dir: &Path,
+        kind: &str,
+        title: &str,
+        groups: &BTreeMap<String, (String, Vec<&Page>)>,
+    ) -> anyhow::Result<()> {
+        for (slug, (name, pages)) in groups {
+            let links = pages
+                .iter()
+                .map(|page| json!({ "link_path": page.path, "link_title": page.story.title }))
+                .collect::<Vec<_>>();
+            let data = json!({ "page_title": name, "root": "../", "links": links });
+            self.write(dir, &format!("{}/{}.html", kind, slug), "list", &data)?;
+        }
+
+        let links = groups
+            .iter()
+            .map(|(slug, (name, pages))| {
+                json!({
+                    "link_path": format!("{}/{}.html", kind, slug),
+                    "link_title": name,
+                    "link_count": pages.len(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let data = json!({ "page_title": title, "root": "../", "links": links });
+        self.write(dir, &format!("{}/index.html", kind), "list", &data)
+    }
+
+    fn write(&self, dir: &Path, path: &str, template: &str, data: &Value) -> anyhow::Result<()> {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, self.template.render(template, data)?)?;
+        Ok(())
+    }
+}
+
+/// Removes the pages of the kind which are not to be kept.
+fn remove_stale(dir: &Path, kind: &str, keep: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+    let entries = match std::fs::read_dir(dir.join(kind)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.ends_with(".html") && !keep(&format!("{}/{}", kind, name)) {
+            std::fs::remove_file(dir.join(kind).join(name.as_ref()))?;
+        }
+    }
+    Ok(())
+}
+
+fn is_group_page(groups: &BTreeMap<String, (String, Vec<&Page>)>, kind: &str, path: &str) -> bool {
+    path == format!("{}/index.html", kind)
+        || groups
+            .keys()
+            .any(|slug| path == format!("{}/{}.html", kind, slug))
+}
+
+fn story_data(page: &Page) -> Value {
+    let story = page.story;
+    let paragraphs = |paragraphs: &[crate::scraper::Paragraph]| {
+        paragraphs
+            .iter()
+            .map(|p| format!("<p>{}</p>", p.telegram_html().trim()))
+            .join("\n")
+    };
+    let sides = story
+        .articles
+        .iter()
+        .sorted_by_key(|article| article.side)
+        .group_by(|article| article.side)
+        .into_iter()
+        .map(|(side, articles)| {
+            let side_stories = articles
+                .map(|article: &Article| {
+                    let source_path = format!("sources/{}.html", slug(article.source.trim()));
+                    json!({
+                        "side_story_title": article.title,
+                        "side_story_url": article.url,
+                        "side_story_source": article.source,
+                        "side_story_source_path": source_path,
+                        "side_story_content": paragraphs(&article.summary),
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "side_emoji": side.emoji(),
+                "side_label": side.label(),
+                "side_stories": side_stories,
+            })
+        })
+        .collect::<Vec<_>>();
+    let topics = story
+        .topics
+        .iter()
+        .map(|topic| {
+            let path = format!("topics/{}.html", slug(topic));
+            json!({ "topic_name": topic, "topic_path": path })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "page_title": story.title,
+        "root": "../",
+        "story_title": story.title,
+        "story_url": page.url,
+        "story_date": story.datetime.format("%Y-%m-%d").to_string(),
+        "story_content": paragraphs(&story.summary),
+        "story_topics": topics,
+        "sides": sides,
+    })
+}
+
+/// Makes the file name of the story page of the whole url path, as the last segment alone
+/// may be empty or shared by stories of different sections.
+/// Falls back to a hash of the url if the path has no letters or digits.
+fn story_slug(url: &str) -> String {
+    let path = url
+        .split("://")
+        .last()
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .unwrap_or(url);
+    match words(path) {
+        slug if slug.is_empty() => hash(url),
+        slug => slug,
+    }
+}
+
+/// Makes the path of the story page, numbered if the slug is taken by another story.
+fn unique_path(paths: &mut HashSet<String>, slug: &str) -> String {
+    let mut path = format!("stories/{}.html", slug);
+    let mut number = 1;
+    while !paths.insert(path.clone()) {
+        number += 1;
+        path = format!("stories/{}-{}.html", slug, number);
+    }
+    path
+}
+
+/// Makes a file name of the text, e.g. `fox-news-online-news` of "Fox News (Online News)".
+/// Falls back to a hash of the text if it has no letters or digits, e.g. is all emoji.
+fn slug(text: &str) -> String {
+    match words(text) {
+        slug if slug.is_empty() => hash(text),
+        slug => slug,
+    }
+}
+
+/// Joins the lowercase words of the text with dashes.
+fn words(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .join("-")
+}
+
+/// Returns the first 16 hex digits of the SHA-256 of the text.
+fn hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slug("Fox News (Online News)"), "fox-news-online-news");
+        assert_eq!(slug("Sexual Misconduct"), "sexual-misconduct");
+        assert_eq!(
+            story_slug("https://www.allsides.com/story/cuomo-accused/"),
+            "story-cuomo-accused"
+        );
+        assert_eq!(slug("🇺🇸"), hash("🇺🇸"));
+        assert_eq!(hash("🇺🇸").len(), 16);
+        assert_ne!(slug("🇺🇸"), slug("🇬🇧"));
+        assert_eq!(
+            story_slug("https://www.allsides.com/🇺🇸"),
+            hash("https://www.allsides.com/🇺🇸")
+        );
+
+        let mut paths = HashSet::new();
+        assert_eq!(unique_path(&mut paths, "story"), "stories/story.html");
+        assert_eq!(unique_path(&mut paths, "story"), "stories/story-2.html");
+    }
+
+    #[test]
+    fn build_site() -> anyhow::Result<()> {
+        let publications = vec![
+            (
+                "https://www.allsides.com/story/ny-gov-cuomo-accused".to_owned(),
+                fixtures::publication(chrono::Utc::now()),
+            ),
+            (
+                "https://www.allsides.com/story/still-queued".to_owned(),
+                Publication {
+                    partial: true,
+                    ..fixtures::publication(chrono::Utc::now())
+                },
+            ),
+        ];
+        let dir = std::env::temp_dir().join(format!("astg-site-{}", std::process::id()));
+        let report = Site::try_new()?.build(&publications, &dir)?;
+        assert_eq!(
+            report,
+            SiteReport {
+                stories: 1,
+                topics: 1,
+                sources: 3
+            }
+        );
+
+        let read = |path: &str| std::fs::read_to_string(dir.join(path)).unwrap();
+        let index = read("index.html");
+        assert!(index.contains(r#"<h2 class="date">2020-12-15</h2>"#));
+        assert!(index.contains(r#"<a href="stories/story-ny-gov-cuomo-accused.html">"#));
+
+        let story = read("stories/story-ny-gov-cuomo-accused.html");
+        assert!(story.contains("<h2>🟦 From the Left</h2>"));
+        assert!(story.contains(r#"<a href="../sources/vox.html">Vox</a>"#));
+        assert!(
+            story.contains(r#"<a href="../topics/sexual-misconduct.html">Sexual Misconduct</a>"#)
+        );
+
+        let topic = read("topics/sexual-misconduct.html");
+        assert!(topic.contains(r#"<a href="../stories/story-ny-gov-cuomo-accused.html">"#));
+        assert!(!dir.join("stories/story-still-queued.html").exists());
+        let sources = read("sources/index.html");
+        assert!(sources.contains(
+            r#"<a href="../sources/fox-news-online-news.html">Fox News (Online News)</a> (1)"#
+        ));
+
+        // The pages of the forgotten stories are removed on the next build.
+        Site::try_new()?.build(&publications[1..], &dir)?;
+        assert!(!dir.join("stories/story-ny-gov-cuomo-accused.html").exists());
+        assert!(!dir.join("topics/sexual-misconduct.html").exists());
+        assert!(!dir.join("sources/vox.html").exists());
+        assert!(dir.join("sources/index.html").exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use crate::config::SmtpOptions;
+use anyhow::{anyhow, bail};
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Name the client introduces itself with.
+const CLIENT_NAME: &str = "astg";
+
+/// Time a connection, a command or the message data may take at most.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A minimal SMTP client delivering composed messages to a relay,
+/// see https://tools.ietf.org/html/rfc5321
+pub struct Smtp {
+    host: String,
+    port: u16,
+    starttls: bool,
+    credentials: Option<(String, String)>,
+}
+
+impl Smtp {
+    pub fn try_new(opts: &SmtpOptions) -> anyhow::Result<Self> {
+        let host = match &opts.host {
+            Some(host) => host.clone(),
+            None => bail!("SMTP server is not configured (ASTG_SMTP_HOST)"),
+        };
+        let credentials = match (&opts.username, &opts.password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            (None, None) => None,
+            _ => bail!("both ASTG_SMTP_USERNAME and ASTG_SMTP_PASSWORD are required to log in"),
+        };
+        Ok(Smtp {
+            host,
+            port: opts.port,
+            starttls: opts.starttls,
+            credentials,
+        })
+    }
+
+    /// Delivers the message, which must have its headers and CRLF line endings, to the recipients.
+    pub async fn send(&self, from: &str, to: &[String], message: &str) -> anyhow::Result<()> {
+        let stream = timeout(TcpStream::connect((self.host.as_str(), self.port))).await?;
+        let mut connection = Connection::new(stream);
+        connection.expect(220).await?;
+        connection
+            .command(&format!("EHLO {}", CLIENT_NAME), 250)
+            .await?;
+        if !self.starttls {
+            return self.deliver(connection, from, to, message).await;
+        }
+
+        connection.command("STARTTLS", 220).await?;
+        let connector = tokio_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+        let stream = timeout(connector.connect(&self.host, connection.into_inner())).await?;
+        let mut connection = Connection::new(stream);
+        connection
+            .command(&format!("EHLO {}", CLIENT_NAME), 250)
+            .await?;
+        self.deliver(connection, from, to, message).await
+    }
+
+    async fn deliver<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut connection: Connection<S>,
+        from: &str,
+        to: &[String],
+        message: &str,
+    ) -> anyhow::Result<()> {
+        if let Some((username, password)) = &self.credentials {
+            let token = base64::encode(format!("\0{}\0{}", username, password));
+            connection
+                .command(&format!("AUTH PLAIN {}", token), 235)
+                .await?;
+        }
+        connection
+            .command(&format!("MAIL FROM:<{}>", from), 250)
+            .await?;
+        for recipient in to {
+            connection
+                .command(&format!("RCPT TO:<{}>", recipient), 250)
+                .await?;
+        }
+        connection.command("DATA", 354).await?;
+        connection.send(&dot_stuff(message)).await?;
+        connection.command(".", 250).await?;
+        connection.command("QUIT", 221).await?;
+        Ok(())
+    }
+}
+
+struct Connection<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(stream: S) -> Self {
+        Connection {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    async fn send(&mut self, text: &str) -> anyhow::Result<()> {
+        let stream = self.stream.get_mut();
+        timeout(async {
+            stream.write_all(text.as_bytes()).await?;
+            stream.flush().await
+        })
+        .await
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> anyhow::Result<String> {
+        self.send(&format!("{}\r\n", command)).await?;
+        self.expect(expected).await.map_err(|e| {
+            // Don't leak the credentials into the logs
+            let command = if command.starts_with("AUTH") {
+                "AUTH"
+            } else {
+                command
+            };
+            anyhow!("smtp: {}: {}", command, e)
+        })
+    }
+
+    /// Reads a reply, which may span multiple lines, and checks its code.
+    async fn expect(&mut self, expected: u16) -> anyhow::Result<String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if timeout(self.stream.read_line(&mut line)).await? == 0 {
+                bail!("connection closed");
+            }
+            let line = line.trim_end();
+            reply.push_str(line);
+            reply.push('\n');
+            // The last line of a reply has a space after the code, the others a dash
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+        }
+        let code: u16 = reply
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+        if code != expected {
+            bail!("unexpected reply: {}", reply.trim_end());
+        }
+        Ok(reply)
+    }
+}
+
+/// Fails the operation if it doesn't complete within the timeout.
+async fn timeout<T, E>(operation: impl Future<Output = Result<T, E>>) -> anyhow::Result<T>
+where
+    anyhow::Error: From<E>,
+{
+    match tokio::time::timeout(TIMEOUT, operation).await {
+        Ok(result) => Ok(result?),
+        Err(_) => bail!("timed out after {}s", TIMEOUT.as_secs()),
+    }
+}
+
+/// Escapes the lines starting with a dot, and ends the message data.
+fn dot_stuff(message: &str) -> String {
+    let mut data = message
+        .split("\r\n")
+        .map(|line| {
+            if line.starts_with('.') {
+                format!(".{}", line)
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    if !data.ends_with("\r\n") {
+        data.push_str("\r\n");
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockSmtpServer;
+
+    #[tokio::test]
+    async fn send_message() -> anyhow::Result<()> {
+        let server = MockSmtpServer::start();
+        let smtp = Smtp::try_new(&SmtpOptions {
+            host: Some("127.0.0.1".to_owned()),
+            port: server.port,
+            starttls: false,
+            username: Some("user".to_owned()),
+            password: Some("secret".to_owned()),
+        })?;
+        smtp.send(
+            "astg@example.com",
+            &["a@example.com".to_owned(), "b@example.com".to_owned()],
+            "Subject: test\r\n\r\nhello\r\n.hidden dot\r\n",
+        )
+        .await?;
+
+        assert_eq!(
+            server.session(),
+            vec![
+                "EHLO astg",
+                "AUTH PLAIN AHVzZXIAc2VjcmV0",
+                "MAIL FROM:<astg@example.com>",
+                "RCPT TO:<a@example.com>",
+                "RCPT TO:<b@example.com>",
+                "DATA",
+                "Subject: test",
+                "",
+                "hello",
+                "..hidden dot",
+                ".",
+                "QUIT",
+            ]
+        );
+        Ok(())
+    }
+}
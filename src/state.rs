@@ -1,23 +1,1369 @@
+use crate::publisher::Receipt;
+use crate::scraper::Story;
+use crate::tg_bot::Post;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{TransactionError, Transactional};
+use std::fmt;
 use std::path::Path;
 
-#[derive(Debug)]
+/// Value of the stories marked as published without being posted.
+const SKIPPED: &[u8] = b"skipped";
+
+#[derive(Debug, Clone)]
 pub struct State {
     stories: sled::Db,
+    meta: sled::Tree,
+    /// Maps the chat and message ids of published messages to the urls of their stories
+    messages: sled::Tree,
+    /// Stories waiting for the admin's approval, by id
+    pending: sled::Tree,
+    /// Webhook deliveries which kept failing, by id
+    dead_letters: sled::Tree,
+    /// Webhook deliveries not delivered or given up yet, by id
+    deliveries: sled::Tree,
+    /// Rendered posts waiting to be sent to their destinations, by id
+    outbox: sled::Tree,
+    /// Stories collected for the digests, by destination name and id
+    digests: sled::Tree,
+    /// The pending stories, the posts in the outbox and the digest entries, by url,
+    /// see [`url_key`]
+    by_url: sled::Tree,
+}
+
+/// A story as it was published.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Publication {
+    pub story: Story,
+    pub published_at: DateTime<Utc>,
+    /// The posts of the story, one per destination it was published to
+    #[serde(default)]
+    pub posts: Vec<PublishedPost>,
+    /// Set while the story is still to be published to some of its destinations
+    #[serde(default)]
+    pub partial: bool,
+    /// When the publication was last finished, set once it stops being partial
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+/// The fields of a publication recorded before the stories were published to destinations.
+#[derive(Deserialize)]
+struct LegacyPublication {
+    /// Ids of the messages in the Telegram channel
+    #[serde(default)]
+    messages: Vec<i32>,
+}
+
+/// The messages a story was published as to a destination.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedPost {
+    /// [`Destination::name`](crate::config::Destination::name)
+    pub destination: String,
+    #[serde(flatten)]
+    pub receipt: Receipt,
+}
+
+impl Publication {
+    pub fn post(&self, destination: &str) -> Option<&PublishedPost> {
+        self.posts
+            .iter()
+            .find(|post| post.destination == destination)
+    }
+
+    /// When the story was published to all its destinations, `None` while it's partial.
+    ///
+    /// The stories published before the time was recorded were published all at once.
+    pub fn completed(&self) -> Option<DateTime<Utc>> {
+        if self.partial {
+            None
+        } else {
+            Some(self.completed_at.unwrap_or(self.published_at))
+        }
+    }
+}
+
+/// A story waiting for the admin's approval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingStory {
+    pub url: String,
+    pub story: Story,
+    /// [`Destination::name`](crate::config::Destination::name) the post is for
+    pub destination: String,
+    pub post: Post,
+    pub queued_at: DateTime<Utc>,
+    /// The message asking the admin for the edited post, if the admin is editing it
+    #[serde(default)]
+    pub edit_prompt: Option<i32>,
+    /// Set once the admin edited the post, which is then published as it is
+    /// instead of being rendered again on approval
+    #[serde(default)]
+    pub edited: bool,
+}
+
+/// A webhook delivery given up after its retries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub endpoint: String,
+    pub event: String,
+    pub payload: String,
+    /// The error of the last attempt
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// A webhook delivery, recorded before it's attempted so it's delivered
+/// even if the process exits in between.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub endpoint: String,
+    pub event: String,
+    pub payload: String,
+    /// The dead letter the delivery delivers again, which is kept until it's delivered
+    #[serde(default)]
+    pub dead_letter: Option<u64>,
+}
+
+/// What a post of the outbox is of, which decides where it's recorded once it's sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subject {
+    /// A story, the post is recorded in its publication
+    Story { url: String, story: Story },
+    /// The digest of the stories with the urls, the post is recorded in each of their publications
+    Digest { urls: Vec<String> },
+    /// The weekly statistics, the post isn't recorded
+    Stats,
+}
+
+impl Subject {
+    /// The url of the story, if the post is of a single story.
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            Subject::Story { url, .. } => Some(url),
+            Subject::Digest { .. } | Subject::Stats => None,
+        }
+    }
+
+    /// The urls of the stories the post is of, alone or in a digest.
+    pub fn urls(&self) -> Vec<&str> {
+        match self {
+            Subject::Story { url, .. } => vec![url.as_str()],
+            Subject::Digest { urls } => urls.iter().map(String::as_str).collect(),
+            Subject::Stats => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Story { url, .. } => f.write_str(url),
+            Subject::Digest { urls } => write!(f, "the digest of {} stories", urls.len()),
+            Subject::Stats => f.write_str("the weekly statistics"),
+        }
+    }
+}
+
+/// A rendered post waiting to be sent to its destination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxItem {
+    pub subject: Subject,
+    /// [`Destination::name`](crate::config::Destination::name) the post is for
+    pub destination: String,
+    pub post: Post,
+    pub queued_at: DateTime<Utc>,
+    /// Failed attempts to send the post
+    #[serde(default)]
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    /// The error of the last attempt
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Set once sending the post failed for good, the post is kept for the admin to inspect
+    #[serde(default)]
+    pub failed: bool,
+    /// Set just before the post is sent, it's still set after a crash or an error while
+    /// recording the sent post, in which case the post may or may not have been published
+    #[serde(default)]
+    pub sending_since: Option<DateTime<Utc>>,
+    /// The messages of the post published by the previous attempts, recorded as they're sent
+    /// so the post is resumed after them, see [`Progress`](crate::publisher::Progress)
+    #[serde(default)]
+    pub published: Option<Receipt>,
+}
+
+impl OutboxItem {
+    /// Makes the item of a post due right away.
+    pub fn new(subject: Subject, destination: &str, post: Post) -> Self {
+        let now = Utc::now();
+        OutboxItem {
+            subject,
+            destination: destination.to_owned(),
+            post,
+            queued_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            failed: false,
+            sending_since: None,
+            published: None,
+        }
+    }
+}
+
+/// A story waiting for the digest of a destination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestEntry {
+    pub url: String,
+    pub story: Story,
+    pub collected_at: DateTime<Utc>,
 }
 
 impl State {
     pub fn try_new(stories_db_path: &Path) -> anyhow::Result<Self> {
-        let db = sled::open(stories_db_path)?;
-        Ok(State { stories: db })
+        Self::from_db(open_db(stories_db_path)?)
+    }
+
+    /// Opens a state which is deleted once dropped.
+    #[cfg(test)]
+    pub fn try_new_temporary() -> anyhow::Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: sled::Db) -> anyhow::Result<Self> {
+        let meta = db.open_tree("meta")?;
+        let messages = db.open_tree("messages")?;
+        let pending = db.open_tree("pending")?;
+        let dead_letters = db.open_tree("dead_letters")?;
+        let deliveries = db.open_tree("deliveries")?;
+        let outbox = db.open_tree("outbox")?;
+        let digests = db.open_tree("digests")?;
+        let by_url = db.open_tree("by_url")?;
+        let state = State {
+            stories: db,
+            meta,
+            messages,
+            pending,
+            dead_letters,
+            deliveries,
+            outbox,
+            digests,
+            by_url,
+        };
+        if !state.meta.contains_key("by_url")? {
+            state.index_urls()?;
+        }
+        Ok(state)
+    }
+
+    /// Indexes the pending stories, the posts in the outbox and the digest entries by url,
+    /// for the databases written before the index was introduced.
+    fn index_urls(&self) -> anyhow::Result<()> {
+        self.by_url.clear()?;
+        for (id, pending) in self.pending_stories()? {
+            self.by_url
+                .insert(url_key(&pending.url, PENDING, &id.to_be_bytes()), &[])?;
+        }
+        for (id, item) in self.outbox()? {
+            for url in item.subject.urls() {
+                self.by_url
+                    .insert(url_key(url, QUEUED, &id.to_be_bytes()), &[])?;
+            }
+        }
+        for entry in self.digests.iter() {
+            let (key, entry) = entry?;
+            let entry: DigestEntry = serde_json::from_slice(&entry)?;
+            self.by_url
+                .insert(url_key(&entry.url, COLLECTED, &key), &[])?;
+        }
+        self.meta.insert("by_url", "true")?;
+        self.stories.flush()?;
+        Ok(())
+    }
+
+    /// Opens a named tree in the same database, for data kept alongside the stories.
+    pub fn open_tree(&self, name: &str) -> anyhow::Result<sled::Tree> {
+        Ok(self.stories.open_tree(name)?)
     }
 
+    /// Whether the story is published to all its destinations, or marked as published.
     pub fn is_published(&self, url: &str) -> anyhow::Result<bool> {
-        Ok(self.stories.contains_key(url)?)
+        let published = match self.stories.get(url)? {
+            Some(value) => serde_json::from_slice::<Publication>(&value)
+                .map_or(true, |publication| !publication.partial),
+            None => false,
+        };
+        Ok(published)
     }
 
-    pub async fn set_published(&mut self, url: &str) -> anyhow::Result<()> {
-        self.stories.insert(url, "true")?;
+    /// Returns the published story, if it was recorded with its content.
+    ///
+    /// Stories published before the content was stored are only marked as published,
+    /// so `None` is returned for them as well as for unknown urls.
+    pub fn publication(&self, url: &str) -> anyhow::Result<Option<Publication>> {
+        let value = match self.stories.get(url)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        Ok(serde_json::from_slice(&value).ok())
+    }
+
+    /// Finds the published story one of the messages of which has the given ids.
+    pub fn publication_by_message(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+    ) -> anyhow::Result<Option<(String, Publication)>> {
+        let url = match self.messages.get(message_key(chat_id, message_id))? {
+            Some(url) => String::from_utf8(url.to_vec())?,
+            None => return Ok(None),
+        };
+        Ok(self
+            .publication(&url)?
+            .map(|publication| (url, publication)))
+    }
+
+    /// Counts the stories published to all their destinations,
+    /// leaving out the skipped ones.
+    pub fn published_count(&self) -> anyhow::Result<usize> {
+        let mut count = 0;
+        for entry in self.stories.iter().values() {
+            let value = entry?;
+            let published = match serde_json::from_slice::<Publication>(&value) {
+                Ok(publication) => !publication.partial,
+                // Stories published before the content was stored
+                Err(_) => value != SKIPPED,
+            };
+            if published {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Iterates over the published stories recorded with their content.
+    pub fn publications(&self) -> impl Iterator<Item = anyhow::Result<(String, Publication)>> {
+        self.stories.iter().filter_map(|entry| {
+            let (url, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let publication = serde_json::from_slice(&value).ok()?;
+            Some(
+                String::from_utf8(url.to_vec())
+                    .map(|url| (url, publication))
+                    .map_err(Into::into),
+            )
+        })
+    }
+
+    /// Records the story as published to all its destinations,
+    /// keeping the posts it was already published as.
+    ///
+    /// The publication is completed now if it was partial.
+    pub async fn set_published(&mut self, url: &str, story: &Story) -> anyhow::Result<()> {
+        let mut publication = self.published_or_new(url, story)?;
+        if publication.partial || publication.completed_at.is_none() {
+            publication.completed_at = Some(Utc::now());
+        }
+        publication.partial = false;
+        self.stories
+            .insert(url, serde_json::to_vec(&publication)?)?;
         self.stories.flush_async().await?;
         Ok(())
     }
+
+    /// Records the stories the post is of as published to its destination, replacing their
+    /// previous posts to the same destination, and removes the post from the outbox at once.
+    ///
+    /// A story recorded for the first time is [`Publication::partial`]
+    /// until [`State::set_published`] is called.
+    pub async fn confirm_post(
+        &mut self,
+        outbox_id: u64,
+        subject: &Subject,
+        post: PublishedPost,
+    ) -> anyhow::Result<()> {
+        let mut publications = Vec::new();
+        let mut message_keys = Vec::new();
+        match subject {
+            Subject::Story { url, story } => {
+                if let Receipt::Telegram { chat_id, messages } = &post.receipt {
+                    message_keys = messages
+                        .iter()
+                        .map(|&id| (message_key(*chat_id, id), url.as_str()))
+                        .collect();
+                }
+                publications.push((url.as_str(), self.published_or_new(url, story)?));
+            }
+            Subject::Digest { urls } => {
+                for url in urls {
+                    // Stories forgotten since they were collected stay forgotten
+                    if let Some(publication) = self.publication(url)? {
+                        publications.push((url.as_str(), publication));
+                    }
+                }
+            }
+            Subject::Stats => {}
+        }
+        let publications = publications
+            .into_iter()
+            .map(|(url, mut publication)| {
+                publication
+                    .posts
+                    .retain(|other| other.destination != post.destination);
+                publication.posts.push(post.clone());
+                Ok((url, serde_json::to_vec(&publication)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let url_keys = subject
+            .urls()
+            .into_iter()
+            .map(|url| url_key(url, QUEUED, &outbox_id.to_be_bytes()))
+            .collect::<Vec<_>>();
+
+        let trees: (&sled::Tree, &sled::Tree, &sled::Tree, &sled::Tree) =
+            (&self.stories, &self.messages, &self.outbox, &self.by_url);
+        trees
+            .transaction(|(stories, messages, outbox, by_url)| {
+                for (key, url) in &message_keys {
+                    messages.insert(key.as_slice(), *url)?;
+                }
+                for (url, publication) in &publications {
+                    stories.insert(*url, publication.as_slice())?;
+                }
+                outbox.remove(&outbox_id.to_be_bytes())?;
+                for key in &url_keys {
+                    by_url.remove(key.as_slice())?;
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot record the post: {}", e))?;
+        self.stories.flush_async().await?;
+        Ok(())
+    }
+
+    fn published_or_new(&self, url: &str, story: &Story) -> anyhow::Result<Publication> {
+        let mut publication = self.publication(url)?.unwrap_or_else(|| Publication {
+            story: story.clone(),
+            published_at: Utc::now(),
+            posts: Vec::new(),
+            partial: true,
+            completed_at: None,
+        });
+        publication.story = story.clone();
+        Ok(publication)
+    }
+
+    /// Whether the stories published before the destinations were introduced
+    /// still have their message ids to migrate, see [`State::migrate_messages`].
+    pub fn has_legacy_messages(&self) -> anyhow::Result<bool> {
+        for key in self.messages.iter().keys() {
+            if key?.len() == LEGACY_MESSAGE_KEY_LEN {
+                return Ok(true);
+            }
+        }
+        for value in self.stories.iter().values() {
+            if legacy_messages(&value?).is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Moves the ids of the messages the stories were published as to the Telegram channel,
+    /// before the destinations were introduced, into their posts to the destination
+    /// of the channel. Returns the number of migrated stories.
+    pub async fn migrate_messages(
+        &mut self,
+        destination: &str,
+        chat_id: i64,
+    ) -> anyhow::Result<usize> {
+        for entry in self.messages.iter() {
+            let (key, url) = entry?;
+            if key.len() == LEGACY_MESSAGE_KEY_LEN {
+                let mut bytes = [0; LEGACY_MESSAGE_KEY_LEN];
+                bytes.copy_from_slice(&key);
+                let id = i32::from_be_bytes(bytes);
+                self.messages.insert(message_key(chat_id, id), url)?;
+                self.messages.remove(key)?;
+            }
+        }
+
+        let mut count = 0;
+        for entry in self.stories.iter() {
+            let (url, value) = entry?;
+            let messages = match legacy_messages(&value) {
+                Some(messages) => messages,
+                None => continue,
+            };
+            // The legacy field isn't part of the publication, so it's dropped as it's written
+            let mut publication: Publication = serde_json::from_slice(&value)?;
+            if publication.post(destination).is_none() {
+                publication.posts.push(PublishedPost {
+                    destination: destination.to_owned(),
+                    receipt: Receipt::Telegram { chat_id, messages },
+                });
+            }
+            self.stories
+                .insert(url, serde_json::to_vec(&publication)?)?;
+            count += 1;
+        }
+        self.messages.flush_async().await?;
+        self.stories.flush_async().await?;
+        Ok(count)
+    }
+
+    /// Marks the story as published without recording its content.
+    pub async fn set_skipped(&mut self, url: &str) -> anyhow::Result<()> {
+        self.stories.insert(url, SKIPPED)?;
+        self.stories.flush_async().await?;
+        Ok(())
+    }
+
+    /// Removes the story from the published ones along with its messages, its posts
+    /// in the outbox, its entries in the digests and its posts waiting for approval,
+    /// returns whether it was published.
+    pub async fn forget(&mut self, url: &str) -> anyhow::Result<bool> {
+        let message_keys = self
+            .publication(url)?
+            .map(|publication| {
+                publication
+                    .posts
+                    .iter()
+                    .flat_map(|post| match &post.receipt {
+                        Receipt::Telegram { chat_id, messages } => messages
+                            .iter()
+                            .map(|&id| message_key(*chat_id, id))
+                            .collect(),
+                        _ => Vec::new(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let pending_keys = self.keys_by_url(url, PENDING)?;
+        let digest_keys = self.keys_by_url(url, COLLECTED)?;
+        // The digests with the story are still sent, with the other stories
+        let mut outbox_keys = Vec::new();
+        for key in self.keys_by_url(url, QUEUED)? {
+            let item = self.outbox.get(&key)?;
+            let item = item.map(|item| serde_json::from_slice::<OutboxItem>(&item));
+            if item
+                .transpose()?
+                .is_some_and(|item| item.subject.url() == Some(url))
+            {
+                outbox_keys.push(key);
+            }
+        }
+
+        let trees: (
+            &sled::Tree,
+            &sled::Tree,
+            &sled::Tree,
+            &sled::Tree,
+            &sled::Tree,
+            &sled::Tree,
+        ) = (
+            &self.stories,
+            &self.messages,
+            &self.outbox,
+            &self.pending,
+            &self.digests,
+            &self.by_url,
+        );
+        let removed = trees
+            .transaction(|(stories, messages, outbox, pending, digests, by_url)| {
+                for key in &message_keys {
+                    messages.remove(key)?;
+                }
+                for key in &outbox_keys {
+                    outbox.remove(key.as_slice())?;
+                    by_url.remove(url_key(url, QUEUED, key))?;
+                }
+                for key in &pending_keys {
+                    pending.remove(key.as_slice())?;
+                    by_url.remove(url_key(url, PENDING, key))?;
+                }
+                for key in &digest_keys {
+                    digests.remove(key.as_slice())?;
+                    by_url.remove(url_key(url, COLLECTED, key))?;
+                }
+                Ok(stories.remove(url)?.is_some())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot forget the story: {}", e))?;
+        self.stories.flush_async().await?;
+        Ok(removed)
+    }
+
+    pub fn is_paused(&self) -> anyhow::Result<bool> {
+        Ok(self.meta.contains_key("paused")?)
+    }
+
+    pub async fn set_paused(&mut self, paused: bool) -> anyhow::Result<()> {
+        if paused {
+            self.meta.insert("paused", "true")?;
+        } else {
+            self.meta.remove("paused")?;
+        }
+        self.meta.flush_async().await?;
+        Ok(())
+    }
+
+    pub fn telegraph_token(&self) -> anyhow::Result<Option<String>> {
+        self.meta
+            .get("telegraph_token")?
+            .map(|token| Ok(String::from_utf8(token.to_vec())?))
+            .transpose()
+    }
+
+    pub async fn set_telegraph_token(&mut self, token: &str) -> anyhow::Result<()> {
+        self.meta.insert("telegraph_token", token)?;
+        self.meta.flush_async().await?;
+        Ok(())
+    }
+
+    /// When the last email digest was sent.
+    pub fn email_digest_sent_at(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        self.meta
+            .get("email_digest_sent_at")?
+            .map(|at| Ok(std::str::from_utf8(&at)?.parse()?))
+            .transpose()
+    }
+
+    pub async fn set_email_digest_sent_at(&mut self, at: DateTime<Utc>) -> anyhow::Result<()> {
+        self.meta
+            .insert("email_digest_sent_at", at.to_rfc3339().as_bytes())?;
+        self.meta.flush_async().await?;
+        Ok(())
+    }
+
+    /// When the last weekly statistics were posted.
+    pub fn stats_sent_at(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        self.meta
+            .get("stats_sent_at")?
+            .map(|at| Ok(std::str::from_utf8(&at)?.parse()?))
+            .transpose()
+    }
+
+    /// Records the weekly statistics as sent at the time and queues their posts, at once.
+    pub async fn stats_sent(
+        &mut self,
+        posts: &[OutboxItem],
+        at: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let posts = posts
+            .iter()
+            .map(|item| -> anyhow::Result<_> {
+                Ok((self.stories.generate_id()?, serde_json::to_vec(item)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // The statistics aren't of any story, so they aren't indexed by url
+        let trees: (&sled::Tree, &sled::Tree) = (&self.outbox, &self.meta);
+        trees
+            .transaction(|(outbox, meta)| {
+                for (id, item) in &posts {
+                    outbox.insert(&id.to_be_bytes(), item.as_slice())?;
+                }
+                meta.insert("stats_sent_at", at.to_rfc3339().as_bytes())?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| {
+                anyhow::anyhow!("cannot record the statistics: {}", e)
+            })?;
+        self.outbox.flush_async().await?;
+        Ok(())
+    }
+
+    /// When the last post to the destination was sent, to space the posts.
+    pub fn last_post_at(&self, destination: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+        self.meta
+            .get(format!("last_post_at:{}", destination))?
+            .map(|at| Ok(std::str::from_utf8(&at)?.parse()?))
+            .transpose()
+    }
+
+    pub async fn set_last_post_at(
+        &mut self,
+        destination: &str,
+        at: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        self.meta.insert(
+            format!("last_post_at:{}", destination),
+            at.to_rfc3339().as_bytes(),
+        )?;
+        self.meta.flush_async().await?;
+        Ok(())
+    }
+
+    /// Queues the story for approval, returns its id.
+    pub async fn add_pending(&mut self, pending: &PendingStory) -> anyhow::Result<u64> {
+        let id = self.stories.generate_id()?;
+        self.update_pending(id, pending).await?;
+        Ok(id)
+    }
+
+    pub async fn update_pending(&mut self, id: u64, pending: &PendingStory) -> anyhow::Result<()> {
+        let value = serde_json::to_vec(pending)?;
+        let url_key = url_key(&pending.url, PENDING, &id.to_be_bytes());
+        let trees: (&sled::Tree, &sled::Tree) = (&self.pending, &self.by_url);
+        trees
+            .transaction(|(pending, by_url)| {
+                pending.insert(&id.to_be_bytes(), value.as_slice())?;
+                by_url.insert(url_key.as_slice(), &[])?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot record the story: {}", e))?;
+        self.pending.flush_async().await?;
+        Ok(())
+    }
+
+    pub async fn remove_pending(&mut self, id: u64) -> anyhow::Result<Option<PendingStory>> {
+        let removed = match self.pending(id)? {
+            Some(removed) => removed,
+            None => return Ok(None),
+        };
+        let url_key = url_key(&removed.url, PENDING, &id.to_be_bytes());
+        let trees: (&sled::Tree, &sled::Tree) = (&self.pending, &self.by_url);
+        trees
+            .transaction(|(pending, by_url)| {
+                pending.remove(&id.to_be_bytes())?;
+                by_url.remove(url_key.as_slice())?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot remove the story: {}", e))?;
+        self.pending.flush_async().await?;
+        Ok(Some(removed))
+    }
+
+    pub fn pending(&self, id: u64) -> anyhow::Result<Option<PendingStory>> {
+        self.pending
+            .get(id.to_be_bytes())?
+            .map(|pending| Ok(serde_json::from_slice(&pending)?))
+            .transpose()
+    }
+
+    /// Lists the stories waiting for approval, oldest first.
+    pub fn pending_stories(&self) -> anyhow::Result<Vec<(u64, PendingStory)>> {
+        self.pending
+            .iter()
+            .map(|entry| {
+                let (id, pending) = entry?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&id);
+                Ok((u64::from_be_bytes(bytes), serde_json::from_slice(&pending)?))
+            })
+            .collect()
+    }
+
+    /// Records the webhook delivery before it's attempted, returns its id.
+    pub async fn add_delivery(&mut self, delivery: &Delivery) -> anyhow::Result<u64> {
+        let id = self.stories.generate_id()?;
+        self.deliveries
+            .insert(id.to_be_bytes(), serde_json::to_vec(delivery)?)?;
+        self.deliveries.flush_async().await?;
+        Ok(id)
+    }
+
+    /// Lists the webhook deliveries not delivered or given up yet, oldest first.
+    pub fn deliveries(&self) -> anyhow::Result<Vec<(u64, Delivery)>> {
+        self.deliveries
+            .iter()
+            .map(|entry| {
+                let (id, delivery) = entry?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&id);
+                Ok((
+                    u64::from_be_bytes(bytes),
+                    serde_json::from_slice(&delivery)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Removes the delivered webhook delivery along with the dead letter it delivered again,
+    /// at once.
+    pub async fn delivered(&mut self, id: u64, delivery: &Delivery) -> anyhow::Result<()> {
+        let trees: (&sled::Tree, &sled::Tree) = (&self.deliveries, &self.dead_letters);
+        trees
+            .transaction(|(deliveries, dead_letters)| {
+                deliveries.remove(&id.to_be_bytes())?;
+                if let Some(dead_letter) = delivery.dead_letter {
+                    dead_letters.remove(&dead_letter.to_be_bytes())?;
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot record the delivery: {}", e))?;
+        self.deliveries.flush_async().await?;
+        Ok(())
+    }
+
+    /// Replaces the webhook delivery given up with its dead letter, at once. The dead letter
+    /// the delivery delivered again is replaced, so it keeps its id.
+    pub async fn delivery_failed(
+        &mut self,
+        id: u64,
+        delivery: &Delivery,
+        dead_letter: &DeadLetter,
+    ) -> anyhow::Result<()> {
+        let dead_letter_id = match delivery.dead_letter {
+            Some(dead_letter_id) => dead_letter_id,
+            None => self.stories.generate_id()?,
+        };
+        let dead_letter = serde_json::to_vec(dead_letter)?;
+        let trees: (&sled::Tree, &sled::Tree) = (&self.deliveries, &self.dead_letters);
+        trees
+            .transaction(|(deliveries, dead_letters)| {
+                deliveries.remove(&id.to_be_bytes())?;
+                dead_letters.insert(&dead_letter_id.to_be_bytes(), dead_letter.as_slice())?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| {
+                anyhow::anyhow!("cannot record the dead letter: {}", e)
+            })?;
+        self.deliveries.flush_async().await?;
+        Ok(())
+    }
+
+    /// Lists the failed webhook deliveries, oldest first.
+    pub fn dead_letters(&self) -> anyhow::Result<Vec<(u64, DeadLetter)>> {
+        self.dead_letters
+            .iter()
+            .map(|entry| {
+                let (id, dead_letter) = entry?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&id);
+                Ok((
+                    u64::from_be_bytes(bytes),
+                    serde_json::from_slice(&dead_letter)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn is_pending(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(!self.keys_by_url(url, PENDING)?.is_empty())
+    }
+
+    /// Keys of the entries of the kind with the url in their trees, see [`url_key`].
+    fn keys_by_url(&self, url: &str, kind: u8) -> anyhow::Result<Vec<Vec<u8>>> {
+        let prefix = url_key(url, kind, &[]);
+        self.by_url
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| Ok(key?[prefix.len()..].to_vec()))
+            .collect()
+    }
+
+    /// Queues the post, the story is no longer published to all its destinations
+    /// until the post is sent.
+    pub async fn add_to_outbox(&mut self, item: &OutboxItem) -> anyhow::Result<u64> {
+        if let Some(url) = item.subject.url() {
+            if let Some(mut publication) = self.publication(url)? {
+                publication.partial = true;
+                self.stories
+                    .insert(url, serde_json::to_vec(&publication)?)?;
+            }
+        }
+        let id = self.stories.generate_id()?;
+        self.update_outbox(id, item).await?;
+        Ok(id)
+    }
+
+    pub async fn update_outbox(&mut self, id: u64, item: &OutboxItem) -> anyhow::Result<()> {
+        let value = serde_json::to_vec(item)?;
+        let url_keys = item
+            .subject
+            .urls()
+            .into_iter()
+            .map(|url| url_key(url, QUEUED, &id.to_be_bytes()))
+            .collect::<Vec<_>>();
+        let trees: (&sled::Tree, &sled::Tree) = (&self.outbox, &self.by_url);
+        trees
+            .transaction(|(outbox, by_url)| {
+                outbox.insert(&id.to_be_bytes(), value.as_slice())?;
+                for key in &url_keys {
+                    by_url.insert(key.as_slice(), &[])?;
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot record the post: {}", e))?;
+        self.outbox.flush_async().await?;
+        Ok(())
+    }
+
+    pub async fn remove_from_outbox(&mut self, id: u64) -> anyhow::Result<Option<OutboxItem>> {
+        let item = match self.outbox_item(id)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let url_keys = item
+            .subject
+            .urls()
+            .into_iter()
+            .map(|url| url_key(url, QUEUED, &id.to_be_bytes()))
+            .collect::<Vec<_>>();
+        let trees: (&sled::Tree, &sled::Tree) = (&self.outbox, &self.by_url);
+        trees
+            .transaction(|(outbox, by_url)| {
+                outbox.remove(&id.to_be_bytes())?;
+                for key in &url_keys {
+                    by_url.remove(key.as_slice())?;
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot remove the post: {}", e))?;
+        self.outbox.flush_async().await?;
+        Ok(Some(item))
+    }
+
+    pub fn outbox_item(&self, id: u64) -> anyhow::Result<Option<OutboxItem>> {
+        self.outbox
+            .get(id.to_be_bytes())?
+            .map(|item| Ok(serde_json::from_slice(&item)?))
+            .transpose()
+    }
+
+    /// Lists the posts in the outbox, including the failed ones, oldest first.
+    pub fn outbox(&self) -> anyhow::Result<Vec<(u64, OutboxItem)>> {
+        self.outbox
+            .iter()
+            .map(|entry| {
+                let (id, item) = entry?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&id);
+                Ok((u64::from_be_bytes(bytes), serde_json::from_slice(&item)?))
+            })
+            .collect()
+    }
+
+    /// Whether a post of the story, alone or in a digest, is in the outbox,
+    /// still to be sent or failed.
+    pub fn is_queued(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(!self.keys_by_url(url, QUEUED)?.is_empty())
+    }
+
+    /// Whether the story is collected for the digest of any destination.
+    pub fn is_collected(&self, url: &str) -> anyhow::Result<bool> {
+        Ok(!self.keys_by_url(url, COLLECTED)?.is_empty())
+    }
+
+    /// Collects the story for the digest of the destination, replacing the story
+    /// collected before under the same url.
+    ///
+    /// A story recorded for the first time is [`Publication::partial`]
+    /// until [`State::set_published`] is called.
+    pub async fn add_to_digest(
+        &mut self,
+        destination: &str,
+        url: &str,
+        story: &Story,
+    ) -> anyhow::Result<()> {
+        if self.publication(url)?.is_none() {
+            let publication = self.published_or_new(url, story)?;
+            self.stories
+                .insert(url, serde_json::to_vec(&publication)?)?;
+        }
+        let replaced = self
+            .digest(destination)?
+            .into_iter()
+            .filter(|(_, entry)| entry.url == url)
+            .map(|(id, _)| digest_key(destination, id))
+            .collect::<Vec<_>>();
+        let entry = DigestEntry {
+            url: url.to_owned(),
+            story: story.clone(),
+            collected_at: Utc::now(),
+        };
+        let value = serde_json::to_vec(&entry)?;
+        let key = digest_key(destination, self.stories.generate_id()?);
+
+        let trees: (&sled::Tree, &sled::Tree) = (&self.digests, &self.by_url);
+        trees
+            .transaction(|(digests, by_url)| {
+                for key in &replaced {
+                    digests.remove(key.as_slice())?;
+                    by_url.remove(url_key(url, COLLECTED, key))?;
+                }
+                digests.insert(key.as_slice(), value.as_slice())?;
+                by_url.insert(url_key(url, COLLECTED, &key), &[])?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot collect the story: {}", e))?;
+        self.digests.flush_async().await?;
+        Ok(())
+    }
+
+    /// Lists the stories collected for the digest of the destination, oldest first.
+    pub fn digest(&self, destination: &str) -> anyhow::Result<Vec<(u64, DigestEntry)>> {
+        let prefix = digest_prefix(destination);
+        self.digests
+            .scan_prefix(&prefix)
+            .map(|entry| {
+                let (key, entry) = entry?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&key[prefix.len()..]);
+                Ok((u64::from_be_bytes(bytes), serde_json::from_slice(&entry)?))
+            })
+            .collect()
+    }
+
+    pub fn digest_sent_at(&self, destination: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+        self.meta
+            .get(format!("digest_sent_at:{}", destination))?
+            .map(|at| Ok(std::str::from_utf8(&at)?.parse()?))
+            .transpose()
+    }
+
+    /// Records the digest of the destination as sent at the time: queues its post, if there's
+    /// one, and removes the stories from the digest, at once. Returns the id of the post.
+    ///
+    /// The post is added to the stories once it's sent, see [`State::confirm_post`].
+    pub async fn digest_sent(
+        &mut self,
+        destination: &str,
+        entries: &[(u64, DigestEntry)],
+        post: Option<&OutboxItem>,
+        at: DateTime<Utc>,
+    ) -> anyhow::Result<Option<u64>> {
+        let post = post
+            .map(|item| -> anyhow::Result<_> {
+                Ok((
+                    self.stories.generate_id()?,
+                    serde_json::to_vec(item)?,
+                    item.subject.urls(),
+                ))
+            })
+            .transpose()?;
+
+        let trees: (&sled::Tree, &sled::Tree, &sled::Tree, &sled::Tree) =
+            (&self.outbox, &self.digests, &self.meta, &self.by_url);
+        trees
+            .transaction(|(outbox, digests, meta, by_url)| {
+                if let Some((id, item, urls)) = &post {
+                    outbox.insert(&id.to_be_bytes(), item.as_slice())?;
+                    for url in urls {
+                        by_url.insert(url_key(url, QUEUED, &id.to_be_bytes()), &[])?;
+                    }
+                }
+                for (id, entry) in entries {
+                    let key = digest_key(destination, *id);
+                    digests.remove(key.as_slice())?;
+                    by_url.remove(url_key(&entry.url, COLLECTED, &key))?;
+                }
+                meta.insert(
+                    format!("digest_sent_at:{}", destination).as_bytes(),
+                    at.to_rfc3339().as_bytes(),
+                )?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("cannot record the digest: {}", e))?;
+        self.outbox.flush_async().await?;
+        Ok(post.map(|(id, ..)| id))
+    }
+}
+
+/// Opens the sled database at the path, which only one process can have open at a time.
+///
+/// The commands of the command line open the databases of the bot, so they fail while
+/// the bot is running; the error says so instead of sled's.
+pub fn open_db(path: &Path) -> anyhow::Result<sled::Db> {
+    match sled::open(path) {
+        Ok(db) => Ok(db),
+        Err(sled::Error::Io(e)) if e.to_string().starts_with("could not acquire lock") => {
+            anyhow::bail!(
+                "{} is in use by another process, stop the running bot before using the command line",
+                path.display()
+            )
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Kinds of the entries of the url index: pending stories, posts in the outbox
+/// and digest entries.
+const PENDING: u8 = b'p';
+const QUEUED: u8 = b'o';
+const COLLECTED: u8 = b'd';
+
+/// Key of the url index: the url, a zero byte, the kind of the entry and its key in its tree,
+/// so the entries of a url are adjacent and grouped by kind.
+fn url_key(url: &str, kind: u8, key: &[u8]) -> Vec<u8> {
+    let mut url_key = url.as_bytes().to_vec();
+    url_key.push(0);
+    url_key.push(kind);
+    url_key.extend_from_slice(key);
+    url_key
+}
+
+/// Key of a digest entry: the destination name, a zero byte, and the id, so the entries
+/// of a destination are adjacent and ordered by id.
+fn digest_key(destination: &str, id: u64) -> Vec<u8> {
+    let mut key = digest_prefix(destination);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn digest_prefix(destination: &str) -> Vec<u8> {
+    let mut prefix = destination.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// Length of the keys of the messages tree before the destinations were introduced:
+/// the message id alone, the messages were all in the Telegram channel.
+const LEGACY_MESSAGE_KEY_LEN: usize = 4;
+
+/// The message ids in the legacy field of the recorded publication, if it has any.
+fn legacy_messages(value: &[u8]) -> Option<Vec<i32>> {
+    serde_json::from_slice::<LegacyPublication>(value)
+        .ok()
+        .map(|legacy| legacy.messages)
+        .filter(|messages| !messages.is_empty())
+}
+
+fn message_key(chat_id: i64, message_id: i32) -> [u8; 12] {
+    let mut key = [0; 12];
+    key[..8].copy_from_slice(&chat_id.to_be_bytes());
+    key[8..].copy_from_slice(&message_id.to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, STORY_URL};
+    use crate::tg_bot::ReplyTo;
+    use pretty_assertions::assert_eq;
+
+    fn post() -> Post {
+        Post {
+            messages: vec!["post".to_owned()],
+            keyboard: None,
+            reply_to: ReplyTo::Previous,
+            silent: false,
+        }
+    }
+
+    fn story_subject(story: &Story) -> Subject {
+        Subject::Story {
+            url: STORY_URL.to_owned(),
+            story: story.clone(),
+        }
+    }
+
+    fn queued_post(story: &Story) -> OutboxItem {
+        OutboxItem::new(story_subject(story), "channel", post())
+    }
+
+    #[test]
+    fn locked_db_is_reported() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("astg-locked-{}", std::process::id()));
+        let _db = open_db(&path)?;
+        let e = open_db(&path).unwrap_err();
+        assert!(e.to_string().contains("stop the running bot"));
+        std::fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn confirmed_post_leaves_outbox() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let mut state = State::try_new_temporary()?;
+        state.set_published(STORY_URL, &story).await?;
+
+        let id = state.add_to_outbox(&queued_post(&story)).await?;
+        assert!(!state.is_published(STORY_URL)?);
+        assert!(state.is_queued(STORY_URL)?);
+
+        let post = PublishedPost {
+            destination: "channel".to_owned(),
+            receipt: Receipt::Telegram {
+                chat_id: -100,
+                messages: vec![7, 8],
+            },
+        };
+        state
+            .confirm_post(id, &story_subject(&story), post.clone())
+            .await?;
+        assert!(state.outbox()?.is_empty());
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(publication.posts, vec![post]);
+        assert!(state.publication_by_message(-100, 8)?.is_some());
+
+        state.set_published(STORY_URL, &story).await?;
+        assert!(state.is_published(STORY_URL)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forgotten_story_leaves_messages() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let mut state = State::try_new_temporary()?;
+        state
+            .set_skipped("https://www.allsides.com/story/skipped")
+            .await?;
+        let id = state.add_to_outbox(&queued_post(&story)).await?;
+        let published = PublishedPost {
+            destination: "channel".to_owned(),
+            receipt: Receipt::Telegram {
+                chat_id: -100,
+                messages: vec![7],
+            },
+        };
+        state
+            .confirm_post(id, &story_subject(&story), published)
+            .await?;
+        assert_eq!(state.published_count()?, 0);
+        state.set_published(STORY_URL, &story).await?;
+        assert_eq!(state.published_count()?, 1);
+
+        state.add_to_outbox(&queued_post(&story)).await?;
+        state.add_to_digest("digest", STORY_URL, &story).await?;
+        state
+            .add_pending(&PendingStory {
+                url: STORY_URL.to_owned(),
+                story: story.clone(),
+                destination: "channel".to_owned(),
+                post: post(),
+                queued_at: Utc::now(),
+                edit_prompt: None,
+                edited: false,
+            })
+            .await?;
+
+        assert!(state.forget(STORY_URL).await?);
+        assert!(state.publication_by_message(-100, 7)?.is_none());
+        assert!(state.messages.is_empty());
+        assert_eq!(state.published_count()?, 0);
+        assert!(!state.is_queued(STORY_URL)?);
+        assert!(!state.is_pending(STORY_URL)?);
+        assert!(state.digest("digest")?.is_empty());
+        assert!(!state.forget(STORY_URL).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn legacy_messages_are_migrated() -> anyhow::Result<()> {
+        let mut state = State::try_new_temporary()?;
+        let mut legacy = serde_json::to_value(fixtures::publication(Utc::now()))?;
+        legacy["messages"] = serde_json::json!([7, 8]);
+        state
+            .stories
+            .insert(STORY_URL, serde_json::to_vec(&legacy)?)?;
+        state.messages.insert(7i32.to_be_bytes(), STORY_URL)?;
+        state.messages.insert(8i32.to_be_bytes(), STORY_URL)?;
+        state
+            .set_skipped("https://www.allsides.com/story/skipped")
+            .await?;
+        assert!(state.has_legacy_messages()?);
+
+        assert_eq!(state.migrate_messages("channel", -100).await?, 1);
+        assert!(!state.has_legacy_messages()?);
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(
+            publication.posts,
+            vec![PublishedPost {
+                destination: "channel".to_owned(),
+                receipt: Receipt::Telegram {
+                    chat_id: -100,
+                    messages: vec![7, 8],
+                },
+            }]
+        );
+        assert!(state.publication_by_message(-100, 8)?.is_some());
+        assert_eq!(state.messages.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sent_digest_is_recorded() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let mut state = State::try_new_temporary()?;
+
+        state.add_to_digest("daily", STORY_URL, &story).await?;
+        state.add_to_digest("daily", STORY_URL, &story).await?;
+        assert!(state.digest("other")?.is_empty());
+        let entries = state.digest("daily")?;
+        assert_eq!(entries.len(), 1);
+        assert!(state.publication(STORY_URL)?.unwrap().partial);
+        assert!(state.is_collected(STORY_URL)?);
+        assert!(!state.is_queued(STORY_URL)?);
+
+        let subject = Subject::Digest {
+            urls: vec![STORY_URL.to_owned()],
+        };
+        let item = OutboxItem::new(subject.clone(), "daily", post());
+        let sent_at = Utc::now();
+        let id = state
+            .digest_sent("daily", &entries, Some(&item), sent_at)
+            .await?
+            .unwrap();
+        assert!(state.digest("daily")?.is_empty());
+        assert_eq!(state.digest_sent_at("daily")?, Some(sent_at));
+        assert!(state.outbox_item(id)?.is_some());
+        // The stories wait for the digest post
+        assert!(!state.is_collected(STORY_URL)?);
+        assert!(state.is_queued(STORY_URL)?);
+
+        let post = PublishedPost {
+            destination: "daily".to_owned(),
+            receipt: Receipt::Telegram {
+                chat_id: -100,
+                messages: vec![9],
+            },
+        };
+        state.confirm_post(id, &subject, post.clone()).await?;
+        assert!(state.outbox()?.is_empty());
+        assert!(!state.is_queued(STORY_URL)?);
+        let publication = state.publication(STORY_URL)?.unwrap();
+        assert_eq!(publication.posts, vec![post]);
+        assert!(publication.partial);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn urls_are_indexed_in_older_databases() -> anyhow::Result<()> {
+        let story = fixtures::story();
+        let mut state = State::try_new_temporary()?;
+        state.add_to_outbox(&queued_post(&story)).await?;
+        state.add_to_digest("daily", STORY_URL, &story).await?;
+        state.by_url.clear()?;
+        state.meta.remove("by_url")?;
+        assert!(!state.is_queued(STORY_URL)?);
+
+        let state = State::from_db(state.stories.clone())?;
+        assert!(state.is_queued(STORY_URL)?);
+        assert!(state.is_collected(STORY_URL)?);
+        assert!(!state.is_pending(STORY_URL)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queued_stats_are_recorded() -> anyhow::Result<()> {
+        let mut state = State::try_new_temporary()?;
+        state.set_published(STORY_URL, &fixtures::story()).await?;
+
+        let sent_at = Utc::now();
+        let item = OutboxItem::new(Subject::Stats, "channel", post());
+        state.stats_sent(&[item], sent_at).await?;
+        assert_eq!(state.stats_sent_at()?, Some(sent_at));
+        let (id, item) = state.outbox()?.remove(0);
+
+        let post = PublishedPost {
+            destination: "channel".to_owned(),
+            receipt: Receipt::Telegram {
+                chat_id: -100,
+                messages: vec![10],
+            },
+        };
+        state.confirm_post(id, &item.subject, post).await?;
+        assert!(state.outbox()?.is_empty());
+        assert!(state.publication(STORY_URL)?.unwrap().posts.is_empty());
+        assert!(state.messages.is_empty());
+        Ok(())
+    }
 }
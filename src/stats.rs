@@ -0,0 +1,336 @@
+use crate::config::StatsOptions;
+use crate::render::{split_html, MESSAGE_LIMIT};
+use crate::schedule::Schedule;
+use crate::scraper::Side;
+use crate::state::Publication;
+use chrono::{DateTime, Duration, Utc};
+use handlebars::Handlebars;
+use itertools::Itertools;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Sources listed per side.
+const TOP_SOURCES: usize = 3;
+/// Topics listed.
+const TOP_TOPICS: usize = 5;
+
+/// Coverage statistics of the stories published during a week.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeeklyStats {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub stories: usize,
+    /// A per side, in the order of [`Side::ALL`]
+    pub sides: Vec<SideStats>,
+    /// The topics with the most stories, with their numbers of stories
+    pub topics: Vec<(String, usize)>,
+    pub one_sided: Vec<OneSidedStory>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SideStats {
+    pub side: Side,
+    pub articles: usize,
+    /// The most cited sources, with their numbers of articles
+    pub sources: Vec<(String, usize)>,
+}
+
+/// A story without articles from the left, the center or the right.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OneSidedStory {
+    pub url: String,
+    pub title: String,
+    /// [`Side::Left`], [`Side::Center`] or [`Side::Right`], each standing for its leaning
+    pub missing: Vec<Side>,
+}
+
+impl WeeklyStats {
+    /// Counts the publications completed during the week ending at `end`.
+    ///
+    /// A story still partial at `end` is counted in the week it's completed in.
+    pub fn of(publications: &[(String, Publication)], end: DateTime<Utc>) -> Self {
+        let start = end - Duration::weeks(1);
+        let publications = publications
+            .iter()
+            .filter(|(_, publication)| {
+                publication
+                    .completed()
+                    .is_some_and(|completed| start < completed && completed <= end)
+            })
+            .sorted_by_key(|(_, publication)| publication.completed())
+            .collect::<Vec<_>>();
+
+        let sides = Side::ALL
+            .iter()
+            .map(|&side| {
+                let articles = publications
+                    .iter()
+                    .flat_map(|(_, publication)| &publication.story.articles)
+                    .filter(|article| article.side == side)
+                    .collect::<Vec<_>>();
+                let mut sources: HashMap<&str, usize> = HashMap::new();
+                for article in &articles {
+                    *sources.entry(article.source.trim()).or_default() += 1;
+                }
+                SideStats {
+                    side,
+                    articles: articles.len(),
+                    sources: top(sources, TOP_SOURCES),
+                }
+            })
+            .collect();
+
+        let mut topics: HashMap<&str, usize> = HashMap::new();
+        for (_, publication) in &publications {
+            for topic in publication.story.topics.iter().unique() {
+                *topics.entry(topic.as_str()).or_default() += 1;
+            }
+        }
+
+        let one_sided = publications
+            .iter()
+            .filter_map(|(url, publication)| {
+                let story = &publication.story;
+                let missing = [Side::Left, Side::Center, Side::Right]
+                    .iter()
+                    .copied()
+                    .filter(|&leaning| {
+                        !story
+                            .articles
+                            .iter()
+                            .any(|article| leaning_of(article.side) == leaning)
+                    })
+                    .collect::<Vec<_>>();
+                if missing.is_empty() {
+                    return None;
+                }
+                Some(OneSidedStory {
+                    url: url.clone(),
+                    title: story.title.clone(),
+                    missing,
+                })
+            })
+            .collect();
+
+        WeeklyStats {
+            start,
+            end,
+            stories: publications.len(),
+            sides,
+            topics: top(topics, TOP_TOPICS),
+            one_sided,
+        }
+    }
+}
+
+/// Maps the side to [`Side::Left`], [`Side::Center`] or [`Side::Right`].
+fn leaning_of(side: Side) -> Side {
+    match side {
+        Side::Left | Side::CenterLeft => Side::Left,
+        Side::Center => Side::Center,
+        Side::CenterRight | Side::Right => Side::Right,
+    }
+}
+
+/// Returns the `n` names with the highest counts, the ties ordered by name.
+fn top(counts: HashMap<&str, usize>, n: usize) -> Vec<(String, usize)> {
+    counts
+        .into_iter()
+        .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)))
+        .take(n)
+        .map(|(name, count)| (name.to_owned(), count))
+        .collect()
+}
+
+/// Renders the weekly statistics into the messages of the post.
+pub struct StatsPost {
+    template: Handlebars<'static>,
+}
+
+impl StatsPost {
+    pub fn try_new(opts: &StatsOptions) -> anyhow::Result<Self> {
+        let mut template = Handlebars::new();
+        match &opts.template {
+            Some(path) => template.register_template_file("stats", path)?,
+            None => template.register_template_string(
+                "stats",
+                include_str!("../data/weekly-stats-template.handlebars"),
+            )?,
+        }
+        Ok(StatsPost { template })
+    }
+
+    /// Renders the statistics into one or more messages, each fitting into [`MESSAGE_LIMIT`],
+    /// dating the week in the timezone of the schedule.
+    pub fn format(&self, stats: &WeeklyStats, schedule: &Schedule) -> anyhow::Result<Vec<String>> {
+        let rendered = self
+            .template
+            .render("stats", &stats_data(stats, schedule))?;
+        Ok(split_html(rendered.trim(), MESSAGE_LIMIT))
+    }
+}
+
+fn stats_data(stats: &WeeklyStats, schedule: &Schedule) -> Value {
+    let sides = stats
+        .sides
+        .iter()
+        .map(|side| {
+            let sources = side
+                .sources
+                .iter()
+                .map(|(name, articles)| json!({ "source_name": name, "source_articles": articles }))
+                .collect::<Vec<_>>();
+            json!({
+                "side_emoji": side.side.emoji(),
+                "side_label": side.side.label(),
+                "side_articles": side.articles,
+                "side_sources": sources,
+            })
+        })
+        .collect::<Vec<_>>();
+    let topics = stats
+        .topics
+        .iter()
+        .map(|(name, stories)| json!({ "topic_name": name, "topic_stories": stories }))
+        .collect::<Vec<_>>();
+    let one_sided = stats
+        .one_sided
+        .iter()
+        .map(|story| {
+            let missing = story
+                .missing
+                .iter()
+                .map(|side| format!("{} {}", side.emoji(), side.label()))
+                .join(", ");
+            json!({
+                "story_title": story.title,
+                "story_url": story.url,
+                "missing_sides": missing,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "week_start": schedule.local_date(stats.start).to_string(),
+        "week_end": schedule.local_date(stats.end).to_string(),
+        "stories": stats.stories,
+        "articles": stats.sides.iter().map(|side| side.articles).sum::<usize>(),
+        "sides": sides,
+        "topics": topics,
+        "one_sided": one_sided,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScheduleOptions;
+    use crate::fixtures;
+    use chrono::{NaiveTime, TimeZone, Weekday};
+    use pretty_assertions::assert_eq;
+
+    fn publication(published_at: DateTime<Utc>, partial: bool) -> Publication {
+        Publication {
+            partial,
+            ..fixtures::publication(published_at)
+        }
+    }
+
+    #[test]
+    fn weekly_coverage() {
+        let end = Utc.ymd(2020, 12, 20).and_hms(18, 0, 0);
+        let mut balanced = publication(end - Duration::days(2), false);
+        balanced.story.articles[1].side = Side::Center;
+        balanced.story.topics.push("Media Bias".to_owned());
+        let publications = vec![
+            ("https://www.allsides.com/story/one".to_owned(), balanced),
+            (
+                "https://www.allsides.com/story/two".to_owned(),
+                publication(end - Duration::days(1), false),
+            ),
+            (
+                "https://www.allsides.com/story/old".to_owned(),
+                publication(end - Duration::days(8), false),
+            ),
+            (
+                "https://www.allsides.com/story/partial".to_owned(),
+                publication(end - Duration::days(1), true),
+            ),
+            (
+                "https://www.allsides.com/story/completed-later".to_owned(),
+                Publication {
+                    completed_at: Some(end + Duration::hours(1)),
+                    ..publication(end - Duration::days(2), false)
+                },
+            ),
+        ];
+
+        // The story completed after the end of the week is counted the next week
+        assert_eq!(
+            WeeklyStats::of(&publications, end + Duration::weeks(1)).stories,
+            1
+        );
+        let stats = WeeklyStats::of(&publications, end);
+        assert_eq!(stats.stories, 2);
+        let articles = stats
+            .sides
+            .iter()
+            .map(|side| (side.side, side.articles))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            articles,
+            vec![
+                (Side::Left, 2),
+                (Side::CenterLeft, 0),
+                (Side::Center, 1),
+                (Side::CenterRight, 1),
+                (Side::Right, 2),
+            ]
+        );
+        assert_eq!(stats.sides[0].sources, vec![("Vox".to_owned(), 2)]);
+        assert_eq!(
+            stats.topics,
+            vec![
+                ("Sexual Misconduct".to_owned(), 2),
+                ("Media Bias".to_owned(), 1)
+            ]
+        );
+        assert_eq!(
+            stats.one_sided,
+            vec![OneSidedStory {
+                url: "https://www.allsides.com/story/two".to_owned(),
+                title: publications[1].1.story.title.clone(),
+                missing: vec![Side::Center],
+            }]
+        );
+
+        let opts = StatsOptions {
+            destinations: vec!["full".to_owned()],
+            weekday: Weekday::Sun,
+            at: NaiveTime::from_hms(18, 0, 0),
+            template: None,
+        };
+        let messages = StatsPost::try_new(&opts)
+            .unwrap()
+            .format(
+                &stats,
+                &Schedule::new(&ScheduleOptions {
+                    timezone: chrono_tz::Europe::Berlin,
+                    quiet_hours: None,
+                    silent_hours: None,
+                    spacing: 0,
+                }),
+            )
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0];
+        assert!(message.starts_with(
+            "<b>AllSides week in review, 2020-12-13 – 2020-12-20</b>\n2 stories, 6 articles\n"
+        ));
+        assert!(message.contains("\n🟦 Left: 2 — Vox (2)\n🔵 Lean Left: 0\n"));
+        assert!(message.contains("\nSexual Misconduct: 2\nMedia Bias: 1\n"));
+        assert!(message.contains(
+            "<a href=\"https://www.allsides.com/story/two\">NY Gov. Cuomo Accused of Sexual \
+             Harrassment; Less Coverage from Left-Rated Outlets</a> — no 🟣 Center"
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::config::TelegraphOptions;
+use crate::scraper::{Inline, Paragraph, Story};
+use anyhow::{anyhow, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Telegraph document node, see https://telegra.ph/api#Node
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Node {
+    Text(String),
+    Element {
+        tag: &'static str,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        attrs: BTreeMap<&'static str, String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        children: Vec<Node>,
+    },
+}
+
+impl Node {
+    fn element(tag: &'static str, children: Vec<Node>) -> Self {
+        Node::Element {
+            tag,
+            attrs: BTreeMap::new(),
+            children,
+        }
+    }
+
+    fn link(url: &str, children: Vec<Node>) -> Self {
+        let mut attrs = BTreeMap::new();
+        attrs.insert("href", url.to_owned());
+        Node::Element {
+            tag: "a",
+            attrs,
+            children,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Response<T> {
+    ok: bool,
+    result: Option<T>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct Page {
+    url: String,
+}
+
+/// Client of the Telegraph API, which hosts the full stories too long for a channel post.
+pub struct Telegraph {
+    client: reqwest::Client,
+    base_url: String,
+    author: Option<String>,
+}
+
+impl Telegraph {
+    pub fn new(opts: &TelegraphOptions) -> Self {
+        Telegraph {
+            client: reqwest::Client::new(),
+            base_url: opts.url.trim_end_matches('/').to_owned(),
+            author: opts.author.clone(),
+        }
+    }
+
+    /// Creates a new account, returns its access token.
+    pub async fn create_account(&self, short_name: &str) -> anyhow::Result<String> {
+        let mut params = vec![("short_name", short_name.to_owned())];
+        if let Some(author) = &self.author {
+            params.push(("author_name", author.clone()));
+        }
+        let account: Account = self.call("createAccount", &params).await?;
+        Ok(account.access_token)
+    }
+
+    /// Creates a page with the full story, returns the page url.
+    pub async fn create_page(
+        &self,
+        token: &str,
+        story: &Story,
+        url: &str,
+    ) -> anyhow::Result<String> {
+        let mut params = vec![
+            ("access_token", token.to_owned()),
+            ("title", story.title.clone()),
+            ("content", serde_json::to_string(&story_nodes(story, url))?),
+        ];
+        if let Some(author) = &self.author {
+            params.push(("author_name", author.clone()));
+        }
+        let page: Page = self.call("createPage", &params).await?;
+        Ok(page.url)
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[(&str, String)],
+    ) -> anyhow::Result<T> {
+        let response: Response<T> = self
+            .client
+            .post(&format!("{}/{}", self.base_url, method))
+            .form(params)
+            .send()
+            .await?
+            .json()
+            .await
+            .with_context(|| format!("unexpected telegraph {} response", method))?;
+
+        match response {
+            Response {
+                ok: true,
+                result: Some(result),
+                ..
+            } => Ok(result),
+            Response { error, .. } => Err(anyhow!(
+                "telegraph {} failed: {}",
+                method,
+                error.unwrap_or_else(|| "unknown error".to_owned())
+            )),
+        }
+    }
+}
+
+/// Renders the full story with every side's article as Telegraph nodes.
+pub fn story_nodes(story: &Story, url: &str) -> Vec<Node> {
+    let mut nodes: Vec<Node> = story.summary.iter().map(paragraph_node).collect();
+    nodes.push(Node::element(
+        "p",
+        vec![Node::link(
+            url,
+            vec![Node::Text("Read on AllSides".to_owned())],
+        )],
+    ));
+
+    for article in &story.articles {
+        nodes.push(Node::element("hr", Vec::new()));
+        nodes.push(Node::element(
+            "h4",
+            vec![Node::Text(format!(
+                "{} {}",
+                article.side.emoji(),
+                article.title
+            ))],
+        ));
+        nodes.push(Node::element(
+            "p",
+            vec![Node::link(
+                &article.url,
+                vec![Node::Text(article.source.clone())],
+            )],
+        ));
+        nodes.extend(article.summary.iter().map(paragraph_node));
+    }
+    nodes
+}
+
+fn paragraph_node(paragraph: &Paragraph) -> Node {
+    Node::element("p", paragraph.0.iter().map(inline_node).collect())
+}
+
+fn inline_node(inline: &Inline) -> Node {
+    let children = |content: &[Inline]| content.iter().map(inline_node).collect();
+    match inline {
+        Inline::Text(text) => Node::Text(text.clone()),
+        Inline::Link { url, content } => Node::link(url, children(content)),
+        Inline::Bold(content) => Node::element("b", children(content)),
+        Inline::Italic(content) => Node::element("i", children(content)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::mock_server::MockServer;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn create_page() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(
+            200,
+            r#"{"ok":true,"result":{"path":"NY-Gov-12-15","url":"https://telegra.ph/NY-Gov-12-15"}}"#,
+        )]);
+        let telegraph = Telegraph::new(&TelegraphOptions {
+            url: server.url.clone(),
+            author: Some("AllSides".to_owned()),
+        });
+
+        let url = telegraph
+            .create_page(
+                "token",
+                &fixtures::story(),
+                "https://www.allsides.com/story/test",
+            )
+            .await?;
+        assert_eq!(url, "https://telegra.ph/NY-Gov-12-15");
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/createPage");
+        assert_eq!(
+            requests[0].header("content-type"),
+            Some("application/x-www-form-urlencoded")
+        );
+        assert!(requests[0].body.contains("access_token=token"));
+        assert!(requests[0].body.contains("author_name=AllSides"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn api_error() {
+        let server =
+            MockServer::start(vec![(200, r#"{"ok":false,"error":"SHORT_NAME_REQUIRED"}"#)]);
+        let telegraph = Telegraph::new(&TelegraphOptions {
+            url: server.url.clone(),
+            author: None,
+        });
+        let err = telegraph.create_account("").await.unwrap_err();
+        assert!(err.to_string().contains("SHORT_NAME_REQUIRED"));
+    }
+
+    #[test]
+    fn inline_nodes() -> anyhow::Result<()> {
+        let node = inline_node(&Inline::Link {
+            url: "https://example.com".to_owned(),
+            content: vec![Inline::Bold(vec![Inline::Text("bold".to_owned())])],
+        });
+        assert_eq!(
+            serde_json::to_string(&node)?,
+            r#"{"tag":"a","attrs":{"href":"https://example.com"},"children":[{"tag":"b","children":["bold"]}]}"#
+        );
+        Ok(())
+    }
+}
@@ -1,10 +1,63 @@
 use crate::config::TelegramOptions;
+use crate::publisher::{Progress, Publisher, Receipt};
+use crate::scraper::{Side, Story};
+use anyhow::bail;
+use async_trait::async_trait;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use teloxide::prelude::*;
-use teloxide::types::{ChatId, ParseMode};
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, ParseMode};
 
+/// Callback data of the buttons of compact posts, see [`sides_keyboard`].
+pub const EXPAND_CALLBACK_PREFIX: &str = "expand:";
+pub const COLLAPSE_CALLBACK: &str = "collapse";
+
+/// Callback data of the buttons of approval previews, followed by the pending story id.
+pub const APPROVE_CALLBACK_PREFIX: &str = "approve:";
+pub const REJECT_CALLBACK_PREFIX: &str = "reject:";
+pub const EDIT_CALLBACK_PREFIX: &str = "edit:";
+
+/// Which message of a thread the following messages reply to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplyTo {
+    /// Each message replies to the previous one, forming a chain
+    Previous,
+    /// Every message replies to the first one
+    First,
+}
+
+impl ReplyTo {
+    /// Returns the message the next message of the thread replies to, given the sent ones.
+    pub fn parent<T>(self, sent: &[T]) -> Option<&T> {
+        match self {
+            ReplyTo::Previous => sent.last(),
+            ReplyTo::First => sent.first(),
+        }
+    }
+}
+
+/// A rendered story, ready to be sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Post {
+    pub messages: Vec<String>,
+    /// Attached to the first message
+    pub keyboard: Option<InlineKeyboardMarkup>,
+    pub reply_to: ReplyTo,
+    /// Sent without a notification, where the platform supports it
+    #[serde(default)]
+    pub silent: bool,
+}
+
+/// Addresses a chat by its numeric id, or by its username otherwise.
+pub fn chat_id(chat: &str) -> ChatId {
+    chat.parse()
+        .map(ChatId::Id)
+        .unwrap_or_else(|_| ChatId::ChannelUsername(chat.to_owned()))
+}
+
+#[derive(Clone)]
 pub struct Bot {
     bot: teloxide::Bot,
-    channel_id: String,
     admin_id: String,
 }
 
@@ -16,30 +69,235 @@ impl Bot {
             .build();
         Ok(Bot {
             bot,
-            channel_id: opts.channel.clone(),
             admin_id: opts.admin.clone(),
         })
     }
 
+    /// Returns the underlying client, e.g. to listen for updates.
+    pub fn client(&self) -> teloxide::Bot {
+        self.bot.clone()
+    }
+
     pub async fn log_error(&self, err: impl std::fmt::Display) -> anyhow::Result<()> {
         self.bot
-            .send_message(
-                ChatId::ChannelUsername(self.admin_id.clone()),
-                err.to_string(),
-            )
+            .send_message(chat_id(&self.admin_id), err.to_string())
             .send()
             .await?;
         Ok(())
     }
 
-    pub async fn publish_message(&self, msg: impl std::fmt::Display) -> anyhow::Result<()> {
-        self.bot
-            .send_message(
-                ChatId::ChannelUsername(self.channel_id.clone()),
-                msg.to_string(),
-            )
-            .send()
-            .await?;
+    /// Looks up the numeric id of the chat, given by its username or numeric id.
+    pub async fn chat_id_of(&self, chat: &str) -> anyhow::Result<i64> {
+        match chat_id(chat) {
+            ChatId::Id(id) => Ok(id),
+            chat => Ok(self.bot.get_chat(chat).send().await?.id),
+        }
+    }
+
+    /// Returns the publisher posting to the chat, given by its username or numeric id.
+    pub fn chat(&self, chat: &str) -> TelegramChat {
+        TelegramChat {
+            bot: self.clone(),
+            chat: chat.to_owned(),
+        }
+    }
+
+    /// Sends the post to the admin for approval, with the approval buttons
+    /// attached to its first message instead of the post keyboard.
+    /// The Edit button is left out unless the post is `editable`.
+    pub async fn send_preview(
+        &self,
+        post: &Post,
+        pending_id: u64,
+        editable: bool,
+    ) -> anyhow::Result<()> {
+        self.send_thread(
+            chat_id(&self.admin_id),
+            post,
+            Some(approval_keyboard(pending_id, editable)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Sends the messages of the post as a thread, every message after the first one replying
+    /// to a previous one, returns the sent messages.
+    async fn send_thread(
+        &self,
+        chat: ChatId,
+        post: &Post,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<Vec<Message>> {
+        let mut sent: Vec<Message> = Vec::with_capacity(post.messages.len());
+        for text in &post.messages {
+            let parent = post.reply_to.parent(&sent).map(|message| message.id);
+            let message = self
+                .send_post_message(chat.clone(), post, text, parent, keyboard.clone())
+                .await?;
+            sent.push(message);
+        }
+        Ok(sent)
+    }
+
+    /// Sends a message of the post, replying to the parent message,
+    /// or with the keyboard attached if it's the first message of the thread.
+    async fn send_post_message(
+        &self,
+        chat: ChatId,
+        post: &Post,
+        text: &str,
+        parent: Option<i32>,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<Message> {
+        let mut request = self
+            .bot
+            .send_message(chat, text)
+            .disable_notification(post.silent);
+        match parent {
+            Some(parent) => request = request.reply_to_message_id(parent),
+            None => {
+                if let Some(keyboard) = keyboard {
+                    request = request.reply_markup(keyboard);
+                }
+            }
+        }
+        Ok(request.send().await?)
+    }
+}
+
+/// Publishes posts to a Telegram chat.
+pub struct TelegramChat {
+    bot: Bot,
+    chat: String,
+}
+
+#[async_trait]
+impl Publisher for TelegramChat {
+    async fn publish(
+        &self,
+        _story: Option<(&str, &Story)>,
+        post: &Post,
+        _key: &str,
+        progress: &mut dyn Progress,
+    ) -> anyhow::Result<Receipt> {
+        let chat = chat_id(&self.chat);
+        let (mut chat_id, mut messages) = match progress.published() {
+            Some(Receipt::Telegram { chat_id, messages }) => (*chat_id, messages.clone()),
+            Some(receipt) => bail!("not a telegram post: {:?}", receipt),
+            None => (0, Vec::new()),
+        };
+        // Every message is recorded as it's sent, so an interrupted thread is resumed
+        // after its last recorded message instead of being published again
+        for text in post.messages.iter().skip(messages.len()) {
+            let parent = post.reply_to.parent(&messages).copied();
+            let message = self
+                .bot
+                .send_post_message(chat.clone(), post, text, parent, post.keyboard.clone())
+                .await?;
+            chat_id = message.chat.id;
+            messages.push(message.id);
+            progress
+                .record(&Receipt::Telegram {
+                    chat_id,
+                    messages: messages.clone(),
+                })
+                .await?;
+        }
+        Ok(Receipt::Telegram { chat_id, messages })
+    }
+
+    /// Deletes the published messages, e.g. the whole thread of a story.
+    async fn delete(&self, receipt: &Receipt) -> anyhow::Result<()> {
+        let (chat_id, messages) = match receipt {
+            Receipt::Telegram { chat_id, messages } => (*chat_id, messages),
+            _ => bail!("not a telegram post: {:?}", receipt),
+        };
+        for &id in messages {
+            self.bot
+                .bot
+                .delete_message(ChatId::Id(chat_id), id)
+                .send()
+                .await?;
+        }
         Ok(())
     }
 }
+
+fn approval_keyboard(pending_id: u64, editable: bool) -> InlineKeyboardMarkup {
+    let button = |text: &str, prefix: &str| {
+        InlineKeyboardButton::callback(text.to_owned(), format!("{}{}", prefix, pending_id))
+    };
+    let mut buttons = vec![
+        button("Approve", APPROVE_CALLBACK_PREFIX),
+        button("Reject", REJECT_CALLBACK_PREFIX),
+    ];
+    if editable {
+        buttons.push(button("Edit", EDIT_CALLBACK_PREFIX));
+    }
+    InlineKeyboardMarkup::new(vec![buttons])
+}
+
+/// Builds the keyboard of a compact post: the side buttons,
+/// followed by the article links if `with_articles` is set.
+pub fn compact_keyboard(
+    story: &Story,
+    expanded: Option<Side>,
+    with_articles: bool,
+) -> InlineKeyboardMarkup {
+    let mut keyboard = sides_keyboard(story, expanded);
+    if with_articles {
+        keyboard
+            .inline_keyboard
+            .extend(articles_keyboard(story).inline_keyboard);
+    }
+    keyboard
+}
+
+/// Builds a keyboard with a button revealing the articles of each side of the story,
+/// or hiding them if the side is the `expanded` one.
+pub fn sides_keyboard(story: &Story, expanded: Option<Side>) -> InlineKeyboardMarkup {
+    let buttons = story
+        .articles
+        .iter()
+        .map(|article| article.side)
+        .sorted()
+        .dedup()
+        .map(|side| {
+            if Some(side) == expanded {
+                InlineKeyboardButton::callback(
+                    format!("Hide {}", side.label()),
+                    COLLAPSE_CALLBACK.to_owned(),
+                )
+            } else {
+                InlineKeyboardButton::callback(
+                    format!("Show {}", side.label()),
+                    format!("{}{}", EXPAND_CALLBACK_PREFIX, side.name()),
+                )
+            }
+        })
+        .collect::<Vec<_>>();
+    InlineKeyboardMarkup::new(buttons.chunks(3).map(<[_]>::to_vec).collect::<Vec<_>>())
+}
+
+/// Builds a keyboard with a button linking to every article of the story,
+/// one row per side, from left to right.
+pub fn articles_keyboard(story: &Story) -> InlineKeyboardMarkup {
+    let rows = story
+        .articles
+        .iter()
+        .sorted_by_key(|article| article.side)
+        .group_by(|article| article.side)
+        .into_iter()
+        .map(|(_, articles)| {
+            articles
+                .map(|article| {
+                    InlineKeyboardButton::url(
+                        format!("{} {}", article.side.emoji(), article.source),
+                        article.url.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    InlineKeyboardMarkup::new(rows)
+}
@@ -0,0 +1,365 @@
+use crate::config::WebhookOptions;
+use crate::scraper::Story;
+use crate::state::{DeadLetter, Delivery, Publication, State};
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Header with the hex-encoded HMAC-SHA256 of the body, keyed by the webhook secret.
+pub const SIGNATURE_HEADER: &str = "X-Astg-Signature";
+pub const EVENT_HEADER: &str = "X-Astg-Event";
+
+/// Payload of the webhooks sent when a story is published.
+#[derive(Debug, Serialize)]
+pub struct StoryPublished<'a> {
+    pub event: &'static str,
+    pub url: &'a str,
+    pub published_at: DateTime<Utc>,
+    pub story: &'a Story,
+}
+
+/// Notifies the configured endpoints of the published stories.
+///
+/// The deliveries are recorded in the state and run in the background; a delivery still
+/// failing after the retries is recorded as a [`DeadLetter`], to be delivered again with
+/// [`Webhooks::redeliver`]. The deliveries interrupted by an exit are resumed with
+/// [`Webhooks::resume`].
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    endpoints: Vec<String>,
+    secret: String,
+    attempts: u32,
+    retry_delay: Duration,
+    state: State,
+    /// Number of the deliveries running in the background, see [`Webhooks::wait`]
+    running: Arc<AtomicUsize>,
+    finished: Arc<Notify>,
+}
+
+impl Webhooks {
+    pub fn try_new(opts: &WebhookOptions, state: &State) -> anyhow::Result<Self> {
+        let secret = match &opts.secret {
+            Some(secret) => secret.clone(),
+            None if opts.endpoints.is_empty() => String::new(),
+            None => bail!("the secret of the webhooks is not configured (ASTG_WEBHOOK_SECRET)"),
+        };
+        Ok(Webhooks {
+            client: reqwest::Client::new(),
+            endpoints: opts.endpoints.clone(),
+            secret,
+            attempts: opts.attempts.max(1),
+            retry_delay: Duration::from_secs(opts.retry_delay),
+            state: state.clone(),
+            running: Arc::new(AtomicUsize::new(0)),
+            finished: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Records the deliveries of the story to every endpoint and starts them.
+    pub async fn story_published(
+        &self,
+        url: &str,
+        publication: &Publication,
+    ) -> anyhow::Result<()> {
+        if self.endpoints.is_empty() {
+            return Ok(());
+        }
+        let payload = serde_json::to_string(&StoryPublished {
+            event: "story.published",
+            url,
+            published_at: publication.published_at,
+            story: &publication.story,
+        })?;
+        for endpoint in &self.endpoints {
+            let delivery = Delivery {
+                endpoint: endpoint.clone(),
+                event: "story.published".to_owned(),
+                payload: payload.clone(),
+                dead_letter: None,
+            };
+            let id = self.state.clone().add_delivery(&delivery).await?;
+            self.spawn_delivery(id, delivery);
+        }
+        Ok(())
+    }
+
+    /// Starts the deliveries recorded before an exit, returns their number.
+    pub fn resume(&self) -> anyhow::Result<usize> {
+        let deliveries = self.state.deliveries()?;
+        let count = deliveries.len();
+        for (id, delivery) in deliveries {
+            self.spawn_delivery(id, delivery);
+        }
+        Ok(count)
+    }
+
+    /// Starts delivering the dead letters again, except the ones being delivered already.
+    /// A dead letter is removed once it's delivered, and updated if it fails again.
+    /// Returns the number of the dead letters delivered again.
+    pub async fn redeliver(&self) -> anyhow::Result<usize> {
+        let delivering = self
+            .state
+            .deliveries()?
+            .into_iter()
+            .filter_map(|(_, delivery)| delivery.dead_letter)
+            .collect::<HashSet<_>>();
+        let mut count = 0;
+        for (id, dead_letter) in self.state.dead_letters()? {
+            if delivering.contains(&id) {
+                continue;
+            }
+            let delivery = Delivery {
+                endpoint: dead_letter.endpoint,
+                event: dead_letter.event,
+                payload: dead_letter.payload,
+                dead_letter: Some(id),
+            };
+            let delivery_id = self.state.clone().add_delivery(&delivery).await?;
+            self.spawn_delivery(delivery_id, delivery);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Waits for the deliveries running in the background, for the commands
+    /// which exit once they're done.
+    pub async fn wait(&self) {
+        while self.running.load(Ordering::SeqCst) > 0 {
+            self.finished.notified().await;
+        }
+    }
+
+    fn spawn_delivery(&self, id: u64, delivery: Delivery) {
+        let webhooks = self.clone();
+        self.running.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            if let Err(e) = webhooks.deliver(id, &delivery).await {
+                log::error!("failed to record a webhook delivery: {}", e);
+            }
+            webhooks.running.fetch_sub(1, Ordering::SeqCst);
+            webhooks.finished.notify();
+        });
+    }
+
+    /// Delivers the payload, retrying with an exponential backoff, and records
+    /// the delivery as delivered, or as a dead letter if all the attempts fail.
+    async fn deliver(&self, id: u64, delivery: &Delivery) -> anyhow::Result<()> {
+        let Delivery {
+            endpoint,
+            event,
+            payload,
+            ..
+        } = delivery;
+        let mut delay = self.retry_delay;
+        let mut error = String::new();
+        for attempt in 1..=self.attempts {
+            match self.post(endpoint, event, payload).await {
+                Ok(()) => return self.state.clone().delivered(id, delivery).await,
+                Err(e) => {
+                    log::warn!("webhook {} failed (attempt {}): {}", endpoint, attempt, e);
+                    error = e.to_string();
+                }
+            }
+            if attempt < self.attempts {
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+            }
+        }
+        let dead_letter = DeadLetter {
+            endpoint: endpoint.clone(),
+            event: event.clone(),
+            payload: payload.clone(),
+            error,
+            attempts: self.attempts,
+            failed_at: Utc::now(),
+        };
+        self.state
+            .clone()
+            .delivery_failed(id, delivery, &dead_letter)
+            .await
+    }
+
+    async fn post(&self, endpoint: &str, event: &str, payload: &str) -> anyhow::Result<()> {
+        let response = self
+            .client
+            .post(endpoint)
+            .header("Content-Type", "application/json")
+            .header(EVENT_HEADER, event)
+            .header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(&self.secret, payload)),
+            )
+            .body(payload.to_owned())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("webhook: {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the hex-encoded HMAC-SHA256 of the payload.
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, STORY_URL};
+    use crate::mock_server::MockServer;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    fn options(server: &MockServer) -> WebhookOptions {
+        WebhookOptions {
+            endpoints: vec![format!("{}/hook", server.url)],
+            secret: Some("secret".to_owned()),
+            attempts: 3,
+            retry_delay: 0,
+        }
+    }
+
+    fn webhooks(server: &MockServer, state: &State) -> Webhooks {
+        Webhooks::try_new(&options(server), state).unwrap()
+    }
+
+    #[test]
+    fn secret_is_required() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, "{}")]);
+        let state = State::try_new_temporary()?;
+        let mut opts = options(&server);
+        opts.secret = None;
+        assert!(Webhooks::try_new(&opts, &state).is_err());
+        opts.endpoints.clear();
+        assert!(Webhooks::try_new(&opts, &state).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn signature() {
+        // echo -n '{"a":1}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", r#"{"a":1}"#),
+            "aa9e2e3575f5d7098b6caccd790888c36d5fdb63342a73bada2d6a51747a8494"
+        );
+    }
+
+    async fn recorded(server: &MockServer, state: &State, payload: &str) -> (u64, Delivery) {
+        let delivery = Delivery {
+            endpoint: format!("{}/hook", server.url),
+            event: "story.published".to_owned(),
+            payload: payload.to_owned(),
+            dead_letter: None,
+        };
+        let id = state.clone().add_delivery(&delivery).await.unwrap();
+        (id, delivery)
+    }
+
+    #[tokio::test]
+    async fn delivery_is_retried() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(500, "{}"), (200, "{}")]);
+        let state = State::try_new_temporary()?;
+        let (id, delivery) = recorded(&server, &state, r#"{"a":1}"#).await;
+        webhooks(&server, &state).deliver(id, &delivery).await?;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].path, "/hook");
+        assert_eq!(requests[1].header(EVENT_HEADER), Some("story.published"));
+        assert_eq!(
+            requests[1].header(SIGNATURE_HEADER),
+            Some(format!("sha256={}", sign("secret", r#"{"a":1}"#)).as_str())
+        );
+        assert!(state.deliveries()?.is_empty());
+        assert!(state.dead_letters()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_a_dead_letter() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(503, "{}")]);
+        let state = State::try_new_temporary()?;
+        let (id, delivery) = recorded(&server, &state, "{}").await;
+        webhooks(&server, &state).deliver(id, &delivery).await?;
+
+        assert_eq!(server.requests().len(), 3);
+        assert!(state.deliveries()?.is_empty());
+        let dead_letters = state.dead_letters()?;
+        assert_eq!(dead_letters.len(), 1);
+        let (_, dead_letter) = &dead_letters[0];
+        assert_eq!(dead_letter.attempts, 3);
+        assert_eq!(dead_letter.error, "webhook: 503 Service Unavailable");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_redelivered() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(503, "{}"), (503, "{}"), (503, "{}"), (200, "{}")]);
+        let state = State::try_new_temporary()?;
+        let webhooks = webhooks(&server, &state);
+        let (id, delivery) = recorded(&server, &state, "{}").await;
+        webhooks.deliver(id, &delivery).await?;
+        assert_eq!(state.dead_letters()?.len(), 1);
+
+        assert_eq!(webhooks.redeliver().await?, 1);
+        // The dead letter is kept until it's delivered, and isn't delivered twice meanwhile
+        assert_eq!(state.dead_letters()?.len(), 1);
+        assert_eq!(webhooks.redeliver().await?, 0);
+        webhooks.wait().await;
+        assert_eq!(server.requests().len(), 4);
+        assert!(state.dead_letters()?.is_empty());
+        assert!(state.deliveries()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn published_story_is_delivered() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, "{}")]);
+        let state = State::try_new_temporary()?;
+        let webhooks = webhooks(&server, &state);
+        let published_at = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        webhooks
+            .story_published(STORY_URL, &fixtures::publication(published_at))
+            .await?;
+        webhooks.wait().await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let payload: serde_json::Value = serde_json::from_str(&requests[0].body)?;
+        assert_eq!(payload["url"], STORY_URL);
+        assert_eq!(payload["published_at"], "2021-03-01T12:00:00Z");
+        assert!(state.deliveries()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_delivery_is_resumed() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![(200, "{}")]);
+        let state = State::try_new_temporary()?;
+        recorded(&server, &state, "{}").await;
+
+        let webhooks = webhooks(&server, &state);
+        assert_eq!(webhooks.resume()?, 1);
+        webhooks.wait().await;
+        assert_eq!(server.requests().len(), 1);
+        assert!(state.deliveries()?.is_empty());
+        Ok(())
+    }
+}